@@ -0,0 +1,240 @@
+//! archive.rs
+//!
+//! Move the older entries of a large swinstall_stack into an archive beside it.
+//!
+//! Stacks which have been installed into for years hold thousands of elt tags, nearly all
+//! of which only matter to lookups in the distant past. Archiving moves those entries into
+//! `<file>_swinstall_stack_archive`, a stack in its own right, and links it from the
+//! stack_history tag of the main stack:
+//!
+//! ```xml
+//! <stack_history archive="packages.xml_swinstall_stack_archive" archived_before="20190101-000000" path="..." schema="2">
+//! ```
+//!
+//! The schema decides which entries may go (see `SwinstallCurrent::archivable`), keeping
+//! every entry a lookup at or after `archived_before` could need. Such lookups read the
+//! main stack alone; earlier lookups consult the archive as well (see `with_archive`).
+
+use chrono::NaiveDateTime;
+use crate::{
+    constants::DATETIME_FMT,
+    errors::SwInstallError,
+    lock::StackLock,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    transaction::pending_check,
+    utils::{swinstall_archive_from_swinstall_stack, swinstall_stack_from_versionless},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::path::Path;
+
+/// The outcome of archiving the older entries of a swinstall_stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Archival {
+    /// path to the archive
+    pub archive: String,
+    /// the number of entries moved to the archive
+    pub archived: usize,
+    /// the number of entries left in the swinstall_stack
+    pub remaining: usize,
+    /// whether this was a dry run, leaving everything in place
+    pub dry_run: bool,
+}
+
+/// The archive linked from the supplied stack, and the datetime before which lookups must
+/// consult it, if the stack has been archived.
+pub fn archive_link(swinstall_stack: &str, stack: &StackDocument) -> Result<Option<(String, NaiveDateTime)>, SwInstallError> {
+    let archive = match stack.root.get("archive") {
+        Some(archive) => archive,
+        None => return Ok(None),
+    };
+    let before = stack.root.require_datetime("archived_before")?;
+    let archive = match Path::new(swinstall_stack).parent() {
+        Some(parent) => parent.join(archive).to_str().ok_or(SwInstallError::ConvertOsStrFail)?.to_string(),
+        None => archive.to_string(),
+    };
+    Ok(Some((archive, before)))
+}
+
+/// The supplied stack, along with any entries archived from it, as though it had never been
+/// archived.
+pub fn with_archive(parser: &SwinstallParser, swinstall_stack: &str, stack: &StackDocument) -> Result<StackDocument, SwInstallError> {
+    match archive_link(swinstall_stack, stack)? {
+        Some((archive, _)) => merge_archive(parser, stack, StackDocument::from_file(archive.as_str())?),
+        None => Ok(stack.clone()),
+    }
+}
+
+/// The supplied stack merged with the supplied archive of its older entries.
+pub fn merge_archive(parser: &SwinstallParser, stack: &StackDocument, archive: StackDocument) -> Result<StackDocument, SwInstallError> {
+    let mut merged = stack.clone();
+    parser.component_for(stack)?.merge_archived(&mut merged, archive.elements)?;
+    Ok(merged)
+}
+
+/// Move the entries of the `versionless` file's swinstall_stack which no lookup at or after
+/// `before` needs into its archive, creating the archive if need be. Nothing is written when
+/// `dry_run` is set.
+///
+/// The archive is written before the swinstall_stack, so an interrupted archival leaves
+/// entries in both, rather than in neither.
+pub fn archive(parser: &SwinstallParser, versionless: &str, before: &NaiveDateTime, dry_run: bool) -> Result<Archival, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let mut stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    let component = parser.component_for(&stack)?;
+    let indices = component.archivable(&stack, before)?;
+    let (archive, mut archived_before) = match archive_link(swinstall_stack.as_str(), &stack)? {
+        Some(link) => link,
+        None => (swinstall_archive_from_swinstall_stack(swinstall_stack.as_str()), *before),
+    };
+    let mut archival = Archival {
+        archive: archive.clone(),
+        archived: indices.len(),
+        remaining: stack.elts().count() - indices.len(),
+        dry_run,
+    };
+    if indices.is_empty() || dry_run {
+        return Ok(archival);
+    }
+
+    let mut archived = Vec::new();
+    for idx in indices.into_iter().rev() {
+        archived.insert(0, stack.elements.remove(idx));
+    }
+    let mut archive_stack = match Path::new(&archive).exists() {
+        true => StackDocument::from_file(archive.as_str())?,
+        false => StackDocument::new(archive.as_str(), component.schema()),
+    };
+    // entries archived earlier are older than these
+    let earlier = std::mem::replace(&mut archive_stack.elements, archived);
+    component.merge_archived(&mut archive_stack, earlier)?;
+    archive_stack.write(archive.as_str())?;
+
+    // a later cutoff keeps the main stack sufficient for fewer lookups
+    if *before > archived_before {
+        archived_before = *before;
+    }
+    let file_name = Path::new(&archive).file_name().and_then(|f| f.to_str()).ok_or(SwInstallError::ConvertOsStrFail)?;
+    stack.root.set("archive", file_name);
+    stack.root.set("archived_before", archived_before.format(DATETIME_FMT).to_string().as_str());
+    stack.write(swinstall_stack.as_str())?;
+    info!("archived {} entries of {} to {}", archival.archived, swinstall_stack, archive);
+    archival.archive = archive;
+    Ok(archival)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{datetime, install_daily},
+        traits::EltOptions,
+        transaction,
+        utils::versioned_from_versionless,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    // the version current at each of the supplied datetimes
+    fn versions_at(parser: &SwinstallParser, swinstall_stack: &str, datetimes: &[&str]) -> Vec<String> {
+        datetimes.iter()
+                 .map(|dt| parser.version_at(swinstall_stack, &datetime(dt)).unwrap_or_else(|_| "none".to_string()))
+                 .collect()
+    }
+
+    #[test]
+    fn archived_lookups_unchanged() {
+        let dir = TempDir::new().unwrap();
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let contents: Vec<String> = (1..=9).map(|day| day.to_string()).collect();
+        let versionless = install_daily(&parser, &dir, "packages.xml", &contents);
+        // version 5 expires, falling back to 4, which must therefore stay in the stack
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        let contents = fs::read_to_string(&swinstall_stack).unwrap()
+            .replace("version=\"5\"", "expires=\"20190110-000000\" version=\"5\"");
+        fs::write(&swinstall_stack, contents).unwrap();
+
+        let datetimes = [
+            "20190101-130000", "20190103-130000", "20190105-130000", "20190106-000000", "20190107-130000",
+            "20190109-130000", "20190110-130000", "20181231-000000",
+        ];
+        let expected = versions_at(&parser, swinstall_stack.as_str(), &datetimes);
+        assert_eq!(expected[6], "9");
+
+        let dry = archive(&parser, versionless.as_str(), &datetime("20190106-000000"), true).unwrap();
+        assert_eq!((dry.archived, dry.remaining), (3, 6));
+
+        archive(&parser, versionless.as_str(), &datetime("20190106-000000"), false).unwrap();
+        let stack = StackDocument::from_file(swinstall_stack.as_str()).unwrap();
+        let versions: Vec<&str> = stack.elts().map(|e| e.get("version").unwrap()).collect();
+        assert_eq!(versions, vec!["9", "8", "7", "6", "5", "4"]);
+        assert_eq!(versions_at(&parser, swinstall_stack.as_str(), &datetimes), expected);
+
+        // archiving again moves more entries into the same archive
+        let archival = archive(&parser, versionless.as_str(), &datetime("20190108-000000"), false).unwrap();
+        assert_eq!((archival.archived, archival.remaining), (3, 3));
+        assert_eq!(versions_at(&parser, swinstall_stack.as_str(), &datetimes), expected);
+        let merged = with_archive(&parser, swinstall_stack.as_str(), &StackDocument::from_file(swinstall_stack.as_str()).unwrap()).unwrap();
+        let versions: Vec<&str> = merged.elts().map(|e| e.get("version").unwrap()).collect();
+        assert_eq!(versions, vec!["9", "8", "7", "6", "5", "4", "3", "2", "1"]);
+
+        // archived versions may still be rolled back to
+        let now = datetime("20190201-120000");
+        transaction::rollback(&parser, versionless.as_str(), "1", &now, &EltOptions::default()).unwrap();
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "1");
+        assert_eq!(versions_at(&parser, swinstall_stack.as_str(), &datetimes), expected);
+
+        // the restored entries appear once in the history, though the archive keeps its copies
+        let timeline = transaction::timeline(&parser, versionless.as_str()).unwrap();
+        let events: Vec<(&str, &str)> = timeline.iter().map(|e| (e.action.as_str(), e.version.as_str())).collect();
+        assert_eq!(events.iter().filter(|e| **e == ("install", "1")).count(), 1);
+        assert_eq!(events.len(), 11);
+        assert_eq!(events[0], ("rollback", "1"));
+
+        // nor are they duplicated in the archive when archived once more
+        archive(&parser, versionless.as_str(), &datetime("20190301-000000"), false).unwrap();
+        let merged = with_archive(&parser, swinstall_stack.as_str(), &StackDocument::from_file(swinstall_stack.as_str()).unwrap()).unwrap();
+        let installs = merged.elts().filter(|e| e.get("action") == Some("install") && e.get("version") == Some("1")).count();
+        assert_eq!(installs, 1);
+        assert_eq!(versions_at(&parser, swinstall_stack.as_str(), &datetimes), expected);
+    }
+
+    #[test]
+    fn schema1_keeps_current() {
+        let dir = TempDir::new().unwrap();
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        fs::create_dir_all(Path::new(&swinstall_stack).parent().unwrap()).unwrap();
+        fs::write(&swinstall_stack, r#"<stack_history path="packages.xml_swinstall_stack" schema="1">
+   <elt is_current="False" version="20190101-120000"/>
+   <elt is_current="False" version="20190102-120000"/>
+   <elt is_current="True" version="20190103-120000"/>
+   <elt is_current="False" version="20190104-120000"/>
+   <elt is_current="False" version="20190105-120000"/>
+</stack_history>
+"#).unwrap();
+        let datetimes = ["20190101-130000", "20190102-130000", "20190104-130000", "20190106-130000"];
+        let expected = versions_at(&parser, swinstall_stack.as_str(), &datetimes);
+
+        // the current entry stays, however old it is
+        let archival = archive(&parser, versionless.as_str(), &datetime("20190106-000000"), false).unwrap();
+        assert_eq!((archival.archived, archival.remaining), (2, 3));
+        assert_eq!(versions_at(&parser, swinstall_stack.as_str(), &datetimes), expected);
+
+        // a version restored by a rollback appears once when the archive is merged back in
+        fs::write(versioned_from_versionless(versionless.as_str(), "20190101-120000").unwrap(), "1").unwrap();
+        let now = datetime("20190201-120000");
+        transaction::rollback(&parser, versionless.as_str(), "20190101-120000", &now, &EltOptions::default()).unwrap();
+        let merged = with_archive(&parser, swinstall_stack.as_str(), &StackDocument::from_file(swinstall_stack.as_str()).unwrap()).unwrap();
+        let versions: Vec<&str> = merged.elts().map(|e| e.get("version").unwrap()).collect();
+        assert_eq!(versions, vec!["20190102-120000", "20190101-120000", "20190103-120000", "20190104-120000", "20190105-120000"]);
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "20190101-120000");
+    }
+}
@@ -0,0 +1,200 @@
+//! async_parser.rs
+//!
+//! Lookups for async code, on the tokio runtime. Enabled by the `async` feature.
+//!
+//! `SwinstallParser` reads and parses swinstall_stacks with blocking file I/O, which stalls the
+//! executor when called from async code. `AsyncParser` hands each lookup to the blocking parser
+//! on tokio's blocking thread pool instead, so that lookups resolve exactly as blocking ones
+//! do, through the same fast paths, and the executor's threads are left free meanwhile.
+//!
+//! The parser is cheap to clone, sharing the `SwinstallParser` it wraps, so it may be handed
+//! to as many tasks as need it:
+//!
+//! ```ignore
+//! let parser = AsyncParser::with_builtin_schemas();
+//! let path = parser.current_at("/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack", &now).await?;
+//! ```
+//!
+//! Lenient mode and the index (see `SwinstallParser::set_lenient` and `set_index`) apply as
+//! they do to blocking lookups.
+
+use chrono::{Local, NaiveDateTime};
+use crate::{
+    parser::SwinstallParser,
+    traits::TimelineEvent,
+    transaction,
+    utils::{swinstall_stack_from_versionless, versioned_from_swinstall_stack},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::sync::Arc;
+use tokio::task::{self, JoinSet};
+
+/// Resolves swinstall_stacks without blocking the executor. See the module documentation.
+#[derive(Debug, Clone)]
+pub struct AsyncParser {
+    parser: Arc<SwinstallParser>,
+}
+
+impl AsyncParser {
+    /// new up an AsyncParser, resolving with the schemas registered with the supplied parser.
+    pub fn new(parser: SwinstallParser) -> Self {
+        AsyncParser { parser: Arc::new(parser) }
+    }
+
+    /// new up an AsyncParser with the built in schemas registered.
+    pub fn with_builtin_schemas() -> Self {
+        Self::new(SwinstallParser::with_builtin_schemas())
+    }
+
+    /// The blocking parser wrapped by this one.
+    pub fn parser(&self) -> &SwinstallParser {
+        &self.parser
+    }
+
+    /// Retrieve the path to the file marked current in the supplied swinstall_stack.
+    pub async fn current(&self, swinstall_stack: &str) -> Result<String, failure::Error> {
+        self.current_at(swinstall_stack, &Local::now().naive_local()).await
+    }
+
+    /// Retrieve the path to the file marked current as close to but not later than the
+    /// supplied datetime, as `SwinstallParser::current_at` does.
+    pub async fn current_at(&self, swinstall_stack: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        self.current_in(swinstall_stack, None, datetime).await
+    }
+
+    /// Retrieve the path to the file current in the named release channel as close to but not
+    /// later than the supplied datetime, as `SwinstallParser::current_in` does.
+    pub async fn current_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let version = self.version_in(swinstall_stack, channel, datetime).await?;
+        Ok(versioned_from_swinstall_stack(swinstall_stack, version.as_str())?)
+    }
+
+    /// Retrieve the version string of the file current in the named release channel as close
+    /// to but not later than the supplied datetime.
+    pub async fn version_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let parser = self.parser.clone();
+        let swinstall_stack = swinstall_stack.to_string();
+        let channel = channel.map(str::to_string);
+        let datetime = *datetime;
+        task::spawn_blocking(move || parser.version_in(swinstall_stack.as_str(), channel.as_deref(), &datetime)).await?
+    }
+
+    /// Retrieve the history of the `versionless` file, most recent event first, including any
+    /// entries archived from its swinstall_stack, as `transaction::timeline` does.
+    pub async fn history(&self, versionless: &str) -> Result<Vec<TimelineEvent>, failure::Error> {
+        let parser = self.parser.clone();
+        let versionless = versionless.to_string();
+        Ok(task::spawn_blocking(move || transaction::timeline(&parser, versionless.as_str())).await??)
+    }
+
+    /// Resolve the path to the file current at the supplied datetime for each of the supplied
+    /// versionless files, concurrently, as `SwinstallParser::resolve_many` does. Returns one
+    /// result per file, in the order supplied.
+    pub async fn resolve_many(&self, versionless: &[String], datetime: &NaiveDateTime) -> Vec<Result<String, failure::Error>> {
+        self.resolve_many_in(versionless, None, datetime).await
+    }
+
+    /// Resolve the path to the file current in the named release channel for each of the
+    /// supplied versionless files, as `resolve_many` does.
+    pub async fn resolve_many_in(&self, versionless: &[String], channel: Option<&str>, datetime: &NaiveDateTime) -> Vec<Result<String, failure::Error>> {
+        let mut tasks = JoinSet::new();
+        for (idx, file) in versionless.iter().enumerate() {
+            let parser = self.clone();
+            let file = file.clone();
+            let channel = channel.map(str::to_string);
+            let datetime = *datetime;
+            tasks.spawn(async move {
+                let resolved = match swinstall_stack_from_versionless(file.as_str()) {
+                    Ok(swinstall_stack) => parser.current_in(swinstall_stack.as_str(), channel.as_deref(), &datetime).await,
+                    Err(e) => Err(e.into()),
+                };
+                (idx, resolved)
+            });
+        }
+
+        let mut results: Vec<Option<Result<String, failure::Error>>> = versionless.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next().await {
+            let (idx, resolved) = joined.expect("resolve_many task panicked");
+            results[idx] = Some(resolved);
+        }
+        results.into_iter().map(|r| r.expect("every file is resolved")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::archive,
+        testing::{datetime, install_daily},
+    };
+    use tempfile::TempDir;
+
+    // a tree of versionless files, each installed on each of the first few days of 2019, with
+    // the oldest entries of the first archived
+    fn setup(dir: &TempDir) -> (SwinstallParser, Vec<String>) {
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let files: Vec<String> = (0..12).map(|i| {
+            let contents: Vec<String> = (1..=(1 + i % 4)).map(|day| format!("{} {}", i, day)).collect();
+            install_daily(&parser, dir, format!("show{}/file{}.xml", i % 3, i).as_str(), &contents)
+        }).collect();
+        archive(&parser, files[3].as_str(), &datetime("20190103-000000"), false).unwrap();
+        (parser, files)
+    }
+
+    fn datetimes() -> Vec<NaiveDateTime> {
+        ["20181231-000000", "20190101-130000", "20190102-130000", "20190103-130000", "20190201-000000"].iter()
+            .map(|dt| datetime(dt))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn resolutions_agree_with_blocking() {
+        let dir = TempDir::new().unwrap();
+        let (parser, files) = setup(&dir);
+        let expected: Vec<Vec<Option<String>>> = datetimes().iter()
+            .map(|dt| parser.resolve_many(&files, dt).into_iter().map(Result::ok).collect())
+            .collect();
+        let parser = AsyncParser::new(parser);
+
+        // many resolutions in flight at once
+        let mut tasks = JoinSet::new();
+        for _ in 0..16 {
+            let parser = parser.clone();
+            let files = files.clone();
+            tasks.spawn(async move {
+                let mut resolved = Vec::new();
+                for dt in datetimes() {
+                    resolved.push(parser.resolve_many(&files, &dt).await.into_iter().map(Result::ok).collect::<Vec<_>>());
+                }
+                resolved
+            });
+        }
+        while let Some(resolved) = tasks.join_next().await {
+            assert_eq!(resolved.unwrap(), expected);
+        }
+
+        // the archived file resolves before its cutoff
+        let swinstall_stack = swinstall_stack_from_versionless(files[3].as_str()).unwrap();
+        let path = parser.current_at(swinstall_stack.as_str(), &datetime("20190101-130000")).await.unwrap();
+        assert!(path.ends_with("file3.xml_1"));
+    }
+
+    #[tokio::test]
+    async fn history_and_missing_stacks() {
+        let dir = TempDir::new().unwrap();
+        let (parser, files) = setup(&dir);
+        let expected = transaction::timeline(&parser, files[3].as_str()).unwrap();
+        let parser = AsyncParser::new(parser);
+        let history = parser.history(files[3].as_str()).await.unwrap();
+        assert_eq!(history, expected);
+        assert_eq!(history.len(), 4);
+
+        let missing = dir.path().join("missing.xml").to_str().unwrap().to_string();
+        let results = parser.resolve_many(&[files[0].clone(), missing], &datetime("20190201-000000")).await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+}
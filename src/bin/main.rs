@@ -1,18 +1,36 @@
-use chrono::{Datelike, Timelike, Local, NaiveDate, NaiveTime, NaiveDateTime};
+use chrono::{Datelike, Duration, Timelike, Local, NaiveDate, NaiveTime, NaiveDateTime};
 use env_logger::{self, Builder, Env};
 use failure::Error;
 #[allow(unused_imports)]
 use log::{debug, info, warn, error};
 use std::{
-    path::PathBuf
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 use structopt::StructOpt;
 use swinstall_stack::{
-    constants::{DEFAULT_LOG_LEVEL, VERBOSE_LOG_LEVEL},
+    archive,
+    changeset,
+    daemon::{self, Client, Resolver},
+    http::{self, Api},
+    compress::{self, read_versioned},
+    convert::{self, Format},
+    dedup,
+    gc::{self, RetentionPolicy},
+    index::IndexLocation,
+    search_path::SearchPath,
+    stack::StackDocument,
+    constants::{DATETIME_FMT, DEFAULT_COMPRESS_DAYS, DEFAULT_LOG_LEVEL, VERBOSE_LOG_LEVEL},
     errors::SwInstallError,
     parser::SwinstallParser,
-    schemas::{ one, two },
-    utils::swinstall_stack_from_versionless,
+    traits::EltOptions,
+    transaction::{ self, Recovery },
+    utils::{
+        expand_glob, find_swinstall_stacks, parse_datetime, swinstall_stack_from_versionless,
+        versioned_from_versionless, versionless_from_swinstall_stack,
+    },
 };
 
 #[derive(Debug, StructOpt)]
@@ -27,8 +45,301 @@ struct Opt {
     /// Supply explicit time, in the form HH:MM:SS
     #[structopt(short = "t", long = "time")]
     time: Option<String>,
+    /// Resolve the version labelled by this tag, rather than the current version
+    #[structopt(long = "tag")]
+    tag: Option<String>,
+    /// Release channel to resolve, or to install and roll back within. Defaults to the
+    /// default channel, which the versionless file tracks
+    #[structopt(long = "channel")]
+    channel: Option<String>,
+    /// Resolve against what can be recovered from a damaged swinstall_stack, warning about
+    /// the damage, as long as the damage cannot affect the result
+    #[structopt(long = "lenient")]
+    lenient: bool,
+    /// Cache the parsed entries of the swinstall_stack in an index beside it, skipping the
+    /// parse on later lookups while the stack is unchanged
+    #[structopt(long = "index")]
+    index: bool,
+    /// Keep indexes in this directory rather than beside each swinstall_stack. Implies --index
+    #[structopt(long = "index-dir", parse(from_os_str))]
+    index_dir: Option<PathBuf>,
+    /// Ask a running `swinst serve` to resolve the files, resolving them directly when no
+    /// daemon is listening
+    #[structopt(long = "daemon")]
+    daemon: bool,
+    /// The socket of the daemon. Defaults to $SWINSTALL_SOCKET, or a socket in $XDG_RUNTIME_DIR
+    /// or a private directory of the temporary directory
+    #[structopt(long = "socket", parse(from_os_str))]
+    socket: Option<PathBuf>,
+    /// Also resolve the versionless files listed on stdin, one per line
+    #[structopt(long = "stdin")]
+    stdin: bool,
+    /// Versionless files to resolve. Wildcards the shell has not expanded are expanded here,
+    /// and a pattern matching no files is an error
     #[structopt(parse(from_os_str))]
-    input:  PathBuf
+    inputs: Vec<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Install files, making each the current version of its versionless file
+    #[structopt(name = "install")]
+    Install {
+        /// Schema used when creating a new swinstall_stack
+        #[structopt(short = "s", long = "schema", default_value = "2")]
+        schema: String,
+        /// Install the files as a single changeset with this id. An id is generated
+        /// when more than one file is installed
+        #[structopt(short = "c", long = "changeset")]
+        changeset: Option<String>,
+        /// Have the install take effect at this datetime (YYYYMMDD-HHMMSS or
+        /// YYYY-MM-DD HH:MM:SS). The versionless file is only rewritten if the install is
+        /// current now; scheduled installs must be resolved through the swinstall_stack
+        #[structopt(long = "effective-at")]
+        effective_at: Option<String>,
+        /// Stop treating the install as current at this datetime, falling back to the
+        /// version current before it
+        #[structopt(long = "expires")]
+        expires: Option<String>,
+        /// Pairs of files to install and the versionless files to install them as:
+        /// SOURCE VERSIONLESS [SOURCE VERSIONLESS ...]
+        #[structopt(parse(from_os_str), raw(required = "true"))]
+        files: Vec<PathBuf>,
+    },
+    /// Roll back (or forward) to a previously installed version
+    #[structopt(name = "rollback")]
+    Rollback {
+        /// Roll back every file touched by the changeset with this id
+        #[structopt(short = "c", long = "changeset")]
+        changeset: Option<String>,
+        /// The versionless file to roll back, or the directory to search with --changeset
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+        /// The version to roll back to. Not used with --changeset
+        version: Option<String>,
+    },
+    /// Install a file into bak without making it current
+    #[structopt(name = "stage")]
+    Stage {
+        /// Schema used when creating a new swinstall_stack
+        #[structopt(short = "s", long = "schema", default_value = "2")]
+        schema: String,
+        /// The file to stage
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+        /// The versionless file to stage it as
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Make a staged version current
+    #[structopt(name = "promote")]
+    Promote {
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+        version: String,
+    },
+    /// List the staged versions which have not been promoted
+    #[structopt(name = "staged")]
+    Staged {
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Point a tag at a version, moving it if it already exists
+    #[structopt(name = "tag")]
+    Tag {
+        /// Remove the tag rather than pointing it at a version
+        #[structopt(long = "delete")]
+        delete: bool,
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+        name: String,
+        /// The version to tag. Defaults to the current version
+        version: Option<String>,
+    },
+    /// Resolve a file against an ordered list of roots, returning the first current version
+    #[structopt(name = "resolve")]
+    Resolve {
+        /// Colon separated list of roots, most specific first
+        #[structopt(short = "p", long = "path")]
+        path: String,
+        /// File name, relative to each root
+        file_name: String,
+    },
+    /// Show the history of the tags of a versionless file, most recent first
+    #[structopt(name = "tags")]
+    Tags {
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Show the history of a versionless file, most recent first
+    #[structopt(name = "log")]
+    Log {
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// List installs scheduled to take effect later, for a versionless file or for
+    /// every swinstall_stack beneath a directory
+    #[structopt(name = "pending")]
+    Pending {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Print the contents of the current version, or of a specific version
+    #[structopt(name = "cat")]
+    Cat {
+        /// The version to print, rather than the current one
+        #[structopt(long = "version")]
+        version: Option<String>,
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Print a swinstall_stack as json, yaml or toml
+    #[structopt(name = "export")]
+    Export {
+        #[structopt(long = "to", default_value = "json")]
+        to: String,
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Convert a swinstall_stack from json, yaml or toml back to xml, printing the result
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(long = "from", default_value = "json")]
+        from: String,
+        /// File to convert. Read from stdin if absent
+        #[structopt(parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
+    /// List the changesets recorded in the swinstall_stacks beneath a directory
+    #[structopt(name = "changesets")]
+    Changesets {
+        #[structopt(parse(from_os_str))]
+        root: PathBuf,
+    },
+    /// Complete or revert an interrupted install or rollback
+    #[structopt(name = "recover")]
+    Recover {
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Rebuild a lost swinstall_stack from the versioned files left in bak/
+    #[structopt(name = "reconstruct")]
+    Reconstruct {
+        /// Schema of the reconstructed swinstall_stack
+        #[structopt(short = "s", long = "schema", default_value = "2")]
+        schema: String,
+        /// Print the reconstructed swinstall_stack rather than writing it
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Remove the versioned files which fall outside a retention policy, for a versionless
+    /// file or for every swinstall_stack beneath a directory. Versions current in any channel,
+    /// staged or scheduled are always kept
+    #[structopt(name = "gc")]
+    Gc {
+        /// Keep the most recently installed N versions
+        #[structopt(long = "keep-last")]
+        keep_last: Option<usize>,
+        /// Keep every version installed after this datetime
+        #[structopt(long = "keep-newer-than")]
+        keep_newer_than: Option<String>,
+        /// Keep every version which has ever been current
+        #[structopt(long = "keep-current")]
+        keep_current: bool,
+        /// Keep every version which has ever been tagged
+        #[structopt(long = "keep-tagged")]
+        keep_tagged: bool,
+        /// Move the versioned files into this directory rather than removing them
+        #[structopt(long = "archive", parse(from_os_str))]
+        archive: Option<PathBuf>,
+        /// Report what would be collected without touching anything
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Move the entries of a swinstall_stack which only lookups in the past need into an
+    /// archive beside it, for a versionless file or for every swinstall_stack beneath a
+    /// directory. Lookups before the entries left in the stack consult the archive
+    #[structopt(name = "archive")]
+    Archive {
+        /// Archive entries which no lookup from this many days ago onwards needs
+        #[structopt(long = "older-than")]
+        older_than: i64,
+        /// Report what would be archived without touching anything
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Compress the versioned files which have not been in use for a while, for a versionless
+    /// file or for every swinstall_stack beneath a directory. Compressed versions are read
+    /// transparently, and decompressed if rolled back to
+    #[structopt(name = "compress")]
+    Compress {
+        /// Compress versions installed more than this many days ago
+        #[structopt(long = "older-than")]
+        older_than: Option<i64>,
+        /// Report the space which would be saved without compressing anything
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Hard link versioned files with identical contents, within the versions of a
+    /// versionless file or across every swinstall_stack beneath a directory
+    #[structopt(name = "dedup")]
+    Dedup {
+        /// Report the space which would be saved without linking anything
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Rewrite the versionless files whose contents are no longer the current version, as
+    /// when a scheduled install takes effect or an install expires, for a versionless file or
+    /// for every swinstall_stack beneath a directory. The versionless file is only brought up
+    /// to date when this is run; consumers which must see such changes on time should resolve
+    /// through the swinstall_stack instead
+    #[structopt(name = "sync")]
+    Sync {
+        /// Report the files out of step without rewriting them
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Rewrite a damaged swinstall_stack from the elements which can be recovered from it,
+    /// keeping the damaged stack alongside it
+    #[structopt(name = "repair")]
+    Repair {
+        /// Report the damage without rewriting the swinstall_stack
+        #[structopt(short = "n", long = "dry-run")]
+        dry_run: bool,
+        #[structopt(parse(from_os_str))]
+        versionless: PathBuf,
+    },
+    /// Run a daemon answering lookups over a Unix socket, keeping the swinstall_stacks it
+    /// reads in memory until they change. See `swinst --daemon`
+    #[structopt(name = "serve")]
+    Serve {
+        /// The socket to listen on. Defaults to $SWINSTALL_SOCKET, or a socket in
+        /// $XDG_RUNTIME_DIR or a private directory of the temporary directory
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+    },
+    /// Serve a read only HTTP API answering resolve, history, status and content queries
+    /// with JSON
+    #[structopt(name = "http")]
+    Http {
+        /// The address to listen on. Port 0 picks a free port
+        #[structopt(long = "bind", default_value = "127.0.0.1:8080")]
+        bind: String,
+    },
 }
 
 // Given an Option wrapped date string, convert it to a Result wrapping NaiveDate.
@@ -79,7 +390,316 @@ fn get_time(time: Option<String>) -> Result<NaiveTime, SwInstallError> {
     }
 }
 
-fn main() -> Result<(), Error> {
+// Convert a path supplied on the command line to a str
+fn path_str(path: &PathBuf) -> Result<&str, SwInstallError> {
+    path.to_str()
+        .ok_or_else(|| SwInstallError::RuntimeError(format!("unable to convert {:?} to str", path)))
+}
+
+// The versionless files named by a path on the command line: the file itself, or those of
+// every swinstall_stack beneath a directory
+fn versionless_files(path: &str) -> Result<Vec<String>, SwInstallError> {
+    if fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
+        find_swinstall_stacks(path)?
+            .iter()
+            .map(|stack| versionless_from_swinstall_stack(stack))
+            .collect()
+    } else {
+        Ok(vec![path.to_string()])
+    }
+}
+
+// Expand a path supplied as input into `inputs`. A pattern whose wildcards match nothing is
+// reported and recorded in `unmatched`, rather than dropped
+fn expand_input(pattern: &str, inputs: &mut Vec<String>, unmatched: &mut Vec<String>) -> Result<(), SwInstallError> {
+    let paths = expand_glob(pattern)?;
+    if paths.is_empty() {
+        eprintln!("{}: Error: no files match", pattern);
+        unmatched.push(pattern.to_string());
+    }
+    inputs.extend(paths);
+    Ok(())
+}
+
+// Run one of the subcommands. Lookups are made as of `datetime_at`, within `channel`
+fn run_command(parser: &SwinstallParser, cmd: Command, datetime_at: &NaiveDateTime, channel: Option<&str>) -> Result<(), Error> {
+    let mut base = EltOptions::default();
+    if let Some(channel) = channel {
+        base = base.channel(channel);
+    }
+    let now = Local::now().naive_local();
+    match cmd {
+        Command::Install { schema, changeset, effective_at, expires, files } => {
+            if files.len() % 2 != 0 {
+                return Err(SwInstallError::RuntimeError("install expects pairs of SOURCE VERSIONLESS".to_string()).into());
+            }
+            let effective = match effective_at {
+                Some(ref dt) => parse_datetime(dt)?,
+                None => now,
+            };
+            let mut options = base.clone();
+            if let Some(ref dt) = expires {
+                options = options.expires(&parse_datetime(dt)?);
+            }
+            let mut pairs = Vec::new();
+            for pair in files.chunks(2) {
+                pairs.push((path_str(&pair[0])?.to_string(), path_str(&pair[1])?.to_string()));
+            }
+
+            if pairs.len() == 1 && changeset.is_none() {
+                let (ref source, ref versionless) = pairs[0];
+                let versioned = transaction::install_effective(parser, versionless, source, schema.as_str(), &effective, &now, &options)?;
+                println!("\ninstalled: {}\n", versioned);
+            } else {
+                let id = changeset.unwrap_or_else(|| changeset::new_changeset_id(&now));
+                let versioned = changeset::install_effective(parser, id.as_str(), &pairs, schema.as_str(), &effective, &now, &options)?;
+                println!("\nchangeset: {}", id);
+                for path in versioned {
+                    println!("installed: {}", path);
+                }
+                println!();
+            }
+        }
+        Command::Rollback { changeset, versionless, version } => {
+            match (changeset, version) {
+                (Some(id), None) => {
+                    let rolled_back = changeset::rollback(parser, path_str(&versionless)?, id.as_str(), &now)?;
+                    println!("\nchangeset: {}", rolled_back.id);
+                    println!("rolled back: {}", id);
+                    for path in &rolled_back.versioned {
+                        println!("current: {}", path);
+                    }
+                    println!();
+                    for file in &rolled_back.skipped {
+                        eprintln!("{}: did not exist before changeset {}; left in place", file, id);
+                    }
+                    if !rolled_back.is_complete() {
+                        return Err(SwInstallError::RuntimeError(format!(
+                            "changeset {} partially rolled back: {} of {} files left in place",
+                            id, rolled_back.skipped.len(), rolled_back.skipped.len() + rolled_back.versioned.len()
+                        )).into());
+                    }
+                }
+                (None, Some(version)) => {
+                    let versioned = transaction::rollback(parser, path_str(&versionless)?, version.as_str(), &now, &base)?;
+                    println!("\ncurrent: {}\n", versioned);
+                }
+                _ => return Err(SwInstallError::RuntimeError("rollback expects either a version or --changeset".to_string()).into()),
+            }
+        }
+        Command::Stage { schema, source, versionless } => {
+            let versioned = transaction::stage(parser, path_str(&versionless)?, path_str(&source)?, schema.as_str(), &now, &base)?;
+            println!("\nstaged: {}\n", versioned);
+        }
+        Command::Promote { versionless, version } => {
+            let versioned = transaction::promote(parser, path_str(&versionless)?, version.as_str(), &now, &base)?;
+            println!("\ncurrent: {}\n", versioned);
+        }
+        Command::Staged { versionless } => {
+            let versionless = path_str(&versionless)?;
+            for version in transaction::staged(parser, versionless, channel)? {
+                println!("{}", versioned_from_versionless(versionless, version.as_str())?);
+            }
+        }
+        Command::Tag { delete, versionless, name, version } => {
+            let versionless = path_str(&versionless)?;
+            if delete {
+                transaction::tag(parser, versionless, name.as_str(), None, &now)?;
+                println!("\nremoved tag: {}\n", name);
+            } else {
+                let version = match version {
+                    Some(version) => version,
+                    None => parser.version_in(swinstall_stack_from_versionless(versionless)?.as_str(), channel, &now)?,
+                };
+                transaction::tag(parser, versionless, name.as_str(), Some(version.as_str()), &now)?;
+                println!("\n{}: {}\n", name, versioned_from_versionless(versionless, version.as_str())?);
+            }
+        }
+        Command::Resolve { path, file_name } => {
+            let search_path: SearchPath = path.parse()?;
+            let resolution = search_path.resolve_in(parser, file_name.as_str(), channel, datetime_at)?;
+            println!("\npath: {}\nlayer: {} ({})\n", resolution.versioned, resolution.layer, resolution.root);
+        }
+        Command::Tags { versionless } => {
+            for tag in transaction::tags(parser, path_str(&versionless)?)? {
+                let version = tag.version.map(|v| format!("version {}", v)).unwrap_or_else(|| "removed".to_string());
+                println!("{} {} {}", tag.datetime.format(DATETIME_FMT), tag.name, version);
+            }
+        }
+        Command::Log { versionless } => {
+            for event in transaction::timeline(parser, path_str(&versionless)?)? {
+                let mut line = format!("{} {:<8} version {}", event.datetime.format(DATETIME_FMT), event.action, event.version);
+                if let Some(channel) = event.channel {
+                    line.push_str(&format!(" channel {}", channel));
+                }
+                if let Some(expires) = event.expires {
+                    line.push_str(&format!(" expires {}", expires.format(DATETIME_FMT)));
+                }
+                if let Some(changeset) = event.changeset {
+                    line.push_str(&format!(" changeset {}", changeset));
+                }
+                println!("{}", line);
+            }
+        }
+        Command::Pending { path } => {
+            for file in versionless_files(path_str(&path)?)? {
+                for entry in transaction::pending(parser, file.as_str(), datetime_at)? {
+                    println!("{} {} {} version {}", entry.datetime.format(DATETIME_FMT), entry.action, file, entry.version);
+                }
+            }
+        }
+        Command::Cat { version, versionless } => {
+            let versionless = path_str(&versionless)?;
+            let versioned = match version {
+                Some(version) => versioned_from_versionless(versionless, version.as_str())?,
+                None => parser.current_in(swinstall_stack_from_versionless(versionless)?.as_str(), channel, datetime_at)?,
+            };
+            io::stdout().write_all(&read_versioned(versioned.as_str())?)?;
+        }
+        Command::Export { to, versionless } => {
+            let format: Format = to.parse()?;
+            let stack = StackDocument::from_file(swinstall_stack_from_versionless(path_str(&versionless)?)?.as_str())?;
+            println!("{}", convert::export(&stack, format)?);
+        }
+        Command::Import { from, input } => {
+            let format: Format = from.parse()?;
+            let contents = match input {
+                Some(input) => fs::read_to_string(input)?,
+                None => io::read_to_string(io::stdin())?,
+            };
+            print!("{}", convert::import(contents.as_str(), format)?.to_xml());
+        }
+        Command::Changesets { root } => {
+            for cs in changeset::changesets(parser, path_str(&root)?)? {
+                let datetime = cs.datetime().map(|dt| dt.format(DATETIME_FMT).to_string()).unwrap_or_default();
+                println!("{} {}", datetime, cs.id);
+                for file in cs.files {
+                    println!("    {} {} version {}", file.action, file.versionless, file.version);
+                }
+            }
+        }
+        Command::Recover { versionless } => {
+            match transaction::recover(path_str(&versionless)?)? {
+                Recovery::Clean => println!("\nnothing to recover\n"),
+                Recovery::Completed(op, version) => println!("\ncompleted {} of version {}\n", op, version),
+                Recovery::Reverted(op, version) => println!("\nreverted {} of version {}\n", op, version),
+            }
+        }
+        Command::Reconstruct { schema, dry_run, versionless } => {
+            let reconstruction = transaction::reconstruct(parser, path_str(&versionless)?, schema.as_str(), dry_run)?;
+            if dry_run {
+                println!("{}", reconstruction.stack.to_xml());
+            }
+            for guess in &reconstruction.guesses {
+                println!("guess: {}", guess);
+            }
+            let versions = reconstruction.stack.elts().count();
+            match dry_run {
+                true => println!("\nwould reconstruct {} from {} entries\n", reconstruction.swinstall_stack, versions),
+                false => println!("\nreconstructed {} from {} entries\n", reconstruction.swinstall_stack, versions),
+            }
+        }
+        Command::Gc { keep_last, keep_newer_than, keep_current, keep_tagged, archive, dry_run, path } => {
+            let policy = RetentionPolicy {
+                keep_last,
+                keep_newer_than: keep_newer_than.as_deref().map(parse_datetime).transpose()?,
+                keep_current,
+                keep_tagged,
+            };
+            let archive = archive.as_ref().map(path_str).transpose()?;
+            let mut total = 0;
+            for file in versionless_files(path_str(&path)?)? {
+                let collection = gc::collect(parser, file.as_str(), &policy, archive, dry_run, datetime_at)?;
+                for pruned in &collection.pruned {
+                    println!("{} {} bytes", pruned.path, pruned.bytes);
+                }
+                total += collection.bytes();
+            }
+            match dry_run {
+                true => println!("\nwould reclaim {} bytes\n", total),
+                false => println!("\nreclaimed {} bytes\n", total),
+            }
+        }
+        Command::Archive { older_than, dry_run, path } => {
+            let before = *datetime_at - Duration::days(older_than);
+            for file in versionless_files(path_str(&path)?)? {
+                let archival = archive::archive(parser, file.as_str(), &before, dry_run)?;
+                match dry_run {
+                    true => println!("{}: would archive {} entries, leaving {}", file, archival.archived, archival.remaining),
+                    false => println!("{}: archived {} entries to {}, leaving {}", file, archival.archived, archival.archive, archival.remaining),
+                }
+            }
+        }
+        Command::Compress { older_than, dry_run, path } => {
+            let older_than = *datetime_at - Duration::days(older_than.unwrap_or(DEFAULT_COMPRESS_DAYS));
+            let mut total = 0;
+            for file in versionless_files(path_str(&path)?)? {
+                let result = compress::compress(parser, file.as_str(), &older_than, dry_run, datetime_at)?;
+                for version in &result.compressed {
+                    println!("{} {} -> {} bytes", version.path, version.bytes, version.compressed_bytes);
+                }
+                total += result.bytes();
+            }
+            match dry_run {
+                true => println!("\nwould save {} bytes\n", total),
+                false => println!("\nsaved {} bytes\n", total),
+            }
+        }
+        Command::Dedup { dry_run, path } => {
+            let result = dedup::dedup(&versionless_files(path_str(&path)?)?, dry_run)?;
+            for linked in &result.linked {
+                println!("{} -> {} {} bytes", linked.path, linked.target, linked.bytes);
+            }
+            for path in &result.mismatched {
+                println!("skipped {}: contents do not match the swinstall_stack", path);
+            }
+            match dry_run {
+                true => println!("\nwould save {} bytes\n", result.bytes()),
+                false => println!("\nsaved {} bytes\n", result.bytes()),
+            }
+        }
+        Command::Sync { dry_run, path } => {
+            let mut count = 0;
+            for file in versionless_files(path_str(&path)?)? {
+                if let Some(version) = transaction::sync(parser, file.as_str(), datetime_at, dry_run)? {
+                    println!("{}: version {}", file, version);
+                    count += 1;
+                }
+            }
+            match dry_run {
+                true => println!("\n{} files out of step\n", count),
+                false => println!("\n{} files rewritten\n", count),
+            }
+        }
+        Command::Repair { dry_run, versionless } => {
+            let damage = transaction::repair(parser, path_str(&versionless)?, dry_run)?;
+            for diagnostic in &damage {
+                println!("{}\n", diagnostic.render());
+            }
+            match (damage.len(), dry_run) {
+                (0, _) => println!("\nno damage found\n"),
+                (count, true) => println!("{} problems found\n", count),
+                (count, false) => println!("{} problems found; swinstall_stack rewritten\n", count),
+            }
+        }
+        // the servers take ownership of the parser, so are started by run
+        Command::Serve { .. } | Command::Http { .. } => unreachable!("servers are started by run"),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        // malformed stacks are shown with the offending line of the stack
+        match e.downcast_ref::<SwInstallError>() {
+            Some(SwInstallError::Malformed(diagnostic)) => eprintln!("{}", diagnostic.render()),
+            _ => eprintln!("Error: {}", e),
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
 
     let opt = Opt::from_args();
     if opt.verbose {
@@ -87,31 +707,115 @@ fn main() -> Result<(), Error> {
     } else {
         Builder::from_env(Env::default().default_filter_or(DEFAULT_LOG_LEVEL)).init();
     }
-    // create a parser
-    let mut parser = SwinstallParser::new();
-
-    // create schemas and register them with the parser
-    let schema1 = one::One::new();
-    let schema2 = two::Two::new();
-
-    parser.register(Box::new(schema1));
-    parser.register(Box::new(schema2));
-
-    // set a default schema to be used in the event that the swinstall_stack
-    // does not
-    parser.set_default_schema(String::from("1"));
+    // create a parser, with the schemas we know about registered
+    let mut parser = SwinstallParser::with_builtin_schemas();
+    parser.set_lenient(opt.lenient);
+    parser.set_index(match opt.index_dir {
+        Some(dir) => Some(IndexLocation::Directory(dir)),
+        None if opt.index => Some(IndexLocation::Sidecar),
+        None => None,
+    });
 
     let date = get_date(opt.date)?;
     let time = get_time(opt.time)?;
     // now create the datetime
     let datetime_at = NaiveDateTime::new(date, time);
-    let input_path = opt.input
-                     .to_str()
-                     .ok_or(SwInstallError::RuntimeError("unable to unwrap opt.input".to_string()))?;
-    // optparse should guarantee that opt.input can be unwrapped
-    let swinstall_stack = swinstall_stack_from_versionless(input_path)?;
-    debug!("swinstall_stack: {}", swinstall_stack.as_str());
-    let path =  parser.current_at(swinstall_stack.as_str(), &datetime_at)?;
-    println!("\npath: {}\n", path);
+
+    match opt.cmd {
+        Some(Command::Serve { socket }) => {
+            let socket = socket.or(opt.socket).unwrap_or_else(daemon::default_socket);
+            let listener = daemon::bind(&socket)?;
+            info!("serving on {:?}", socket);
+            return Ok(daemon::serve(Resolver::new(parser), listener)?);
+        },
+        Some(Command::Http { bind }) => {
+            let server = http::bind(bind.as_str())?;
+            // announce the address actually bound, for callers asking for any free port
+            println!("listening on http://{}", server.server_addr());
+            io::stdout().flush()?;
+            return Ok(http::serve(Arc::new(Api::new(parser)), server)?);
+        },
+        Some(cmd) => return run_command(&parser, cmd, &datetime_at, opt.channel.as_deref()),
+        None => (),
+    }
+    let mut inputs = Vec::new();
+    let mut unmatched = Vec::new();
+    for input in opt.inputs.iter() {
+        let input = input.to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+        expand_input(input, &mut inputs, &mut unmatched)?;
+    }
+    if opt.stdin {
+        for line in io::stdin().lock().lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() {
+                expand_input(line, &mut inputs, &mut unmatched)?;
+            }
+        }
+    }
+    // fall back to resolving the files ourselves when no daemon is listening
+    let mut client = match opt.daemon {
+        true => {
+            let socket = opt.socket.unwrap_or_else(daemon::default_socket);
+            match Client::connect(&socket) {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    debug!("no daemon listening on {:?}, resolving directly: {}", socket, e);
+                    None
+                },
+            }
+        },
+        false => None,
+    };
+    // a single file named outright keeps the original output
+    let single = !opt.stdin && opt.inputs.len() == 1 && inputs.len() == 1 && opt.inputs[0].as_path() == Path::new(&inputs[0]);
+    if single {
+        if let Some(client) = client.as_mut() {
+            let path = client.resolve(inputs[0].as_str(), opt.channel.as_deref(), opt.tag.as_deref(), &datetime_at)?;
+            println!("\npath: {}\n", path);
+            return Ok(());
+        }
+        let swinstall_stack = swinstall_stack_from_versionless(inputs[0].as_str())?;
+        debug!("swinstall_stack: {}", swinstall_stack.as_str());
+        let path = match opt.tag {
+            Some(tag) => parser.resolve_tag(swinstall_stack.as_str(), tag.as_str(), &datetime_at)?,
+            None => parser.current_in(swinstall_stack.as_str(), opt.channel.as_deref(), &datetime_at)?,
+        };
+        println!("\npath: {}\n", path);
+        return Ok(());
+    }
+    if inputs.is_empty() && unmatched.is_empty() {
+        return Err(SwInstallError::RuntimeError("no input supplied".to_string()).into());
+    }
+
+    let channel = opt.channel.as_deref();
+    let results = match (client.as_mut(), opt.tag) {
+        (Some(client), tag) => inputs.iter().map(|input| {
+            Ok(client.resolve(input.as_str(), channel, tag.as_deref(), &datetime_at)?)
+        }).collect(),
+        (None, Some(tag)) => inputs.iter().map(|input| {
+            let swinstall_stack = swinstall_stack_from_versionless(input.as_str())?;
+            parser.resolve_tag(swinstall_stack.as_str(), tag.as_str(), &datetime_at)
+        }).collect(),
+        (None, None) => parser.resolve_many_in(&inputs, channel, &datetime_at),
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut failed = 0;
+    for (input, result) in inputs.iter().zip(results) {
+        match result {
+            Ok(path) => writeln!(stdout, "{}: {}", input, path)?,
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: Error: {}", input, e);
+            },
+        }
+    }
+    if failed > 0 {
+        return Err(SwInstallError::RuntimeError(format!("unable to resolve {} of {} files", failed, inputs.len())).into());
+    }
+    if !unmatched.is_empty() {
+        return Err(SwInstallError::RuntimeError(format!("no files match {}", unmatched.join(", "))).into());
+    }
     Ok(())
 }
@@ -0,0 +1,434 @@
+//! changeset.rs
+//!
+//! Install and roll back several swinstalled files as a unit.
+//!
+//! A changeset groups the installs of related files (`packages.xml` and a matching
+//! env file, say) under a single id, which is recorded in the `changeset` attribute
+//! of each file's schema 2 elt tag:
+//!
+//! ```xml
+//! <elt action="install" changeset="jdoe-20181221-142313" datetime="20181221-142313" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+//! ```
+//!
+//! Changesets are applied using the same journaled transactions as single file installs
+//! (see `transaction`), with every member journaled before any file is touched, so that
+//! an interrupted changeset is either completed or reverted in its entirety.
+
+use chrono::{Duration, NaiveDateTime};
+use crate::{
+    constants::DATETIME_FMT,
+    errors::SwInstallError,
+    journal::Operation,
+    lock::hostname,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    traits::EltOptions,
+    transaction::{failpoint, lock_all, Member},
+    utils::{find_swinstall_stacks, swinstall_stack_from_versionless, versionless_from_swinstall_stack},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// distinguishes the changesets created by a process within the same second
+static CHANGESET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file touched by a changeset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangesetFile {
+    pub versionless: String,
+    pub action: String,
+    pub version: String,
+    pub datetime: NaiveDateTime,
+    /// the release channel the entry was recorded in, or None for the default channel
+    pub channel: Option<String>,
+}
+
+/// A changeset and the files it touched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changeset {
+    pub id: String,
+    pub files: Vec<ChangesetFile>,
+}
+
+impl Changeset {
+    /// The datetime of the earliest entry recorded for the changeset.
+    pub fn datetime(&self) -> Option<NaiveDateTime> {
+        self.files.iter().map(|f| f.datetime).min()
+    }
+}
+
+/// The outcome of rolling back a changeset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangesetRollback {
+    /// the id the rollback is recorded under
+    pub id: String,
+    /// the now current versioned files
+    pub versioned: Vec<String>,
+    /// the files which did not exist before the changeset, and so were left as they are
+    pub skipped: Vec<String>,
+}
+
+impl ChangesetRollback {
+    /// Whether every file touched by the changeset was rolled back.
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+/// Generate a new changeset id from the host, the process and the supplied datetime. Ids
+/// generated by the same process are distinct.
+pub fn new_changeset_id(datetime: &NaiveDateTime) -> String {
+    let count = CHANGESET_COUNTER.fetch_add(1, Ordering::Relaxed);
+    match count {
+        0 => format!("{}-{}-{}", hostname(), std::process::id(), datetime.format(DATETIME_FMT)),
+        _ => format!("{}-{}-{}-{}", hostname(), std::process::id(), datetime.format(DATETIME_FMT), count),
+    }
+}
+
+/// Install each `(source, versionless)` pair as a new version, recording them all under the
+/// changeset `id`. Either every file is installed or none are. Returns the paths to the
+/// versioned files, in the order supplied.
+pub fn install(
+    parser: &SwinstallParser,
+    id: &str,
+    files: &[(String, String)],
+    schema: &str,
+    datetime: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<Vec<String>, SwInstallError> {
+    install_effective(parser, id, files, schema, datetime, datetime, options)
+}
+
+/// As `install`, but with the changeset taking effect at `effective` rather than `now`. As
+/// with `transaction::install_effective`, only the versionless files of versions current as
+/// of `now` are rewritten.
+pub fn install_effective(
+    parser: &SwinstallParser,
+    id: &str,
+    files: &[(String, String)],
+    schema: &str,
+    effective: &NaiveDateTime,
+    now: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<Vec<String>, SwInstallError> {
+    let options = options.clone().changeset(id);
+    let versionless: Vec<String> = files.iter().map(|(_, v)| v.clone()).collect();
+    check_unique(&versionless)?;
+
+    let _locks = lock_all(&versionless)?;
+    let mut members = Vec::new();
+    for (source, file) in files {
+        members.push(Member::new_version(parser, Operation::Install, file.as_str(), source.as_str(), schema, effective, now, &options)?);
+    }
+    apply(id, &versionless, members)
+}
+
+/// Roll back every file touched by the changeset `id` beneath `root` to the version that was
+/// current immediately before the changeset was installed, recording the rollbacks as a
+/// changeset of their own. Each file is rolled back within the release channel its changeset
+/// entry was recorded in, so only rollbacks in the default channel rewrite the versionless
+/// file. Files which did not exist in that channel before the changeset cannot be rolled
+/// back, and are reported as skipped.
+pub fn rollback(
+    parser: &SwinstallParser,
+    root: &str,
+    id: &str,
+    datetime: &NaiveDateTime,
+) -> Result<ChangesetRollback, SwInstallError> {
+    let changeset = changesets(parser, root)?
+                        .into_iter()
+                        .find(|c| c.id == id)
+                        .ok_or_else(|| SwInstallError::RuntimeError(format!("changeset {} not found under {}", id, root)))?;
+
+    // find the earliest entry for each file, and the channel it was recorded in
+    let mut targets: BTreeMap<String, &ChangesetFile> = BTreeMap::new();
+    for file in &changeset.files {
+        let earliest = targets.entry(file.versionless.clone()).or_insert(file);
+        if earliest.channel != file.channel {
+            return Err(SwInstallError::RuntimeError(format!(
+                "changeset {} touches {} in more than one channel", id, file.versionless
+            )));
+        }
+        if file.datetime < earliest.datetime {
+            *earliest = file;
+        }
+    }
+
+    let rollback_id = new_changeset_id(datetime);
+    let versionless: Vec<String> = targets.keys().cloned().collect();
+    let _locks = lock_all(&versionless)?;
+    let mut members = Vec::new();
+    let mut skipped = Vec::new();
+    for (file, earliest) in &targets {
+        let channel = earliest.channel.as_deref();
+        let swinstall_stack = swinstall_stack_from_versionless(file.as_str())?;
+        let prior = earliest.datetime - Duration::seconds(1);
+        let version = match parser.version_in(swinstall_stack.as_str(), channel, &prior) {
+            Ok(version) => version,
+            Err(_) => {
+                warn!("{} did not exist before changeset {}. leaving it in place", file, id);
+                skipped.push(file.clone());
+                continue;
+            }
+        };
+        let mut options = EltOptions::default().changeset(rollback_id.as_str());
+        if let Some(channel) = channel {
+            options = options.channel(channel);
+        }
+        members.push(Member::existing_version(parser, Operation::Rollback, file.as_str(), version.as_str(), datetime, &options)?);
+    }
+    let rolled_back: Vec<String> = versionless.into_iter().filter(|f| !skipped.contains(f)).collect();
+    let versioned = apply(rollback_id.as_str(), &rolled_back, members)?;
+    Ok(ChangesetRollback { id: rollback_id, versioned, skipped })
+}
+
+/// List the changesets recorded in the swinstall_stacks beneath `root`, ordered by the
+/// datetime at which each was first applied.
+pub fn changesets(parser: &SwinstallParser, root: &str) -> Result<Vec<Changeset>, SwInstallError> {
+    let mut found: BTreeMap<String, Vec<ChangesetFile>> = BTreeMap::new();
+
+    for swinstall_stack in find_swinstall_stacks(root)? {
+        let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+        let entries = parser.component_for(&stack)?.changesets(&stack)?;
+        if entries.is_empty() {
+            continue;
+        }
+        let versionless = versionless_from_swinstall_stack(swinstall_stack.as_str())?;
+        for entry in entries {
+            found.entry(entry.changeset).or_default().push(ChangesetFile {
+                versionless: versionless.clone(),
+                action: entry.action,
+                version: entry.version,
+                datetime: entry.datetime,
+                channel: entry.channel,
+            });
+        }
+    }
+
+    let mut changesets: Vec<Changeset> = found.into_iter()
+                                              .map(|(id, files)| Changeset { id, files })
+                                              .collect();
+    changesets.sort_by_key(|c| c.datetime());
+    Ok(changesets)
+}
+
+// journal every member, write the versioned files, mark every member prepared, and
+// finally update the stacks and versionless files.
+fn apply(id: &str, versionless: &[String], mut members: Vec<Member>) -> Result<Vec<String>, SwInstallError> {
+    for member in members.iter_mut() {
+        member.set_changeset(id, versionless);
+    }
+
+    let mut begun = Vec::new();
+    let mut result = Ok(());
+    for mut member in members {
+        result = member.begin().and_then(|_| member.write_versioned());
+        begun.push(member);
+        if result.is_err() {
+            break;
+        }
+    }
+    failpoint("versioned");
+
+    // until every member is prepared, a failure reverts the lot
+    if let Err(e) = result {
+        for member in begun {
+            if let Err(abort_err) = member.abort() {
+                warn!("unable to abort changeset {} member: {}", id, abort_err);
+            }
+        }
+        return Err(e);
+    }
+
+    for (idx, member) in begun.iter_mut().enumerate() {
+        member.prepare()?;
+        if idx == 0 {
+            failpoint("prepared");
+        }
+    }
+
+    let mut versioned = Vec::new();
+    for member in begun {
+        versioned.push(member.commit()?);
+    }
+    Ok(versioned)
+}
+
+// error if the same versionless file appears twice in a changeset
+fn check_unique(versionless: &[String]) -> Result<(), SwInstallError> {
+    let mut sorted = versionless.to_vec();
+    sorted.sort();
+    for pair in sorted.windows(2) {
+        if pair[0] == pair[1] {
+            return Err(SwInstallError::RuntimeError(format!("{} appears more than once in changeset", pair[0])));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::datetime,
+        transaction::{self, Recovery},
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn file(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn install_list_and_rollback_changeset() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let env = dir.path().join("env.yaml").to_str().unwrap().to_string();
+
+        transaction::install(&parser, packages.as_str(), file(&dir, "p1", "p1").as_str(), "2",
+                             &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        transaction::install(&parser, env.as_str(), file(&dir, "e1", "e1").as_str(), "2",
+                             &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+
+        let files = vec![
+            (file(&dir, "p2", "p2"), packages.clone()),
+            (file(&dir, "e2", "e2"), env.clone()),
+        ];
+        install(&parser, "cs1", &files, "2", &datetime("20181221-142248"), &EltOptions::default()).expect("changeset install failed");
+        assert_eq!(fs::read_to_string(&packages).unwrap(), "p2");
+        assert_eq!(fs::read_to_string(&env).unwrap(), "e2");
+
+        let found = changesets(&parser, root).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, "cs1");
+        assert_eq!(found[0].files.len(), 2);
+        assert_eq!(found[0].datetime(), Some(datetime("20181221-142248")));
+
+        let rolled_back = rollback(&parser, root, "cs1", &datetime("20181221-142313")).expect("changeset rollback failed");
+        assert!(rolled_back.is_complete());
+        assert_eq!(rolled_back.versioned.len(), 2);
+        assert_eq!(fs::read_to_string(&packages).unwrap(), "p1");
+        assert_eq!(fs::read_to_string(&env).unwrap(), "e1");
+        let swinstall_stack = swinstall_stack_from_versionless(packages.as_str()).unwrap();
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap(), "1");
+
+        // the rollback is a changeset of its own
+        assert_ne!(rolled_back.id, "cs1");
+        let found = changesets(&parser, root).unwrap();
+        let ids: Vec<&str> = found.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["cs1", rolled_back.id.as_str()]);
+        assert!(found[0].files.iter().all(|f| f.action == "install"));
+        assert!(found[1].files.iter().all(|f| f.action == "rollback"));
+    }
+
+    #[test]
+    fn rollback_channel_changeset() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let env = dir.path().join("env.yaml").to_str().unwrap().to_string();
+        let dev = EltOptions::default().channel("dev");
+        for (versionless, name) in &[(&packages, "p"), (&env, "e")] {
+            transaction::install(&parser, versionless.as_str(), file(&dir, &format!("{}1", name), "default").as_str(), "2",
+                                 &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+            transaction::install(&parser, versionless.as_str(), file(&dir, &format!("{}2", name), "dev").as_str(), "2",
+                                 &datetime("20181221-110000"), &dev).unwrap();
+        }
+
+        let files = vec![
+            (file(&dir, "p3", "p3"), packages.clone()),
+            (file(&dir, "e3", "e3"), env.clone()),
+        ];
+        install(&parser, "cs1", &files, "2", &datetime("20181221-142248"), &dev).expect("changeset install failed");
+        let found = changesets(&parser, root).unwrap();
+        assert!(found[0].files.iter().all(|f| f.channel.as_deref() == Some("dev")));
+
+        let rolled_back = rollback(&parser, root, "cs1", &datetime("20181221-142313")).expect("changeset rollback failed");
+        assert!(rolled_back.is_complete());
+        for versionless in &[&packages, &env] {
+            // rolled back to the previous dev version, leaving the default channel and the
+            // versionless file alone
+            let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+            assert_eq!(parser.version_in(swinstall_stack.as_str(), Some("dev"), &datetime("20181221-150000")).unwrap(), "2");
+            assert_eq!(parser.version_in(swinstall_stack.as_str(), None, &datetime("20181221-150000")).unwrap(), "1");
+            assert_eq!(fs::read_to_string(versionless.as_str()).unwrap(), "default");
+        }
+        let found = changesets(&parser, root).unwrap();
+        assert!(found[1].files.iter().all(|f| f.action == "rollback" && f.channel.as_deref() == Some("dev")));
+    }
+
+    #[test]
+    fn rollback_reports_new_files() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let env = dir.path().join("env.yaml").to_str().unwrap().to_string();
+        transaction::install(&parser, packages.as_str(), file(&dir, "p1", "p1").as_str(), "2",
+                             &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+
+        // env.yaml is new with the changeset
+        let files = vec![
+            (file(&dir, "p2", "p2"), packages.clone()),
+            (file(&dir, "e1", "e1"), env.clone()),
+        ];
+        install(&parser, "cs1", &files, "2", &datetime("20181221-142248"), &EltOptions::default()).unwrap();
+        let rolled_back = rollback(&parser, root, "cs1", &datetime("20181221-142313")).unwrap();
+        assert!(!rolled_back.is_complete());
+        assert_eq!(rolled_back.skipped, vec![env.clone()]);
+        assert_eq!(rolled_back.versioned.len(), 1);
+        assert_eq!(fs::read_to_string(&packages).unwrap(), "p1");
+        assert_eq!(fs::read_to_string(&env).unwrap(), "e1");
+    }
+
+    #[test]
+    fn aborted_member_leaves_nothing_behind() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        transaction::install(&parser, packages.as_str(), file(&dir, "p1", "p1").as_str(), "2",
+                             &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        let bak = dir.path().join("bak").join("packages.xml");
+        let before: Vec<_> = fs::read_dir(&bak).unwrap().map(|e| e.unwrap().file_name()).collect();
+
+        let source = file(&dir, "p2", "p2");
+        let mut member = Member::new_version(&parser, Operation::Install, packages.as_str(), source.as_str(), "2",
+                                             &datetime("20181221-142248"), &datetime("20181221-142248"),
+                                             &EltOptions::default()).unwrap();
+        member.begin().unwrap();
+        member.write_versioned().unwrap();
+        member.abort().unwrap();
+        let mut after: Vec<_> = fs::read_dir(&bak).unwrap().map(|e| e.unwrap().file_name()).collect();
+        after.sort();
+        let mut before = before;
+        before.sort();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn failed_changeset_installs_nothing() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let env = dir.path().join("env.yaml").to_str().unwrap().to_string();
+
+        let files = vec![
+            (file(&dir, "p1", "p1"), packages.clone()),
+            (dir.path().join("missing").to_str().unwrap().to_string(), env.clone()),
+        ];
+        assert!(install(&parser, "cs1", &files, "2", &datetime("20181221-142248"), &EltOptions::default()).is_err());
+        assert!(!dir.path().join("packages.xml").exists());
+        assert!(!dir.path().join("bak/packages.xml/packages.xml_1").exists());
+        assert_eq!(transaction::recover(packages.as_str()).unwrap(), Recovery::Clean);
+    }
+}
@@ -0,0 +1,274 @@
+//! compress.rs
+//!
+//! Store old versioned files compressed.
+//!
+//! A versioned file which has not been in use for a while may be replaced by a gzipped copy
+//! alongside it, `<file>_<version>.gz`. The swinstall_stack is unchanged; the version is
+//! simply stored differently. Anything reading versioned files should go through
+//! `open_versioned`, `read_versioned` or `versioned_hash`, which look for either form, so
+//! that callers need not care how a version is stored.
+//!
+//! Versions in use (current in any channel, staged, or scheduled) are never compressed, so
+//! the paths handed out by `SwinstallParser::current_at` for the present are always usable
+//! as they stand. A lookup in the past may resolve to a compressed version;
+//! `SwinstallParser::open_in` streams the contents of either. Rolling back to a compressed
+//! version restores the uncompressed file.
+
+use chrono::NaiveDateTime;
+use crate::{
+    constants::COMPRESSED_SUFFIX,
+    errors::SwInstallError,
+    lock::StackLock,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    transaction::pending_check,
+    utils::{swinstall_stack_from_versionless, versioned_from_versionless, write_atomic_as},
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression as Level};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// The path of the compressed form of the supplied versioned file.
+pub fn compressed_path(versioned: &str) -> String {
+    format!("{}{}", versioned, COMPRESSED_SUFFIX)
+}
+
+/// The path of the file holding the contents of the supplied versioned file, compressed or
+/// not, if there is one.
+pub fn stored_path(versioned: &str) -> Option<String> {
+    if Path::new(versioned).exists() {
+        return Some(versioned.to_string());
+    }
+    let compressed = compressed_path(versioned);
+    if Path::new(&compressed).exists() {
+        return Some(compressed);
+    }
+    None
+}
+
+/// Whether the supplied versioned file is stored compressed.
+pub fn is_compressed(versioned: &str) -> bool {
+    !Path::new(versioned).exists() && Path::new(&compressed_path(versioned)).exists()
+}
+
+/// Open the supplied versioned file for reading, decompressing it if it is stored compressed.
+pub fn open_versioned(versioned: &str) -> Result<Box<dyn Read>, SwInstallError> {
+    match File::open(versioned) {
+        Ok(file) => Ok(Box::new(BufReader::new(file))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let file = File::open(compressed_path(versioned)).map_err(|_| {
+                SwInstallError::RuntimeError(format!("versioned file {} does not exist", versioned))
+            })?;
+            Ok(Box::new(GzDecoder::new(BufReader::new(file))))
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the contents of the supplied versioned file, however it is stored.
+pub fn read_versioned(versioned: &str) -> Result<Vec<u8>, SwInstallError> {
+    let mut contents = Vec::new();
+    open_versioned(versioned)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Calculate the hash of the contents of the supplied versioned file, however it is stored.
+pub fn versioned_hash(versioned: &str) -> Result<String, SwInstallError> {
+    let mut reader = open_versioned(versioned)?;
+    let mut context = md5::Context::new();
+    let mut buf = [0; 8192];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => context.consume(&buf[..n]),
+        }
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+/// Replace the supplied versioned file with a compressed copy, returning the size of the
+/// copy. The copy keeps the modification time, mode and group of the original.
+pub fn compress_file(versioned: &str) -> Result<u64, SwInstallError> {
+    let compressed = gzip(versioned)?;
+    store_compressed(versioned, &compressed)?;
+    Ok(compressed.len() as u64)
+}
+
+/// Restore the uncompressed form of the supplied versioned file, if it is stored compressed.
+/// The restored file takes the mode and group of the compressed copy.
+pub fn decompress_file(versioned: &str) -> Result<(), SwInstallError> {
+    if !is_compressed(versioned) {
+        return Ok(());
+    }
+    let compressed = compressed_path(versioned);
+    write_atomic_as(versioned, &read_versioned(versioned)?, compressed.as_str())?;
+    File::open(versioned)?.set_modified(fs::metadata(&compressed)?.modified()?)?;
+    fs::remove_file(&compressed)?;
+    info!("decompressed {}", versioned);
+    Ok(())
+}
+
+// replace the versioned file with the supplied compressed contents
+fn store_compressed(versioned: &str, compressed: &[u8]) -> Result<(), SwInstallError> {
+    let target = compressed_path(versioned);
+    write_atomic_as(target.as_str(), compressed, versioned)?;
+    File::open(&target)?.set_modified(fs::metadata(versioned)?.modified()?)?;
+    fs::remove_file(versioned)?;
+    Ok(())
+}
+
+// the gzipped contents of the file at `path`
+fn gzip(path: &str) -> Result<Vec<u8>, SwInstallError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.flush()?;
+    Ok(encoder.finish()?)
+}
+
+/// A versioned file which was, or would be, compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedVersion {
+    pub version: String,
+    /// path to the uncompressed versioned file
+    pub path: String,
+    /// size of the uncompressed file
+    pub bytes: u64,
+    /// size of the compressed file
+    pub compressed_bytes: u64,
+}
+
+/// The outcome of compressing the versioned files of a swinstalled file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    pub compressed: Vec<CompressedVersion>,
+    /// whether this was a dry run, leaving everything in place
+    pub dry_run: bool,
+}
+
+impl Compression {
+    /// The number of bytes saved, or which would be saved by a dry run.
+    pub fn bytes(&self) -> u64 {
+        self.compressed.iter().map(|c| c.bytes.saturating_sub(c.compressed_bytes)).sum()
+    }
+}
+
+/// Compress the versioned files of the `versionless` file which were installed before
+/// `older_than` and are no longer in use as of `datetime`. Files which would not shrink, and
+/// files hard linked to others (see `dedup`), are left alone. Nothing is touched when
+/// `dry_run` is set.
+pub fn compress(
+    parser: &SwinstallParser,
+    versionless: &str,
+    older_than: &NaiveDateTime,
+    dry_run: bool,
+    datetime: &NaiveDateTime,
+) -> Result<Compression, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    let component = parser.component_for(&stack)?;
+    let mut result = Compression { compressed: Vec::new(), dry_run };
+    for record in component.versions(&stack, datetime)? {
+        if record.in_use || record.pruned || record.installed >= *older_than {
+            continue;
+        }
+        let path = versioned_from_versionless(versionless, record.version.as_str())?;
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            // already compressed, or missing
+            Err(_) => continue,
+        };
+        if metadata.nlink() > 1 {
+            debug!("{} is shared with another version; leaving it uncompressed", path);
+            continue;
+        }
+        let compressed = gzip(path.as_str())?;
+        if compressed.len() as u64 >= metadata.len() {
+            continue;
+        }
+        if !dry_run {
+            store_compressed(path.as_str(), &compressed)?;
+            info!("compressed {}", path);
+        }
+        result.compressed.push(CompressedVersion {
+            version: record.version,
+            path,
+            bytes: metadata.len(),
+            compressed_bytes: compressed.len() as u64,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{datetime, install_daily},
+        traits::EltOptions,
+        transaction,
+        utils::file_hash,
+    };
+    use tempfile::TempDir;
+
+    #[test]
+    fn old_versions_compressed_and_read_transparently() {
+        let dir = TempDir::new().unwrap();
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let contents: Vec<String> = (1..=3).map(|i| format!("<packages version=\"{}\"/>\n", i).repeat(100)).collect();
+        let versionless = install_daily(&parser, &dir, "packages.xml", &contents);
+        let first = versioned_from_versionless(versionless.as_str(), "1").unwrap();
+        let hash = file_hash(first.as_str()).unwrap();
+        let now = datetime("20190201-120000");
+
+        let dry = compress(&parser, versionless.as_str(), &datetime("20190103-000000"), true, &now).unwrap();
+        assert_eq!(dry.compressed.len(), 2);
+        assert!(dry.bytes() > 0);
+        assert!(!is_compressed(first.as_str()));
+
+        // the current version is never compressed
+        let result = compress(&parser, versionless.as_str(), &now, false, &now).unwrap();
+        assert_eq!(result.compressed.iter().map(|c| c.version.as_str()).collect::<Vec<_>>(), vec!["2", "1"]);
+        assert!(is_compressed(first.as_str()));
+        assert_eq!(stored_path(first.as_str()), Some(compressed_path(first.as_str())));
+        assert_eq!(versioned_hash(first.as_str()).unwrap(), hash);
+
+        // lookups in the past stream the compressed contents
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        let mut contents = String::new();
+        parser.open_in(swinstall_stack.as_str(), None, &datetime("20190101-130000")).unwrap()
+            .read_to_string(&mut contents).unwrap();
+        assert!(contents.starts_with("<packages version=\"1\"/>"));
+
+        // rolling back restores the uncompressed file
+        transaction::rollback(&parser, versionless.as_str(), "1", &now, &EltOptions::default()).unwrap();
+        assert!(!is_compressed(first.as_str()));
+        assert_eq!(file_hash(versionless.as_str()).unwrap(), hash);
+        assert_eq!(file_hash(first.as_str()).unwrap(), hash);
+    }
+
+    #[test]
+    fn compression_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let versioned = dir.path().join("packages.xml_1").to_str().unwrap().to_string();
+        fs::write(&versioned, "restricted").unwrap();
+        fs::set_permissions(&versioned, fs::Permissions::from_mode(0o640)).unwrap();
+        let mode = |path: &str| fs::metadata(path).unwrap().mode() & 0o7777;
+
+        compress_file(versioned.as_str()).unwrap();
+        assert_eq!(mode(compressed_path(versioned.as_str()).as_str()), 0o640);
+        decompress_file(versioned.as_str()).unwrap();
+        assert_eq!(mode(versioned.as_str()), 0o640);
+        assert_eq!(fs::read_to_string(&versioned).unwrap(), "restricted");
+    }
+}
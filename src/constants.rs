@@ -1,3 +1,18 @@
 pub const DATETIME_FMT: &'static str = "%Y%m%d-%H%M%S";
 pub const DEFAULT_LOG_LEVEL: &'static str = "warn";
-pub const VERBOSE_LOG_LEVEL: &'static str = "debug";
\ No newline at end of file
+pub const VERBOSE_LOG_LEVEL: &'static str = "debug";
+/// Number of seconds to wait on a swinstall_stack lock before giving up
+pub const LOCK_TIMEOUT_SECS: u64 = 30;
+/// Age, in seconds, after which a lock held by another host is considered stale
+pub const STALE_LOCK_SECS: u64 = 600;
+/// Environment variable naming a transaction step at which to abort the process. Used
+/// to exercise recovery of interrupted transactions.
+pub const FAILPOINT_ENV: &str = "SWINSTALL_FAILPOINT";
+/// Suffix of versioned files stored compressed
+pub const COMPRESSED_SUFFIX: &str = ".gz";
+/// Default age, in days, after which versioned files are compressed
+pub const DEFAULT_COMPRESS_DAYS: i64 = 30;
+/// Environment variable naming the socket of the resolver daemon, overriding the default
+pub const SOCKET_ENV: &str = "SWINSTALL_SOCKET";
+/// Number of stacks the resolver daemon holds in memory before dropping the least recently used
+pub const DAEMON_CACHE_STACKS: usize = 4096;
@@ -0,0 +1,141 @@
+//! convert.rs
+//!
+//! Conversion of swinstall_stacks to and from JSON, YAML and TOML.
+//!
+//! Conversions go through `StackDocument`, so every element and attribute, including
+//! those we do not otherwise interpret and their children, survives the trip; converting a
+//! stack to any of the supported formats and back yields the same document. Formatting does
+//! not: comments, whitespace and the xml declaration are dropped, and an imported stack is
+//! written in the canonical form.
+
+use crate::{
+    errors::SwInstallError,
+    stack::StackDocument,
+};
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+/// A serialization format a swinstall_stack may be converted to or from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Yaml => write!(f, "yaml"),
+            Format::Toml => write!(f, "toml"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = SwInstallError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(SwInstallError::ConversionError(format!("unknown format: {}", s))),
+        }
+    }
+}
+
+/// Serialize the stack in the supplied format.
+pub fn export(stack: &StackDocument, format: Format) -> Result<String, SwInstallError> {
+    let result = match format {
+        Format::Json => serde_json::to_string_pretty(stack).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::to_string(stack).map_err(|e| e.to_string()),
+        Format::Toml => toml::to_string(stack).map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| SwInstallError::ConversionError(format!("{}: {}", format, e)))
+}
+
+/// Parse a stack serialized in the supplied format. The root element must be stack_history.
+pub fn import(contents: &str, format: Format) -> Result<StackDocument, SwInstallError> {
+    let result: Result<StackDocument, String> = match format {
+        Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        Format::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+    };
+    let stack = result.map_err(|e| SwInstallError::ConversionError(format!("{}: {}", format, e)))?;
+    if stack.root.name != "stack_history" {
+        return Err(SwInstallError::ConversionError(format!("{}: root is {}, not stack_history", format, stack.root.name)));
+    }
+    Ok(stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA2: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+   <elt action="install" channel="dev" datetime="20181222-103000" hash="0f343b0931126a20f133d67c2b018a3b" version="6"/>
+   <elt action="install" datetime="20181221-142313" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+   <elt version="4" hash="5c8fdabe2ae7fa9287c0672b88ef6593" datetime="20181221-142248" action="install"/>
+   <tag datetime="20181222-103000" name="known-good" version="5"/>
+</stack_history>
+"#;
+
+    #[test]
+    fn round_trip_formats() {
+        let stack = StackDocument::from_xml(SCHEMA2).unwrap();
+        for format in &[Format::Json, Format::Yaml, Format::Toml] {
+            let exported = export(&stack, *format).expect("unable to export");
+            let imported = import(exported.as_str(), *format).expect("unable to import");
+            assert_eq!(imported, stack, "{} round trip", format);
+            assert_eq!(imported.to_xml(), SCHEMA2);
+        }
+    }
+
+    #[test]
+    fn round_trip_drops_formatting() {
+        let xml = r#"<?xml version="1.0"?>
+<!-- edited by hand -->
+<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+  <elt action="install" datetime="20181221-142313" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+  <!-- reviewed -->
+  <note author="jdoe"><text>hotfix for <b>show</b></text></note>
+</stack_history>
+"#;
+        let canonical = r#"<?xml version="1.0" encoding="UTF-8"?>
+<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+   <elt action="install" datetime="20181221-142313" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+   <note author="jdoe"><text>hotfix for <b>show</b></text></note>
+</stack_history>
+"#;
+        let stack = StackDocument::from_xml(xml).unwrap();
+        for format in &[Format::Json, Format::Yaml, Format::Toml] {
+            let imported = import(export(&stack, *format).unwrap().as_str(), *format).expect("unable to import");
+            // elements, attributes and children survive, comments and whitespace do not
+            assert_eq!(imported, stack, "{} round trip", format);
+            assert_eq!(imported.to_xml(), canonical);
+        }
+    }
+
+    #[test]
+    fn import_requires_stack_history() {
+        let json = r#"{"root": {"name": "packages", "attributes": {}}, "elements": []}"#;
+        match import(json, Format::Json) {
+            Err(SwInstallError::ConversionError(msg)) => assert!(msg.contains("stack_history")),
+            other => panic!("expected a conversion error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_attributes_are_a_map() {
+        let stack = StackDocument::from_xml(SCHEMA2).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&export(&stack, Format::Json).unwrap()).unwrap();
+        assert_eq!(value["root"]["attributes"]["schema"], "2");
+        assert_eq!(value["elements"][1]["attributes"]["version"], "5");
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,749 @@
+//! daemon.rs
+//!
+//! A long running resolver, answering lookups over a Unix domain socket.
+//!
+//! Starting a process and parsing a swinstall_stack for every lookup adds up when the same
+//! stacks are resolved over and over. `swinst serve` keeps the stacks it reads in memory
+//! instead, and answers requests for as long as it runs. Each cached stack is checked against
+//! its modification time, size and inode (see `IndexKey`) before it is used, and is dropped
+//! from memory as soon as it changes, where the daemon can watch the directory holding it with
+//! inotify. Stacks are cached by their path with the directory canonicalized, so that a stack
+//! reached by several paths is cached, and dropped, once. A directory which cannot be watched,
+//! say for want of inotify watches, is left to the key check. The daemon holds up to
+//! `DAEMON_CACHE_STACKS` stacks, dropping the least recently used to make room for more.
+//!
+//! The protocol is line delimited JSON. Each request is a JSON object on a line of its own,
+//! and is answered by a JSON object on a line of its own:
+//!
+//! ```text
+//! {"op": "resolve", "path": "/dd/facility/etc/packages.xml", "datetime": "20190101-120000"}
+//! {"ok": true, "path": "/dd/facility/etc/bak/packages.xml/packages.xml_4", "version": "4"}
+//! {"op": "history", "path": "/dd/facility/etc/packages.xml"}
+//! {"ok": true, "history": [{"action": "install", "datetime": "20181221-142313", "version": "5"}, ...]}
+//! {"op": "status"}
+//! {"ok": true, "status": {"pid": 4242, "started": "20190101-090000", "requests": 3, ...}}
+//! ```
+//!
+//! Paths are those of versionless files, and should be absolute. `resolve` accepts an
+//! optional `datetime` (defaulting to now), `channel` and `tag`, as the command line does.
+//! Failures are answered with `{"ok": false, "error": "..."}`.
+//!
+//! The socket lives in a directory only its user may write to, so that no one else can put a
+//! socket of their own in its place, and clients only trust a daemon run by the same user.
+
+use chrono::{Local, NaiveDateTime};
+use crate::{
+    archive::{archive_link, merge_archive},
+    constants::{DAEMON_CACHE_STACKS, DATETIME_FMT, SOCKET_ENV},
+    errors::SwInstallError,
+    index::IndexKey,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    traits::{tagged_version, TimelineEvent},
+    utils::{parse_datetime, swinstall_stack_from_versionless, versioned_from_swinstall_stack},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// The socket the daemon listens on unless told otherwise: `$SWINSTALL_SOCKET`, or a socket
+/// in `$XDG_RUNTIME_DIR`, or failing that, in a directory of the temporary directory named for
+/// the user.
+pub fn default_socket() -> PathBuf {
+    if let Some(socket) = env::var_os(SOCKET_ENV) {
+        return PathBuf::from(socket);
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("swinst.sock"),
+        _ => env::temp_dir().join(format!("swinst-{}", unsafe { libc::getuid() })).join("swinst.sock"),
+    }
+}
+
+// check that the supplied directory is ours, and that no one else may write to it, creating
+// it if need be
+fn private_dir(dir: &Path) -> Result<(), SwInstallError> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        result => result?,
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o022 != 0 {
+        return Err(SwInstallError::RuntimeError(
+            format!("{} must be a directory of our own, which no one else may write to", dir.display())
+        ));
+    }
+    Ok(())
+}
+
+// the user at the other end of the supplied connection
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// A request to the daemon.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// Resolve the versioned file of a versionless file
+    Resolve {
+        path: String,
+        /// defaults to now
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        datetime: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+    },
+    /// Retrieve the history of a versionless file
+    History { path: String },
+    /// Report on the daemon itself
+    Status,
+}
+
+/// An event in the history of a swinstalled file, as sent over the wire. See `TimelineEvent`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEvent {
+    pub action: String,
+    pub datetime: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changeset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+}
+
+impl From<TimelineEvent> for HistoryEvent {
+    fn from(event: TimelineEvent) -> Self {
+        HistoryEvent {
+            action: event.action,
+            datetime: event.datetime.format(DATETIME_FMT).to_string(),
+            version: event.version,
+            changeset: event.changeset,
+            channel: event.channel,
+            expires: event.expires.map(|dt| dt.format(DATETIME_FMT).to_string()),
+        }
+    }
+}
+
+/// The state of the daemon, as reported by a `status` request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub pid: u32,
+    pub started: String,
+    /// requests answered, including this one
+    pub requests: u64,
+    /// lookups of a stack answered from memory
+    pub hits: u64,
+    /// lookups of a stack which had to read it
+    pub misses: u64,
+    /// stacks dropped from memory because they changed
+    pub invalidations: u64,
+    /// stacks held in memory
+    pub cached: usize,
+    /// whether changes are picked up by inotify, rather than by checking each stack
+    pub watching: bool,
+}
+
+/// The answer to a request. Only the fields relevant to the request are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<HistoryEvent>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    fn error(error: impl std::fmt::Display) -> Self {
+        Response { error: Some(error.to_string()), ..Response::default() }
+    }
+}
+
+// a stack held in memory, along with the key of the file it was read from
+struct CachedStack {
+    stack: Arc<StackDocument>,
+    key: IndexKey,
+    // the tick of the lookup which last used the stack
+    used: u64,
+}
+
+/// Answers requests from the stacks it holds in memory. Shared between the connections of a
+/// daemon, and the thread watching for changes.
+pub struct Resolver {
+    parser: SwinstallParser,
+    cache: Mutex<HashMap<String, CachedStack>>,
+    // the number of stacks held in memory at most
+    capacity: usize,
+    // counts lookups, ordering the use of cached stacks
+    ticks: AtomicU64,
+    watcher: Option<Watcher>,
+    // bumped by every invalidation, so that a stack read while it changed is not cached
+    generation: AtomicU64,
+    started: NaiveDateTime,
+    requests: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Resolver").field("parser", &self.parser).field("started", &self.started).finish()
+    }
+}
+
+impl Resolver {
+    /// new up a Resolver, and start watching for changes to the stacks it caches.
+    pub fn new(parser: SwinstallParser) -> Arc<Self> {
+        Self::with_capacity(parser, DAEMON_CACHE_STACKS)
+    }
+
+    /// new up a Resolver holding at most `capacity` stacks in memory.
+    pub fn with_capacity(parser: SwinstallParser, capacity: usize) -> Arc<Self> {
+        let watcher = match Watcher::new() {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("unable to watch for changes, checking each stack instead: {}", e);
+                None
+            },
+        };
+        let resolver = Arc::new(Resolver {
+            parser,
+            cache: Mutex::new(HashMap::new()),
+            capacity,
+            ticks: AtomicU64::new(0),
+            watcher,
+            generation: AtomicU64::new(0),
+            started: Local::now().naive_local(),
+            requests: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        });
+        if resolver.watcher.is_some() {
+            let watching = Arc::clone(&resolver);
+            thread::spawn(move || {
+                let watcher = watching.watcher.as_ref().expect("watcher");
+                if let Err(e) = watcher.run(|path| watching.invalidate(path)) {
+                    warn!("stopped watching for changes: {}", e);
+                }
+            });
+        }
+        resolver
+    }
+
+    /// Answer the supplied request.
+    pub fn handle(&self, request: Request) -> Response {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let result = match request {
+            Request::Resolve { path, datetime, channel, tag } => {
+                self.resolve(path.as_str(), datetime.as_deref(), channel.as_deref(), tag.as_deref())
+            },
+            Request::History { path } => self.history(path.as_str()).map(|history| {
+                Response { history: Some(history.into_iter().map(HistoryEvent::from).collect()), ..Response::default() }
+            }),
+            Request::Status => Ok(Response { status: Some(self.status()), ..Response::default() }),
+        };
+        match result {
+            Ok(response) => Response { ok: true, ..response },
+            Err(e) => Response::error(e),
+        }
+    }
+
+    /// Drop the stack at the supplied path from memory, or every stack for None.
+    pub fn invalidate(&self, swinstall_stack: Option<&Path>) {
+        let key = swinstall_stack.map(|path| path.to_str().and_then(|path| cache_key(path).ok()));
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let mut cache = self.cache.lock().expect("cache poisoned");
+        let dropped = match key {
+            Some(key) => key.and_then(|key| cache.remove(key.to_str()?)).map(|_| 1).unwrap_or(0) as u64,
+            None => cache.drain().count() as u64,
+        };
+        if dropped > 0 {
+            debug!("dropped {} cached stacks", dropped);
+            self.invalidations.fetch_add(dropped, Ordering::Relaxed);
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            pid: std::process::id(),
+            started: self.started.format(DATETIME_FMT).to_string(),
+            requests: self.requests.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            cached: self.cache.lock().expect("cache poisoned").len(),
+            watching: self.watcher.is_some(),
+        }
+    }
+
+    fn resolve(&self, versionless: &str, datetime: Option<&str>, channel: Option<&str>, tag: Option<&str>) -> Result<Response, failure::Error> {
+        let datetime = match datetime {
+            Some(datetime) => parse_datetime(datetime)?,
+            None => Local::now().naive_local(),
+        };
+        let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+        let stack = self.stack(swinstall_stack.as_str())?;
+        let component = self.parser.component_for(&stack)?;
+        let version = match tag {
+            Some(tag) => tagged_version(&component.tags(&stack)?, tag, &datetime)
+                .ok_or_else(|| SwInstallError::TagNotFound(tag.to_string()))?
+                .to_string(),
+            None => {
+                // lookups before the entries left in an archived stack need the archive
+                let resolved = match archive_link(swinstall_stack.as_str(), &stack)? {
+                    Some((archive, before)) if datetime < before => {
+                        let merged = merge_archive(&self.parser, &stack, (*self.stack(archive.as_str())?).clone())?;
+                        component.current_in_stack(&merged, &datetime, channel)?
+                    },
+                    _ => component.current_in_stack(&stack, &datetime, channel)?,
+                };
+                resolved.0
+            },
+        };
+        Ok(Response {
+            path: Some(versioned_from_swinstall_stack(swinstall_stack.as_str(), version.as_str())?),
+            version: Some(version),
+            ..Response::default()
+        })
+    }
+
+    fn history(&self, versionless: &str) -> Result<Vec<TimelineEvent>, failure::Error> {
+        let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+        let stack = self.stack(swinstall_stack.as_str())?;
+        let stack = match archive_link(swinstall_stack.as_str(), &stack)? {
+            Some((archive, _)) => merge_archive(&self.parser, &stack, (*self.stack(archive.as_str())?).clone())?,
+            None => (*stack).clone(),
+        };
+        Ok(self.parser.component_for(&stack)?.timeline(&stack)?)
+    }
+
+    // the stack at the supplied path, from memory if we have it
+    fn stack(&self, swinstall_stack: &str) -> Result<Arc<StackDocument>, SwInstallError> {
+        let swinstall_stack = cache_key(swinstall_stack)?;
+        let swinstall_stack = swinstall_stack.to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+        let tick = self.ticks.fetch_add(1, Ordering::Relaxed);
+        // inotify may lag behind a change, so the key is checked even while watching
+        if let Some(cached) = self.cache.lock().expect("cache poisoned").get_mut(swinstall_stack) {
+            if IndexKey::of(swinstall_stack).ok().as_ref() == Some(&cached.key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                cached.used = tick;
+                return Ok(Arc::clone(&cached.stack));
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // watch before reading, so that no change goes unnoticed. A stack whose directory cannot
+        // be watched is still caught by the key check
+        if let (Some(watcher), Some(parent)) = (self.watcher.as_ref(), Path::new(swinstall_stack).parent()) {
+            if let Err(e) = watcher.watch(parent) {
+                warn!("unable to watch {}, checking its stacks instead: {}", parent.display(), e);
+            }
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let key = IndexKey::of(swinstall_stack)?;
+        let stack = Arc::new(StackDocument::from_file(swinstall_stack)?);
+        let mut cache = self.cache.lock().expect("cache poisoned");
+        if self.generation.load(Ordering::SeqCst) == generation && self.capacity > 0 {
+            if cache.len() >= self.capacity && !cache.contains_key(swinstall_stack) {
+                let oldest = cache.iter().min_by_key(|(_, cached)| cached.used).map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    debug!("dropping least recently used stack {}", oldest);
+                    cache.remove(&oldest);
+                }
+            }
+            cache.insert(swinstall_stack.to_string(), CachedStack { stack: Arc::clone(&stack), key, used: tick });
+        }
+        Ok(stack)
+    }
+}
+
+// the path a stack is cached and watched by: the stack's own name, in its canonical directory.
+// The stack itself is not resolved, as it is a link which is replaced, not its target
+fn cache_key(swinstall_stack: &str) -> Result<PathBuf, SwInstallError> {
+    let path = Path::new(swinstall_stack);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => Ok(fs::canonicalize(parent)?.join(name)),
+        (_, Some(name)) => Ok(env::current_dir()?.join(name)),
+        _ => Err(SwInstallError::RuntimeError(format!("{} is not a file", swinstall_stack))),
+    }
+}
+
+/// Listen on the supplied socket, replacing the socket of a daemon which is no longer running.
+/// The socket's directory is created if need be, and must be writable by us alone.
+pub fn bind(socket: &Path) -> Result<UnixListener, SwInstallError> {
+    if let Some(dir) = socket.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        private_dir(dir)?;
+    }
+    match UnixListener::bind(socket) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(SwInstallError::RuntimeError(format!("a daemon is already listening on {}", socket.display())));
+            }
+            fs::remove_file(socket)?;
+            Ok(UnixListener::bind(socket)?)
+        },
+        result => Ok(result?),
+    }
+}
+
+/// Answer requests on the supplied listener until the process exits, each connection on a
+/// thread of its own.
+pub fn serve(resolver: Arc<Resolver>, listener: UnixListener) -> Result<(), SwInstallError> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let resolver = Arc::clone(&resolver);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(&resolver, stream) {
+                debug!("connection closed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// answer each request on the connection in turn
+fn handle_connection(resolver: &Resolver, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(line.as_str()) {
+            Ok(request) => resolver.handle(request),
+            Err(e) => Response::error(format!("invalid request: {}", e)),
+        };
+        let mut reply = serde_json::to_string(&response).map_err(io::Error::other)?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// A connection to a running daemon.
+#[derive(Debug)]
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    /// Connect to the daemon listening on the supplied socket. Fails if none is, or if the
+    /// daemon is run by another user.
+    pub fn connect(socket: &Path) -> Result<Self, SwInstallError> {
+        let writer = UnixStream::connect(socket)?;
+        let uid = peer_uid(&writer)?;
+        if uid != unsafe { libc::getuid() } {
+            return Err(SwInstallError::RuntimeError(format!("the daemon on {} is run by uid {}, not by us", socket.display(), uid)));
+        }
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Client { reader, writer })
+    }
+
+    /// Send the supplied request, and wait for the answer.
+    pub fn request(&mut self, request: &Request) -> Result<Response, SwInstallError> {
+        let mut line = serde_json::to_string(request).map_err(|e| SwInstallError::RuntimeError(e.to_string()))?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        line.clear();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(SwInstallError::RuntimeError("the daemon closed the connection".to_string()));
+        }
+        serde_json::from_str(line.as_str()).map_err(|e| SwInstallError::RuntimeError(format!("invalid response: {}", e)))
+    }
+
+    /// Retrieve the path to the file current in the named release channel, or labelled by the
+    /// named tag, as of the supplied datetime, as `SwinstallParser::current_in` and
+    /// `resolve_tag` do.
+    pub fn resolve(&mut self, versionless: &str, channel: Option<&str>, tag: Option<&str>, datetime: &NaiveDateTime) -> Result<String, SwInstallError> {
+        // the daemon does not share our working directory
+        let path = env::current_dir()?.join(versionless);
+        let response = self.request(&Request::Resolve {
+            path: path.to_str().ok_or(SwInstallError::ConvertOsStrFail)?.to_string(),
+            datetime: Some(datetime.format(DATETIME_FMT).to_string()),
+            channel: channel.map(str::to_string),
+            tag: tag.map(str::to_string),
+        })?;
+        match (response.ok, response.path) {
+            (true, Some(path)) => Ok(path),
+            _ => Err(SwInstallError::RuntimeError(response.error.unwrap_or_else(|| "no path returned".to_string()))),
+        }
+    }
+}
+
+/// Watches directories for changes to the files within them.
+#[derive(Debug)]
+pub struct Watcher {
+    fd: libc::c_int,
+    // the directories of each watch descriptor. A directory reached by several paths, such as
+    // through a bind mount, shares a watch descriptor between them
+    dirs: Mutex<HashMap<libc::c_int, Vec<PathBuf>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    /// new up a Watcher, watching nothing.
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watcher { fd, dirs: Mutex::new(HashMap::new()) })
+    }
+
+    /// Watch the supplied directory, if we are not already. Changes are reported under the
+    /// path supplied, so it should be canonical.
+    pub fn watch(&self, dir: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.lock().expect("dirs poisoned");
+        if dirs.values().flatten().any(|d| d == dir) {
+            return Ok(());
+        }
+        let path = std::ffi::CString::new(dir.as_os_str().to_str().unwrap_or_default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // stacks are replaced by renaming a new file over them, so watch for that as well as
+        // for writes in place
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE | libc::IN_CREATE;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        dirs.entry(wd).or_default().push(dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Report changes as they happen, passing the path of each file changed to `changed`, or
+    /// None when changes may have been missed. Only returns on error.
+    pub fn run(&self, changed: impl Fn(Option<&Path>)) -> io::Result<()> {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = vec![0u8; 64 * (header + 256)];
+        loop {
+            let read = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if read < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            let read = read as usize;
+            let mut offset = 0;
+            while offset + header <= read {
+                let event = unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+                let name = &buf[offset + header..offset + header + event.len as usize];
+                offset += header + event.len as usize;
+
+                if event.mask & (libc::IN_Q_OVERFLOW | libc::IN_IGNORED) != 0 {
+                    // the queue overflowed, or a directory went away along with its watch
+                    if event.mask & libc::IN_IGNORED != 0 {
+                        self.dirs.lock().expect("dirs poisoned").remove(&event.wd);
+                    }
+                    changed(None);
+                    continue;
+                }
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                let dirs = self.dirs.lock().expect("dirs poisoned").get(&event.wd).cloned().unwrap_or_default();
+                if let Ok(name) = std::str::from_utf8(name) {
+                    for dir in dirs {
+                        changed(Some(&dir.join(name)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    /// inotify is specific to linux; elsewhere, cached stacks are checked before use.
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::other("inotify is not available on this platform"))
+    }
+
+    pub fn watch(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn run(&self, _changed: impl Fn(Option<&Path>)) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::install;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    fn resolve(path: &str) -> Request {
+        Request::Resolve { path: path.to_string(), datetime: Some("20190201-000000".to_string()), channel: None, tag: None }
+    }
+
+    // wait for the resolver to notice a change, which it does asynchronously
+    fn eventually(mut check: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if check() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn cached_until_changed() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        install(&parser, &dir, versionless.as_str(), "first", "20190101-120000");
+        let resolver = Resolver::new(SwinstallParser::with_builtin_schemas());
+
+        let response = resolver.handle(resolve(versionless.as_str()));
+        assert!(response.ok);
+        assert_eq!(response.version.as_deref(), Some("1"));
+        assert!(response.path.unwrap().ends_with("packages.xml_1"));
+        resolver.handle(resolve(versionless.as_str()));
+        let status = resolver.handle(Request::Status).status.unwrap();
+        assert_eq!((status.requests, status.hits, status.misses, status.cached), (3, 1, 1, 1));
+
+        // the same stack reached by another path is the same entry
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let alias = dir.path().join("sub/../packages.xml").to_str().unwrap().to_string();
+        assert_eq!(resolver.handle(resolve(alias.as_str())).version.as_deref(), Some("1"));
+        let status = resolver.handle(Request::Status).status.unwrap();
+        assert_eq!((status.hits, status.misses, status.cached), (2, 1, 1));
+
+        // a change is picked up at once, without waiting on inotify
+        install(&parser, &dir, versionless.as_str(), "second", "20190102-120000");
+        assert_eq!(resolver.handle(resolve(alias.as_str())).version.as_deref(), Some("2"));
+        assert!(eventually(|| resolver.handle(Request::Status).status.unwrap().invalidations > 0));
+
+        let history = resolver.handle(Request::History { path: versionless.clone() }).history.unwrap();
+        assert_eq!(history.iter().map(|e| e.version.as_str()).collect::<Vec<_>>(), vec!["2", "1"]);
+        let missing = resolver.handle(resolve(dir.path().join("missing.xml").to_str().unwrap()));
+        assert!(!missing.ok && missing.error.is_some());
+    }
+
+    #[test]
+    fn least_recently_used_dropped() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let files = ["a.xml", "b.xml", "c.xml"].iter()
+            .map(|name| {
+                let versionless = dir.path().join(name).to_str().unwrap().to_string();
+                install(&parser, &dir, versionless.as_str(), name, "20190101-120000");
+                versionless
+            })
+            .collect::<Vec<_>>();
+        let resolver = Resolver::with_capacity(parser, 2);
+
+        for idx in &[0, 1, 0, 2, 0, 1] {
+            assert!(resolver.handle(resolve(files[*idx].as_str())).ok);
+        }
+        // b was dropped to make room for c, and c for b in turn
+        let status = resolver.handle(Request::Status).status.unwrap();
+        assert_eq!((status.hits, status.misses, status.cached), (2, 4, 2));
+    }
+
+    #[test]
+    fn served_over_socket() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        install(&parser, &dir, versionless.as_str(), "first", "20190101-120000");
+
+        let socket = dir.path().join("swinst.sock");
+        assert!(Client::connect(&socket).is_err());
+        let listener = bind(&socket).unwrap();
+        // a second daemon on the same socket is refused
+        assert!(bind(&socket).is_err());
+        let resolver = Resolver::new(parser);
+        thread::spawn(move || serve(resolver, listener));
+
+        let mut client = Client::connect(&socket).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20190201-000000", DATETIME_FMT).unwrap();
+        assert!(client.resolve(versionless.as_str(), None, None, &dt).unwrap().ends_with("packages.xml_1"));
+        assert!(client.resolve(versionless.as_str(), None, Some("stable"), &dt).is_err());
+        let response = client.request(&Request::Status).unwrap();
+        assert_eq!(response.status.unwrap().requests, 3);
+
+        // malformed requests are answered rather than dropping the connection
+        let mut raw = UnixStream::connect(&socket).unwrap();
+        raw.write_all(b"{\"op\": \"explode\"}\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(raw).read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(line.as_str()).unwrap();
+        assert!(!response.ok);
+    }
+
+    #[test]
+    fn socket_directory_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+
+        // the socket's directory is created for us alone
+        let private = dir.path().join("private");
+        bind(&private.join("swinst.sock")).unwrap();
+        assert_eq!(fs::metadata(&private).unwrap().mode() & 0o777, 0o700);
+
+        // and a directory others may write to is refused
+        let shared = dir.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(bind(&shared.join("swinst.sock")).is_err());
+    }
+}
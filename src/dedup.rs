@@ -0,0 +1,254 @@
+//! dedup.rs
+//!
+//! Share the storage of versioned files with identical contents.
+//!
+//! The same contents are often installed more than once: a file is reverted by hand and
+//! reinstalled, say, or identical files are installed beside one another. Versioned files are
+//! never modified in place (every write replaces the file via an atomic rename), so those
+//! with identical contents may safely be hard links to one file.
+//!
+//! The `hash` recorded in the swinstall_stack for each version is the key. Schema 1 records
+//! no hash, so the contents are hashed instead. Each file is hashed and checked against the
+//! stack before it is linked, and the contents compared byte for byte against the file it is
+//! to share, so that a versioned file which has been tampered with, or a stack which is
+//! wrong, is reported rather than papered over.
+//!
+//! Hard links share their owner and permissions as well as their contents, so only files
+//! with the same mode, owner and group are linked to one another.
+
+use crate::{
+    errors::SwInstallError,
+    stack::StackDocument,
+    transaction::lock_all,
+    utils::{file_hash, swinstall_stack_from_versionless, unique_suffix, versioned_from_versionless},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    collections::BTreeMap,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// A versioned file which was, or would be, replaced by a hard link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkedFile {
+    /// path to the versioned file
+    pub path: String,
+    /// path to the file it shares contents with
+    pub target: String,
+    /// size of the versioned file
+    pub bytes: u64,
+}
+
+/// The outcome of deduplicating versioned files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dedup {
+    pub linked: Vec<LinkedFile>,
+    /// versioned files whose contents do not match the hash recorded in the stack, and which
+    /// were left alone
+    pub mismatched: Vec<String>,
+    /// whether this was a dry run, leaving everything in place
+    pub dry_run: bool,
+}
+
+impl Dedup {
+    /// The number of bytes saved, or which would be saved by a dry run.
+    pub fn bytes(&self) -> u64 {
+        self.linked.iter().map(|l| l.bytes).sum()
+    }
+}
+
+// a versioned file considered for deduplication
+struct Candidate {
+    path: String,
+    device: u64,
+    inode: u64,
+    bytes: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl Candidate {
+    // whether linking the candidate to `target` leaves its contents, owner and permissions as
+    // they were
+    fn shares_with(&self, target: &Candidate) -> bool {
+        self.device == target.device && self.mode == target.mode && self.uid == target.uid && self.gid == target.gid
+    }
+}
+
+/// Hard link the versioned files of the supplied versionless files which share contents.
+/// Supply a single versionless file to deduplicate its own versions, or several to share
+/// contents between them. Files on different filesystems, or with different modes or owners,
+/// are never linked. Nothing is touched when `dry_run` is set.
+pub fn dedup(versionless: &[String], dry_run: bool) -> Result<Dedup, SwInstallError> {
+    let _locks = lock_all(versionless)?;
+    let mut result = Dedup { linked: Vec::new(), mismatched: Vec::new(), dry_run };
+
+    // versioned files keyed by the hash of their contents, in the order found
+    let mut groups: BTreeMap<String, Vec<Candidate>> = BTreeMap::new();
+    for file in versionless {
+        let swinstall_stack = swinstall_stack_from_versionless(file.as_str())?;
+        let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+        let mut seen = Vec::new();
+        for elt in stack.elts() {
+            let version = elt.require("version")?;
+            // rollbacks and promotions refer back to a version listed below
+            if seen.contains(&version) {
+                continue;
+            }
+            seen.push(version);
+            let path = versioned_from_versionless(file.as_str(), version)?;
+            // pruned versions, for instance, have no versioned file
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let hash = file_hash(path.as_str())?;
+            if let Some(recorded) = elt.get("hash") {
+                if recorded != hash {
+                    warn!("{} does not match the hash recorded in {}", path, swinstall_stack);
+                    result.mismatched.push(path);
+                    continue;
+                }
+            }
+            groups.entry(hash).or_default().push(Candidate {
+                path,
+                device: metadata.dev(),
+                inode: metadata.ino(),
+                bytes: metadata.len(),
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            });
+        }
+    }
+
+    for candidates in groups.values() {
+        // link every file to the first found on its filesystem with the same mode and owners
+        let mut targets: Vec<&Candidate> = Vec::new();
+        for candidate in candidates {
+            let target = match targets.iter().find(|t| candidate.shares_with(t)) {
+                Some(target) => *target,
+                None => {
+                    targets.push(candidate);
+                    continue;
+                },
+            };
+            if target.inode == candidate.inode {
+                continue;
+            }
+            if fs::read(&target.path)? != fs::read(&candidate.path)? {
+                warn!("{} and {} share a hash but not their contents", target.path, candidate.path);
+                result.mismatched.push(candidate.path.clone());
+                continue;
+            }
+            if !dry_run {
+                link_atomic(target.path.as_str(), candidate.path.as_str())?;
+                info!("linked {} to {}", candidate.path, target.path);
+            }
+            result.linked.push(LinkedFile {
+                path: candidate.path.clone(),
+                target: target.path.clone(),
+                bytes: candidate.bytes,
+            });
+        }
+    }
+    Ok(result)
+}
+
+// replace the file at `path` with a hard link to `target`, via a rename so that readers see
+// either the old file or the link
+fn link_atomic(target: &str, path: &str) -> Result<(), SwInstallError> {
+    let path = Path::new(path);
+    let parent = path.parent().ok_or(SwInstallError::NoParentFromPath)?;
+    let file_name = path.file_name()
+                        .ok_or(SwInstallError::NoFileNameFromPath)?
+                        .to_str()
+                        .ok_or(SwInstallError::ConvertOsStrFail)?;
+    let tmp = parent.join(format!(".{}.link.{}", file_name, unique_suffix()));
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(target, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::SwinstallParser, testing::install_daily};
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn inode(path: &str) -> u64 {
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn identical_versions_linked() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = install_daily(&parser, &dir, "packages.xml", &["first", "second", "first"]);
+        let env = install_daily(&parser, &dir, "env.xml", &["second"]);
+
+        let dry = dedup(std::slice::from_ref(&packages), true).unwrap();
+        assert_eq!(dry.linked.len(), 1);
+        assert_eq!(dry.bytes(), 5);
+        let first = versioned_from_versionless(packages.as_str(), "1").unwrap();
+        let third = versioned_from_versionless(packages.as_str(), "3").unwrap();
+        assert_ne!(inode(first.as_str()), inode(third.as_str()));
+
+        // versions are considered most recent first, so older versions link to newer ones
+        let result = dedup(std::slice::from_ref(&packages), false).unwrap();
+        assert_eq!(result.linked, vec![LinkedFile { path: first.clone(), target: third.clone(), bytes: 5 }]);
+        assert_eq!(inode(first.as_str()), inode(third.as_str()));
+        assert_eq!(fs::read_to_string(&first).unwrap(), "first");
+
+        // across files, and only once
+        let result = dedup(&[packages.clone(), env.clone()], false).unwrap();
+        assert_eq!(result.bytes(), 6);
+        let second = versioned_from_versionless(packages.as_str(), "2").unwrap();
+        assert_eq!(inode(second.as_str()), inode(versioned_from_versionless(env.as_str(), "1").unwrap().as_str()));
+        assert!(dedup(&[packages, env], false).unwrap().linked.is_empty());
+    }
+
+    #[test]
+    fn differing_modes_not_linked() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = install_daily(&parser, &dir, "packages.xml", &["first", "first", "first"]);
+        let first = versioned_from_versionless(packages.as_str(), "1").unwrap();
+        let second = versioned_from_versionless(packages.as_str(), "2").unwrap();
+        let third = versioned_from_versionless(packages.as_str(), "3").unwrap();
+        let mode = |path: &str| fs::metadata(path).unwrap().mode() & 0o7777;
+        let mut permissions = fs::metadata(&third).unwrap().permissions();
+        permissions.set_mode(0o600);
+        fs::set_permissions(&third, permissions).unwrap();
+        assert_ne!(mode(first.as_str()), 0o600);
+
+        // the first and second versions share a mode, and are linked to each other alone
+        let result = dedup(std::slice::from_ref(&packages), false).unwrap();
+        assert_eq!(result.linked, vec![LinkedFile { path: first.clone(), target: second.clone(), bytes: 5 }]);
+        assert_ne!(inode(third.as_str()), inode(second.as_str()));
+        assert_eq!(mode(third.as_str()), 0o600);
+        assert_eq!(mode(first.as_str()), mode(second.as_str()));
+    }
+
+    #[test]
+    fn tampered_version_left_alone() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let packages = install_daily(&parser, &dir, "packages.xml", &["first", "first"]);
+        let first = versioned_from_versionless(packages.as_str(), "1").unwrap();
+        fs::write(&first, "tampered").unwrap();
+
+        let result = dedup(&[packages], false).unwrap();
+        assert!(result.linked.is_empty());
+        assert_eq!(result.mismatched, vec![first]);
+    }
+}
@@ -0,0 +1,194 @@
+//! diagnostic.rs
+//!
+//! Locate errors within a malformed swinstall_stack.
+//!
+//! A `Diagnostic` wraps the `SwInstallError` encountered while reading a stack, along with
+//! where it was encountered: the path to the stack, the line and column (both starting at 1),
+//! the element being read and, where known, the offending attribute and its value. Diagnostics
+//! are surfaced as `SwInstallError::Malformed`, and `render` formats them for the terminal
+//! with the offending line and a caret:
+//!
+//! ```text
+//! error: Missing attribute on elt tag: hash
+//!   --> /dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack:4:4
+//!    |
+//!  4 |    <elt action="install" datetime="20181221-142248" version="4"/>
+//!    |    ^ in elt
+//! ```
+
+use crate::errors::SwInstallError;
+use std::fmt;
+
+/// An error located within the text of a swinstall_stack.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// path to the swinstall_stack, if it was read from a file
+    pub path: Option<String>,
+    /// byte offset of the error within the stack
+    pub offset: usize,
+    /// line of the error, starting at 1
+    pub line: usize,
+    /// column of the error, in characters, starting at 1
+    pub column: usize,
+    /// name of the element in which the error was found
+    pub element: Option<String>,
+    /// name of the offending attribute
+    pub attribute: Option<String>,
+    /// value of the offending attribute
+    pub value: Option<String>,
+    /// the underlying error
+    pub error: SwInstallError,
+    // the line of the stack containing the error
+    source_line: String,
+}
+
+impl Diagnostic {
+    /// New up a diagnostic for an error found at byte `offset` of `source`. If the offset
+    /// lands on a tag, the element and, for attribute errors, the attribute are recorded, and
+    /// the diagnostic points at the attribute.
+    pub fn new(path: Option<&str>, source: &str, offset: usize, error: SwInstallError) -> Self {
+        let mut offset = floor_char_boundary(source, offset.min(source.len()));
+        let (attribute, value) = match error {
+            SwInstallError::MissingEltAttribute(ref attribute) => (Some(attribute.clone()), None),
+            SwInstallError::InvalidEltAttribute(ref attribute, ref value) => (Some(attribute.clone()), Some(value.clone())),
+            _ => (None, None),
+        };
+
+        let element = if source[offset..].starts_with('<') {
+            let tag = &source[offset..];
+            let tag = &tag[..tag.find('>').map(|i| i + 1).unwrap_or_else(|| tag.len())];
+            // point at the attribute in question, if it is present
+            if let Some(ref attribute) = attribute {
+                if let Some(idx) = find_attribute(tag, attribute) {
+                    offset += idx;
+                }
+            }
+            let name: String = tag[1..].chars()
+                .take_while(|c| !c.is_whitespace() && *c != '/' && *c != '>')
+                .collect();
+            if name.is_empty() { None } else { Some(name) }
+        } else {
+            None
+        };
+
+        let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = source[offset..].find('\n').map(|i| i + offset).unwrap_or_else(|| source.len());
+        Diagnostic {
+            path: path.map(|p| p.to_string()),
+            offset,
+            line: source[..offset].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            element,
+            attribute,
+            value,
+            error,
+            source_line: source[line_start..line_end].trim_end_matches('\r').to_string(),
+        }
+    }
+
+    /// New up a diagnostic for an error found in the tag ending at byte `end` of `source`.
+    pub fn in_tag_ending(path: Option<&str>, source: &str, end: usize, error: SwInstallError) -> Self {
+        let end = floor_char_boundary(source, end.min(source.len()));
+        let start = source[..end].rfind('<').unwrap_or(end);
+        Self::new(path, source, start, error)
+    }
+
+    /// The line of the swinstall_stack containing the error.
+    pub fn snippet(&self) -> &str {
+        self.source_line.as_str()
+    }
+
+    /// Format the diagnostic for display, with the offending line and a caret pointing at
+    /// the error.
+    pub fn render(&self) -> String {
+        let number = self.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // keep tabs so that the caret lines up with the snippet
+        let padding: String = self.source_line.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let label = match (&self.element, &self.attribute) {
+            (Some(element), Some(attribute)) => format!(" in {} attribute of {}", attribute, element),
+            (Some(element), None) => format!(" in {}", element),
+            _ => String::new(),
+        };
+        format!("error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}^{}",
+            self.error,
+            gutter, self.path.as_deref().unwrap_or("<swinstall_stack>"), self.line, self.column,
+            gutter,
+            number, self.source_line,
+            gutter, padding, label,
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.path.as_deref().unwrap_or("<swinstall_stack>"), self.line, self.column, self.error)
+    }
+}
+
+// the offset within `tag` of the attribute `name`
+fn find_attribute(tag: &str, name: &str) -> Option<usize> {
+    let mut from = 0;
+    while let Some(idx) = tag[from..].find(name) {
+        let idx = from + idx;
+        let preceded = tag[..idx].ends_with(char::is_whitespace);
+        let followed = tag[idx + name.len()..].trim_start().starts_with('=');
+        if preceded && followed {
+            return Some(idx);
+        }
+        from = idx + name.len();
+    }
+    None
+}
+
+// the largest char boundary in `s` no greater than `idx`
+fn floor_char_boundary(s: &str, mut idx: usize) -> usize {
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STACK: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<stack_history path=\"/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack\" schema=\"2\">
+   <elt action=\"install\" datetime=\"20181221-142313\" hash=\"c618755af9b63728411bc536d2c60cf2\" version=\"5\"/>
+\t<elt action=\"install\" datetime=\"2018-12-21\" hash=\"5c8fdabe2ae7fa9287c0672b88ef6593\" version=\"4\"/>
+</stack_history>
+";
+
+    #[test]
+    fn locate_invalid_attribute() {
+        let end = STACK.find("\"4\"/>").unwrap() + 5;
+        let error = SwInstallError::InvalidEltAttribute("datetime".to_string(), "2018-12-21".to_string());
+        let diagnostic = Diagnostic::in_tag_ending(Some("packages.xml_swinstall_stack"), STACK, end, error);
+        assert_eq!((diagnostic.line, diagnostic.column), (4, 24));
+        assert_eq!(diagnostic.element.as_deref(), Some("elt"));
+        assert_eq!(diagnostic.attribute.as_deref(), Some("datetime"));
+        assert_eq!(diagnostic.value.as_deref(), Some("2018-12-21"));
+        assert!(diagnostic.snippet().starts_with("\t<elt action"));
+        assert_eq!(
+            diagnostic.render().lines().last().unwrap(),
+            format!("  | \t{}^ in datetime attribute of elt", " ".repeat(22))
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "packages.xml_swinstall_stack:4:24: Invalid value for attribute datetime: '2018-12-21'"
+        );
+    }
+
+    #[test]
+    fn locate_missing_attribute() {
+        let start = STACK.find("<elt").unwrap();
+        let diagnostic = Diagnostic::new(None, STACK, start, SwInstallError::MissingEltAttribute("channel".to_string()));
+        assert_eq!((diagnostic.line, diagnostic.column), (3, 4));
+        assert_eq!(diagnostic.value, None);
+        assert!(diagnostic.render().contains("--> <swinstall_stack>:3:4"));
+    }
+}
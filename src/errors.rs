@@ -1,7 +1,9 @@
 use chrono::format::ParseError;
+use crate::diagnostic::Diagnostic;
 use failure::Fail;
 use std::{
     convert::From,
+    io,
     num::ParseIntError,
     str::{ Utf8Error, ParseBoolError },
 };
@@ -22,8 +24,10 @@ pub enum SwInstallError {
     NoFileNameFromPath,
     #[fail(display = "Failed to convert OsStr to Str")]
     ConvertOsStrFail,
-    #[fail(display = "Missing attribute on elt tag")]
-    MissingEltAttribute,
+    #[fail(display = "Missing attribute on elt tag: {}", _0)]
+    MissingEltAttribute(String),
+    #[fail(display = "Invalid value for attribute {}: '{}'", _0, _1)]
+    InvalidEltAttribute(String, String),
     #[fail(display = "failed to convert to utf8: {}", _0)]
     Utf8Error(String),
     #[fail(display = "chrono parse error: {}", _0)]
@@ -38,6 +42,50 @@ pub enum SwInstallError {
     ParseIntError(String),
     #[fail(display = "ParseBoolError - failed to parse bool: {}", _0)]
     ParseBoolError(String),
+    #[fail(display = "io error: {}", _0)]
+    IoError(String),
+    #[fail(display = "Timed out waiting for lock: {}", _0)]
+    LockTimeout(String),
+    #[fail(display = "Unsupported operation: {}", _0)]
+    UnsupportedOperation(String),
+    #[fail(display = "Version not found in swinstall_stack: {}", _0)]
+    VersionNotFound(String),
+    #[fail(display = "Version has been garbage collected: {}", _0)]
+    VersionPruned(String),
+    #[fail(display = "Interrupted transaction pending; run swinst recover: {}", _0)]
+    PendingTransaction(String),
+    #[fail(display = "Tag not found in swinstall_stack: {}", _0)]
+    TagNotFound(String),
+    #[fail(display = "No swinstalled version found in search path: {}", _0)]
+    NotInSearchPath(String),
+    #[fail(display = "Conversion error: {}", _0)]
+    ConversionError(String),
+    #[fail(display = "{}", _0)]
+    Malformed(Box<Diagnostic>),
+    #[fail(display = "Current version is ambiguous; swinstall_stack is damaged at {}", _0)]
+    AmbiguousCurrent(String),
+}
+
+impl SwInstallError {
+    /// Whether the error describes the contents of a swinstall_stack, and may therefore be
+    /// located within it.
+    pub fn is_malformed(&self) -> bool {
+        matches!(self,
+            SwInstallError::QuckXmlError(_)
+            | SwInstallError::MissingEltAttribute(_)
+            | SwInstallError::InvalidEltAttribute(_, _)
+            | SwInstallError::Utf8Error(_)
+            | SwInstallError::ChronoParseError(_)
+            | SwInstallError::ParseIntError(_)
+            | SwInstallError::ParseBoolError(_)
+        )
+    }
+}
+
+impl From<Diagnostic> for SwInstallError {
+    fn from(diagnostic: Diagnostic) -> Self {
+        SwInstallError::Malformed(Box::new(diagnostic))
+    }
 }
 
 impl From<quick_xml::Error> for SwInstallError {
@@ -68,4 +116,10 @@ impl From<ParseBoolError> for SwInstallError {
     fn from(error: ParseBoolError) -> Self {
         SwInstallError::ParseBoolError(error.to_string())
     }
+}
+
+impl From<io::Error> for SwInstallError {
+    fn from(error: io::Error) -> Self {
+        SwInstallError::IoError(error.to_string())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,256 @@
+//! gc.rs
+//!
+//! Garbage collect the versioned files of a swinstalled file according to a retention policy.
+//!
+//! A `RetentionPolicy` names the versions worth keeping: the most recent few, those installed
+//! after a given datetime, and those which were ever current or ever tagged. The policies
+//! combine, so a version is kept if any of them keeps it. Versions which are in use (current
+//! in some channel, staged, or scheduled) are always kept.
+//!
+//! The versioned files of the remaining versions are removed, or moved to an archive
+//! directory, and their elt tags are marked with a `pruned` attribute recording when:
+//!
+//! ```xml
+//! <elt action="install" datetime="20181221-142248" hash="5c8fdabe2ae7fa9287c0672b88ef6593" pruned="20190301-090000" version="4"/>
+//! ```
+//!
+//! A lookup which lands on a pruned entry fails with `SwInstallError::VersionPruned`, rather
+//! than resolving to a missing file or falling through to an older version.
+
+use chrono::NaiveDateTime;
+use crate::{
+    compress::stored_path,
+    errors::SwInstallError,
+    lock::StackLock,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    transaction::pending_check,
+    utils::{swinstall_stack_from_versionless, versioned_from_versionless},
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{fs, path::Path};
+
+/// The versions of a swinstalled file to keep when garbage collecting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// keep the most recently installed N versions
+    pub keep_last: Option<usize>,
+    /// keep every version installed after this datetime
+    pub keep_newer_than: Option<NaiveDateTime>,
+    /// keep every version which has ever been current
+    pub keep_current: bool,
+    /// keep every version which has ever been tagged
+    pub keep_tagged: bool,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy keeps anything beyond the versions in use. An empty policy would
+    /// collect the entire history, and is refused.
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none() && self.keep_newer_than.is_none() && !self.keep_current && !self.keep_tagged
+    }
+}
+
+/// A version whose versioned file was, or would be, garbage collected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrunedVersion {
+    pub version: String,
+    /// path to the versioned file
+    pub path: String,
+    /// size of the versioned file
+    pub bytes: u64,
+}
+
+/// The outcome of garbage collecting a swinstalled file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub pruned: Vec<PrunedVersion>,
+    /// the versions kept, most recent first
+    pub kept: Vec<String>,
+    /// whether the collection was a dry run, leaving everything in place
+    pub dry_run: bool,
+}
+
+impl Collection {
+    /// The number of bytes reclaimed, or which would be reclaimed by a dry run.
+    pub fn bytes(&self) -> u64 {
+        self.pruned.iter().map(|p| p.bytes).sum()
+    }
+}
+
+/// Garbage collect the versioned files of the `versionless` file which fall outside the
+/// supplied policy, as of `datetime`. The files are removed, or moved into the `archive`
+/// directory when one is supplied. Nothing is touched when `dry_run` is set.
+///
+/// The swinstall_stack is updated before any file is removed, so an interrupted collection
+/// leaves versioned files which are no longer referenced, rather than references to missing
+/// files. Versions pruned previously whose files remain are collected again.
+pub fn collect(
+    parser: &SwinstallParser,
+    versionless: &str,
+    policy: &RetentionPolicy,
+    archive: Option<&str>,
+    dry_run: bool,
+    datetime: &NaiveDateTime,
+) -> Result<Collection, SwInstallError> {
+    if policy.is_empty() {
+        return Err(SwInstallError::RuntimeError("refusing to collect with an empty retention policy".to_string()));
+    }
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let mut stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    let component = parser.component_for(&stack)?;
+    let tagged: Vec<String> = component.tags(&stack)?.into_iter().filter_map(|t| t.version).collect();
+
+    let mut collection = Collection { pruned: Vec::new(), kept: Vec::new(), dry_run };
+    let mut newly_pruned = Vec::new();
+    // versions are listed most recently installed first
+    for (idx, record) in component.versions(&stack, datetime)?.into_iter().enumerate() {
+        let keep = !record.pruned && (
+            record.in_use
+            || policy.keep_last.map(|n| idx < n).unwrap_or(false)
+            || policy.keep_newer_than.map(|dt| record.installed > dt).unwrap_or(false)
+            || (policy.keep_current && record.ever_current)
+            || (policy.keep_tagged && tagged.contains(&record.version))
+        );
+        if keep {
+            collection.kept.push(record.version);
+            continue;
+        }
+        let versioned = versioned_from_versionless(versionless, record.version.as_str())?;
+        // the version may be stored compressed
+        let path = stored_path(versioned.as_str()).unwrap_or(versioned);
+        let bytes = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            // already collected
+            Err(_) if record.pruned => continue,
+            Err(_) => {
+                warn!("versioned file {} is missing", path);
+                0
+            },
+        };
+        if !record.pruned {
+            newly_pruned.push(record.version.clone());
+        }
+        collection.pruned.push(PrunedVersion { version: record.version, path, bytes });
+    }
+    if dry_run {
+        return Ok(collection);
+    }
+
+    if !newly_pruned.is_empty() {
+        for version in newly_pruned.iter() {
+            component.prune(&mut stack, version, datetime)?;
+        }
+        stack.write(swinstall_stack.as_str())?;
+    }
+    if let Some(archive) = archive {
+        fs::create_dir_all(archive)?;
+    }
+    for pruned in collection.pruned.iter().filter(|p| Path::new(&p.path).exists()) {
+        match archive {
+            Some(archive) => archive_file(pruned.path.as_str(), archive)?,
+            None => fs::remove_file(&pruned.path)?,
+        }
+        info!("collected {} ({} bytes)", pruned.path, pruned.bytes);
+    }
+    Ok(collection)
+}
+
+// move the file at `path` into the `archive` directory, copying it where the archive lies on
+// another filesystem
+fn archive_file(path: &str, archive: &str) -> Result<(), SwInstallError> {
+    let file_name = Path::new(path).file_name().ok_or(SwInstallError::NoFileNameFromPath)?;
+    let target = Path::new(archive).join(file_name);
+    if fs::rename(path, &target).is_err() {
+        fs::copy(path, &target)?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{datetime, install_daily},
+        traits::EltOptions,
+        transaction,
+    };
+    use tempfile::TempDir;
+
+    // a versionless file with five installs, rolled back to the third
+    fn setup(dir: &TempDir) -> (SwinstallParser, String) {
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let contents: Vec<String> = (1..=5).map(|i| "x".repeat(i * 10)).collect();
+        let versionless = install_daily(&parser, dir, "packages.xml", &contents);
+        transaction::rollback(&parser, versionless.as_str(), "3", &datetime("20190106-120000"), &EltOptions::default()).unwrap();
+        (parser, versionless)
+    }
+
+    #[test]
+    fn keep_last_keeps_current() {
+        let dir = TempDir::new().unwrap();
+        let (parser, versionless) = setup(&dir);
+        let now = datetime("20190201-120000");
+        let policy = RetentionPolicy { keep_last: Some(2), ..RetentionPolicy::default() };
+
+        let dry = collect(&parser, versionless.as_str(), &policy, None, true, &now).unwrap();
+        let versions: Vec<&str> = dry.pruned.iter().map(|p| p.version.as_str()).collect();
+        assert_eq!(versions, vec!["2", "1"]);
+        assert_eq!(dry.kept, vec!["5", "4", "3"]);
+        assert_eq!(dry.bytes(), 30);
+        assert!(dry.pruned.iter().all(|p| Path::new(&p.path).exists()));
+
+        let collection = collect(&parser, versionless.as_str(), &policy, None, false, &now).unwrap();
+        assert_eq!(collection.bytes(), 30);
+        assert!(collection.pruned.iter().all(|p| !Path::new(&p.path).exists()));
+
+        // lookups before the earliest version kept report the version pruned, rather than a
+        // missing file or an older version
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        let current = parser.current_at(swinstall_stack.as_str(), &datetime("20190103-130000")).unwrap();
+        assert!(current.ends_with("packages.xml_3"));
+        let error = parser.current_at(swinstall_stack.as_str(), &datetime("20190102-130000")).unwrap_err();
+        match error.downcast_ref::<SwInstallError>() {
+            Some(SwInstallError::VersionPruned(version)) => assert_eq!(version, "2"),
+            _ => panic!("expected a pruned version, got {}", error),
+        }
+
+        // a second collection has nothing left to do
+        let again = collect(&parser, versionless.as_str(), &policy, None, false, &now).unwrap();
+        assert!(again.pruned.is_empty());
+    }
+
+    #[test]
+    fn archive_and_keep_newer_than() {
+        let dir = TempDir::new().unwrap();
+        let (parser, versionless) = setup(&dir);
+        let now = datetime("20190201-120000");
+        let archive = dir.path().join("archive");
+        let policy = RetentionPolicy { keep_newer_than: Some(datetime("20190103-000000")), ..RetentionPolicy::default() };
+
+        let collection = collect(
+            &parser, versionless.as_str(), &policy, archive.to_str(), false, &now
+        ).unwrap();
+        assert_eq!(collection.kept, vec!["5", "4", "3"]);
+        assert!(archive.join("packages.xml_1").exists());
+        assert!(archive.join("packages.xml_2").exists());
+        assert!(!Path::new(&collection.pruned[0].path).exists());
+    }
+
+    #[test]
+    fn empty_policy_refused() {
+        let dir = TempDir::new().unwrap();
+        let (parser, versionless) = setup(&dir);
+        let now = datetime("20190201-120000");
+        assert!(collect(&parser, versionless.as_str(), &RetentionPolicy::default(), None, true, &now).is_err());
+        // every version has been current, so none are collected
+        let policy = RetentionPolicy { keep_current: true, ..RetentionPolicy::default() };
+        assert!(collect(&parser, versionless.as_str(), &policy, None, true, &now).unwrap().pruned.is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+//! http.rs
+//!
+//! A read only HTTP API onto swinstall_stacks, answering each query with JSON, for dashboards
+//! and the like which would rather not shell out to `swinst`.
+//!
+//! `swinst http --bind 127.0.0.1:PORT` serves the following endpoints:
+//!
+//! ```text
+//! GET /resolve?path=...[&at=...][&channel=...][&tag=...]
+//!     {"path": "/dd/facility/etc/packages.xml", "version": "5", "versioned": "/dd/facility/etc/bak/packages.xml/packages.xml_5"}
+//! GET /history?path=...
+//!     {"path": "...", "history": [{"action": "install", "datetime": "20181221-142313", "version": "5"}, ...]}
+//! GET /status?root=...[&at=...]
+//!     {"root": "...", "files": [{"path": "...", "swinstall_stack": "...", "modified": "...", "version": "5", "versioned": "..."}, ...]}
+//! GET /content?path=...[&version=...][&at=...]
+//!     {"path": "...", "version": "5", "versioned": "...", "content": "<packages>..."}
+//! ```
+//!
+//! `path` is the path to a versionless file, as on the command line, and `at` a datetime in
+//! either of the forms `parse_datetime` accepts, defaulting to now. `/status` reports every
+//! swinstalled file beneath `root`, noting the error for any which cannot be resolved rather
+//! than failing as a whole, and lists any directory beneath `root` which cannot be searched
+//! under `skipped`. `/content` returns the version current at `at` unless a version
+//! is named, which must be one recorded in the swinstall_stack.
+//!
+//! Failures are answered with `{"error": "..."}`, and a status of 400 for a bad query, 404
+//! for a file, version or tag which does not exist, 422 for a query the swinstall_stack cannot
+//! answer, such as the history of a schema 1 stack, and 500 otherwise.
+
+use chrono::{Local, NaiveDateTime};
+use crate::{
+    archive::with_archive,
+    compress::read_versioned,
+    constants::DATETIME_FMT,
+    daemon::HistoryEvent,
+    errors::SwInstallError,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    transaction,
+    utils::{
+        modified_time, parse_datetime, search_swinstall_stacks, swinstall_stack_from_versionless,
+        versioned_from_swinstall_stack, versionless_from_swinstall_stack,
+    },
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    thread,
+};
+use tiny_http::{Header, Method, Server};
+
+/// The resolution of a versionless file, as answered by `/resolve`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Resolved {
+    pub path: String,
+    pub version: String,
+    pub versioned: String,
+}
+
+/// The history of a versionless file, most recent event first, as answered by `/history`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct History {
+    pub path: String,
+    pub history: Vec<HistoryEvent>,
+}
+
+/// The swinstalled files beneath a directory, as answered by `/status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TreeStatus {
+    pub root: String,
+    pub files: Vec<FileStatus>,
+    /// directories which could not be searched
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<SkippedDir>,
+}
+
+/// A directory beneath the root of a `/status` query which could not be searched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedDir {
+    pub path: String,
+    pub error: String,
+}
+
+/// A swinstalled file, and the version of it current at the requested datetime.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub swinstall_stack: String,
+    /// when the swinstall_stack was last written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versioned: Option<String>,
+    /// why the file could not be resolved, or its details read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The contents of a version of a versionless file, as answered by `/content`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Content {
+    pub path: String,
+    pub version: String,
+    pub versioned: String,
+    pub content: String,
+}
+
+/// A failure to answer a query, along with the HTTP status it is answered with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub error: String,
+}
+
+impl ApiError {
+    fn bad_request(error: impl std::fmt::Display) -> Self {
+        ApiError { status: 400, error: error.to_string() }
+    }
+
+    fn not_found(error: impl std::fmt::Display) -> Self {
+        ApiError { status: 404, error: error.to_string() }
+    }
+}
+
+impl From<SwInstallError> for ApiError {
+    fn from(error: SwInstallError) -> Self {
+        let status = match error {
+            SwInstallError::InvalidDate(_) => 400,
+            SwInstallError::NoCurrentFound
+            | SwInstallError::VersionNotFound(_)
+            | SwInstallError::TagNotFound(_) => 404,
+            SwInstallError::VersionPruned(_) => 410,
+            SwInstallError::UnsupportedOperation(_) => 422,
+            _ => 500,
+        };
+        ApiError { status, error: error.to_string() }
+    }
+}
+
+impl From<failure::Error> for ApiError {
+    fn from(error: failure::Error) -> Self {
+        match error.downcast::<SwInstallError>() {
+            Ok(error) => error.into(),
+            Err(error) => ApiError { status: 500, error: error.to_string() },
+        }
+    }
+}
+
+/// Answers queries against swinstall_stacks with the schemas registered with its parser.
+#[derive(Debug)]
+pub struct Api {
+    parser: SwinstallParser,
+}
+
+impl Api {
+    /// new up an Api, resolving with the supplied parser.
+    pub fn new(parser: SwinstallParser) -> Self {
+        Api { parser }
+    }
+
+    /// Answer a GET of the supplied url, the path and query of the request, returning the
+    /// HTTP status and the JSON body of the reply.
+    pub fn get(&self, url: &str) -> (u16, String) {
+        let (route, query) = match url.find('?') {
+            Some(idx) => (&url[..idx], &url[idx + 1..]),
+            None => (url, ""),
+        };
+        let query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let query = Query(query);
+        let reply = match route {
+            "/resolve" => self.resolve(&query).and_then(|r| to_json(&r)),
+            "/history" => self.history(&query).and_then(|r| to_json(&r)),
+            "/status" => self.status(&query).and_then(|r| to_json(&r)),
+            "/content" => self.content(&query).and_then(|r| to_json(&r)),
+            _ => Err(ApiError::not_found(format!("no such endpoint: {}", route))),
+        };
+        match reply {
+            Ok(body) => (200, body),
+            Err(e) => {
+                debug!("GET {}: {}", url, e.error);
+                (e.status, serde_json::to_string(&e).expect("errors serialize"))
+            },
+        }
+    }
+
+    /// Resolve the version of `path` current at `at`, or labelled by `tag`.
+    pub fn resolve(&self, query: &Query) -> Result<Resolved, ApiError> {
+        let path = query.require("path")?;
+        let datetime = query.datetime()?;
+        let swinstall_stack = existing_stack(path)?;
+        let version = match query.get("tag") {
+            Some(tag) => self.parser.tagged_version_at(swinstall_stack.as_str(), tag, &datetime)?,
+            None => self.parser.version_in(swinstall_stack.as_str(), query.get("channel"), &datetime)?,
+        };
+        Ok(Resolved {
+            path: path.to_string(),
+            versioned: versioned_from_swinstall_stack(swinstall_stack.as_str(), version.as_str())?,
+            version,
+        })
+    }
+
+    /// Retrieve the history of `path`, including any entries archived from its stack.
+    pub fn history(&self, query: &Query) -> Result<History, ApiError> {
+        let path = query.require("path")?;
+        existing_stack(path)?;
+        let history = transaction::timeline(&self.parser, path)?;
+        Ok(History {
+            path: path.to_string(),
+            history: history.into_iter().map(HistoryEvent::from).collect(),
+        })
+    }
+
+    /// Report every swinstalled file beneath `root`, and its version current at `at`.
+    pub fn status(&self, query: &Query) -> Result<TreeStatus, ApiError> {
+        let root = query.require("root")?;
+        let datetime = query.datetime()?;
+        if !Path::new(root).is_dir() {
+            return Err(ApiError::not_found(format!("no such directory: {}", root)));
+        }
+        let (stacks, skipped) = search_swinstall_stacks(root)?;
+        let skipped = skipped.into_iter()
+            .map(|(path, error)| SkippedDir { path, error: error.to_string() })
+            .collect();
+        let mut files = Vec::new();
+        for swinstall_stack in stacks {
+            // a file which cannot be reported on in full is reported with what went wrong,
+            // rather than failing the rest
+            let mut errors = Vec::new();
+            let path = versionless_from_swinstall_stack(swinstall_stack.as_str())
+                .map_err(|e| errors.push(e.to_string()))
+                .ok();
+            let modified = modified_time(swinstall_stack.as_str())
+                .map(|modified| modified.format(DATETIME_FMT).to_string())
+                .map_err(|e| errors.push(e.to_string()))
+                .ok();
+            let resolved = self.parser.version_at(swinstall_stack.as_str(), &datetime)
+                .and_then(|version| {
+                    let versioned = versioned_from_swinstall_stack(swinstall_stack.as_str(), version.as_str())?;
+                    Ok((version, versioned))
+                });
+            let (version, versioned) = match resolved {
+                Ok((version, versioned)) => (Some(version), Some(versioned)),
+                Err(e) => {
+                    errors.push(e.to_string());
+                    (None, None)
+                },
+            };
+            let error = if errors.is_empty() { None } else { Some(errors.join("; ")) };
+            files.push(FileStatus { path, modified, swinstall_stack, version, versioned, error });
+        }
+        Ok(TreeStatus { root: root.to_string(), files, skipped })
+    }
+
+    /// Retrieve the contents of `version` of `path`, or of the version current at `at`.
+    pub fn content(&self, query: &Query) -> Result<Content, ApiError> {
+        let path = query.require("path")?;
+        let swinstall_stack = existing_stack(path)?;
+        let version = match query.get("version") {
+            Some(version) => {
+                // only versions of this file, rather than any file the version names
+                let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+                let stack = with_archive(&self.parser, swinstall_stack.as_str(), &stack)?;
+                if !stack.elts().any(|elt| elt.get("version") == Some(version)) {
+                    return Err(SwInstallError::VersionNotFound(version.to_string()).into());
+                }
+                version.to_string()
+            },
+            None => self.parser.version_at(swinstall_stack.as_str(), &query.datetime()?)?,
+        };
+        let versioned = versioned_from_swinstall_stack(swinstall_stack.as_str(), version.as_str())?;
+        let content = String::from_utf8(read_versioned(versioned.as_str())?)
+            .map_err(|_| SwInstallError::Utf8Error(versioned.clone()))?;
+        Ok(Content { path: path.to_string(), version, versioned, content })
+    }
+}
+
+/// The parameters of a query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query(pub HashMap<String, String>);
+
+impl Query {
+    /// The named parameter, if supplied.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    fn require(&self, name: &str) -> Result<&str, ApiError> {
+        self.get(name).ok_or_else(|| ApiError::bad_request(format!("missing parameter: {}", name)))
+    }
+
+    // the `at` parameter, defaulting to now
+    fn datetime(&self) -> Result<NaiveDateTime, ApiError> {
+        match self.get("at") {
+            Some(at) => Ok(parse_datetime(at)?),
+            None => Ok(Local::now().naive_local()),
+        }
+    }
+}
+
+// the swinstall_stack of the supplied versionless file, which must exist
+fn existing_stack(versionless: &str) -> Result<String, ApiError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    if !Path::new(&swinstall_stack).is_file() {
+        return Err(ApiError::not_found(format!("no swinstall_stack for {}", versionless)));
+    }
+    Ok(swinstall_stack)
+}
+
+fn to_json(reply: &impl Serialize) -> Result<String, ApiError> {
+    serde_json::to_string(reply).map_err(|e| ApiError { status: 500, error: e.to_string() })
+}
+
+/// Listen on the supplied address, eg 127.0.0.1:8080, with port 0 picking a free port.
+pub fn bind(address: &str) -> Result<Server, SwInstallError> {
+    Server::http(address).map_err(|e| SwInstallError::RuntimeError(format!("unable to listen on {}: {}", address, e)))
+}
+
+/// Answer requests to the supplied server until it is shut down, each on a thread of its own.
+pub fn serve(api: Arc<Api>, server: Server) -> Result<(), SwInstallError> {
+    for request in server.incoming_requests() {
+        let api = Arc::clone(&api);
+        thread::spawn(move || {
+            let (status, body) = match request.method() {
+                Method::Get => api.get(request.url()),
+                method => (405, serde_json::to_string(&ApiError {
+                    status: 405,
+                    error: format!("method not allowed: {}", method),
+                }).expect("errors serialize")),
+            };
+            let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("valid header");
+            let response = tiny_http::Response::from_string(body).with_status_code(status).with_header(header);
+            if let Err(e) = request.respond(response) {
+                debug!("unable to respond: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::install_daily;
+    use serde_json::Value;
+    use std::{fs, io::{Read, Write}, net::TcpStream};
+    use tempfile::TempDir;
+
+    fn get(api: &Api, url: &str) -> (u16, Value) {
+        let (status, body) = api.get(url);
+        (status, serde_json::from_str(body.as_str()).unwrap())
+    }
+
+    // a versionless file installed on each of the first three days of 2019
+    fn setup(dir: &TempDir) -> (Api, String) {
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        let versionless = install_daily(&parser, dir, "etc/packages.xml", &["day 1", "day 2", "day 3"]);
+        (Api::new(parser), versionless)
+    }
+
+    #[test]
+    fn endpoints() {
+        let dir = TempDir::new().unwrap();
+        let (api, versionless) = setup(&dir);
+        let path: String = form_urlencoded::byte_serialize(versionless.as_bytes()).collect();
+
+        let (status, reply) = get(&api, format!("/resolve?path={}&at=2019-01-02+13:00:00", path).as_str());
+        assert_eq!(status, 200);
+        assert_eq!(reply["version"], "2");
+        assert!(reply["versioned"].as_str().unwrap().ends_with("packages.xml_2"));
+
+        let (status, reply) = get(&api, format!("/history?path={}", path).as_str());
+        assert_eq!(status, 200);
+        assert_eq!(reply["history"].as_array().unwrap().len(), 3);
+        assert_eq!(reply["history"][0]["datetime"], "20190103-120000");
+
+        let (status, reply) = get(&api, format!("/status?root={}&at=20190101-130000", dir.path().to_str().unwrap()).as_str());
+        assert_eq!(status, 200);
+        assert_eq!(reply["files"][0]["path"], versionless.as_str());
+        assert_eq!(reply["files"][0]["version"], "1");
+
+        // a stack which cannot be read is reported as such, alongside the rest
+        let broken = dir.path().join("etc").join("bak").join("broken.xml");
+        fs::create_dir_all(&broken).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("missing"), broken.join("broken.xml_swinstall_stack")).unwrap();
+        let (status, reply) = get(&api, format!("/status?root={}&at=20190101-130000", dir.path().to_str().unwrap()).as_str());
+        assert_eq!(status, 200);
+        let files = reply["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        let broken = files.iter().find(|f| f["swinstall_stack"].as_str().unwrap().contains("broken.xml")).unwrap();
+        assert!(broken["error"].is_string() && broken["modified"].is_null());
+        assert!(files.iter().any(|f| f["version"] == "1"));
+
+        let (status, reply) = get(&api, format!("/content?path={}", path).as_str());
+        assert_eq!((status, reply["content"].as_str()), (200, Some("day 3")));
+        let (status, reply) = get(&api, format!("/content?path={}&version=1", path).as_str());
+        assert_eq!((status, reply["content"].as_str()), (200, Some("day 1")));
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TempDir::new().unwrap();
+        let (api, versionless) = setup(&dir);
+        assert_eq!(get(&api, "/resolve").0, 400);
+        assert_eq!(get(&api, format!("/resolve?path={}&at=yesterday", versionless).as_str()).0, 400);
+        assert_eq!(get(&api, format!("/resolve?path={}&at=20180101-000000", versionless).as_str()).0, 404);
+        assert_eq!(get(&api, format!("/resolve?path={}/missing.xml", dir.path().to_str().unwrap()).as_str()).0, 404);
+        assert_eq!(get(&api, format!("/resolve?path={}&tag=missing", versionless).as_str()).0, 404);
+        // versions name files beside the stack, so only those recorded are read
+        let (status, reply) = get(&api, format!("/content?path={}&version=../../../source", versionless).as_str());
+        assert_eq!(status, 404);
+        assert!(reply["error"].as_str().unwrap().contains("Version not found"));
+        assert_eq!(get(&api, "/nothing").0, 404);
+    }
+
+    #[test]
+    fn served_over_http() {
+        let dir = TempDir::new().unwrap();
+        let (api, versionless) = setup(&dir);
+        let server = bind("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        thread::spawn(move || serve(Arc::new(api), server));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /resolve?path={} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", versionless).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("application/json"));
+        assert!(response.contains(r#""version":"3""#));
+    }
+}
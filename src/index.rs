@@ -0,0 +1,349 @@
+//! index.rs
+//!
+//! Cache the resolved versions of a swinstall_stack in an index, so that repeated lookups
+//! against an unchanged stack need not parse its xml.
+//!
+//! Rather than the stack itself, the index holds, for each release channel, the datetimes at
+//! which the current version changes and what it changes to, in datetime order, so a lookup
+//! is a binary search, and a lookup as of now reads the last entry. It is serialized as JSON
+//! along with the key of the stack it was built from: the stack's modification time, size and
+//! inode. Every write to a stack replaces it via an atomic rename, giving it a new inode, so an
+//! index whose key matches the stack on disk resolves exactly as the stack does. An index which
+//! does not match, or which cannot be read, is ignored and rebuilt. Lookups the index cannot
+//! answer, in a channel it does not know or before the entries of an archived stack, are left
+//! to the stack.
+//!
+//! The index lives beside the stack, as `<file>_swinstall_stack_index`, or in a cache
+//! directory of the caller's choosing, for stacks in directories the caller cannot write to.
+//! It is written under the stack's lock, or for an index in a cache directory, a lock of its
+//! own, which a lookup never waits for: if the lock is held, the index is left to a later
+//! lookup. Failing to write an index never fails a lookup.
+
+use crate::{
+    archive::archive_link,
+    errors::SwInstallError,
+    lock::StackLock,
+    parser::SwinstallParser,
+    stack::StackDocument,
+    utils::{swinstall_index_from_swinstall_stack, write_atomic},
+};
+use chrono::NaiveDateTime;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Where the index of a swinstall_stack is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexLocation {
+    /// beside the swinstall_stack
+    Sidecar,
+    /// in the supplied cache directory, named after the path to the swinstall_stack
+    Directory(PathBuf),
+}
+
+impl IndexLocation {
+    /// The path to the index of the supplied swinstall_stack.
+    pub fn index_path(&self, swinstall_stack: &str) -> Result<PathBuf, SwInstallError> {
+        match self {
+            IndexLocation::Sidecar => Ok(PathBuf::from(swinstall_index_from_swinstall_stack(swinstall_stack))),
+            IndexLocation::Directory(dir) => {
+                // the same stack may be reached by several paths
+                let path = fs::canonicalize(swinstall_stack)?;
+                let name = format!("{:x}.json", md5::compute(path.to_str().ok_or(SwInstallError::ConvertOsStrFail)?));
+                Ok(dir.join(name))
+            },
+        }
+    }
+}
+
+/// Identifies the contents of a swinstall_stack on disk, without reading them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexKey {
+    /// modification time, in seconds since the epoch
+    pub mtime: i64,
+    /// nanoseconds past `mtime`
+    pub mtime_nsec: i64,
+    pub size: u64,
+    pub inode: u64,
+}
+
+impl IndexKey {
+    /// The key of the swinstall_stack at the supplied path, as it is now.
+    pub fn of(swinstall_stack: &str) -> Result<Self, SwInstallError> {
+        let metadata = fs::metadata(swinstall_stack)?;
+        Ok(IndexKey {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.len(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
+// what a lookup resolves to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Resolution {
+    Version(String),
+    Pruned(String),
+    NoCurrent,
+}
+
+impl Resolution {
+    fn of(resolved: Result<(String, usize), SwInstallError>) -> Result<Self, SwInstallError> {
+        match resolved {
+            Ok((version, _)) => Ok(Resolution::Version(version)),
+            Err(SwInstallError::VersionPruned(version)) => Ok(Resolution::Pruned(version)),
+            Err(SwInstallError::NoCurrentFound) => Ok(Resolution::NoCurrent),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn version(&self) -> Result<String, SwInstallError> {
+        match self {
+            Resolution::Version(version) => Ok(version.clone()),
+            Resolution::Pruned(version) => Err(SwInstallError::VersionPruned(version.clone())),
+            Resolution::NoCurrent => Err(SwInstallError::NoCurrentFound),
+        }
+    }
+}
+
+// the lookups of one release channel. None is the default channel
+#[derive(Debug, Serialize, Deserialize)]
+struct ChannelIndex {
+    channel: Option<String>,
+    // each datetime, in seconds since the epoch, at which the current version changes, and
+    // what lookups resolve to from then on, in datetime order
+    changes: Vec<(i64, Resolution)>,
+}
+
+/// The indexed lookups of a swinstall_stack.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StackIndex {
+    key: IndexKey,
+    // lookups before this, in seconds since the epoch, need the archive of the stack
+    archived_before: Option<i64>,
+    channels: Vec<ChannelIndex>,
+}
+
+impl StackIndex {
+    // index the swinstall_stack at the supplied path, whose key is `key`
+    fn build(parser: &SwinstallParser, swinstall_stack: &str, key: IndexKey) -> Result<Self, SwInstallError> {
+        let stack = StackDocument::from_file(swinstall_stack)?;
+        let component = parser.component_for(&stack)?;
+        let archived_before = archive_link(swinstall_stack, &stack)?.map(|(_, before)| before.timestamp());
+
+        let mut transitions = component.transitions(&stack)?;
+        transitions.sort();
+        transitions.dedup();
+        let channels = stack.elts()
+            .filter_map(|e| e.get("channel"))
+            .collect::<BTreeSet<_>>();
+
+        let mut index = StackIndex { key, archived_before, channels: Vec::new() };
+        for channel in std::iter::once(None).chain(channels.into_iter().map(Some)) {
+            let mut changes: Vec<(i64, Resolution)> = Vec::new();
+            let resolved = component.current_in_stack_at(&stack, &transitions, channel)?;
+            for (datetime, resolved) in transitions.iter().zip(resolved) {
+                let resolution = Resolution::of(resolved)?;
+                let unchanged = match changes.last() {
+                    Some((_, last)) => *last == resolution,
+                    None => resolution == Resolution::NoCurrent,
+                };
+                if !unchanged {
+                    changes.push((datetime.timestamp(), resolution));
+                }
+            }
+            index.channels.push(ChannelIndex { channel: channel.map(|c| c.to_string()), changes });
+        }
+        Ok(index)
+    }
+
+    /// The version current in the supplied release channel as of the supplied datetime, or
+    /// None if the lookup is one the index cannot answer.
+    pub fn version_in(&self, channel: Option<&str>, datetime: &NaiveDateTime) -> Option<Result<String, SwInstallError>> {
+        let datetime = datetime.timestamp();
+        if self.archived_before.map(|before| datetime < before).unwrap_or(false) {
+            return None;
+        }
+        let changes = &self.channels.iter().find(|c| c.channel.as_deref() == channel)?.changes;
+        let resolution = match changes.last() {
+            // lookups as of now are settled by the last change
+            Some((last, resolution)) if *last <= datetime => resolution,
+            _ => match changes.partition_point(|(at, _)| *at <= datetime) {
+                0 => &Resolution::NoCurrent,
+                idx => &changes[idx - 1].1,
+            },
+        };
+        Some(resolution.version())
+    }
+}
+
+/// Retrieve the index of the swinstall_stack at the supplied path if the index matches the
+/// stack, and otherwise (re)build it from the stack.
+pub fn read_indexed(parser: &SwinstallParser, location: &IndexLocation, swinstall_stack: &str) -> Result<StackIndex, SwInstallError> {
+    let key = IndexKey::of(swinstall_stack)?;
+    let index_path = location.index_path(swinstall_stack)?;
+    match read_index(&index_path) {
+        Some(index) if index.key == key => return Ok(index),
+        Some(_) => debug!("index {:?} is stale", index_path),
+        None => debug!("no usable index at {:?}", index_path),
+    }
+
+    // the key was taken before the stack was read, so a stack replaced in the meantime no
+    // longer matches the index, which is rebuilt next time
+    let index = StackIndex::build(parser, swinstall_stack, key)?;
+    if let Err(e) = store_index(location, swinstall_stack, &index_path, &index) {
+        debug!("unable to write index {:?}: {}", index_path, e);
+    }
+    Ok(index)
+}
+
+/// Remove the index of the supplied swinstall_stack, if it has one.
+pub fn remove_index(location: &IndexLocation, swinstall_stack: &str) -> Result<(), SwInstallError> {
+    let index_path = location.index_path(swinstall_stack)?;
+    if index_path.exists() {
+        fs::remove_file(&index_path)?;
+    }
+    Ok(())
+}
+
+// the index at the supplied path, if there is one we can read
+fn read_index(index_path: &Path) -> Option<StackIndex> {
+    let contents = fs::read(index_path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// write the index under the lock, so long as the stack has not changed since it was indexed
+fn store_index(location: &IndexLocation, swinstall_stack: &str, index_path: &Path, index: &StackIndex) -> Result<(), SwInstallError> {
+    let _lock = match location {
+        IndexLocation::Sidecar => StackLock::acquire_timeout(swinstall_stack, Duration::from_secs(0))?,
+        IndexLocation::Directory(_) => {
+            if let Some(parent) = index_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            StackLock::acquire_timeout(index_path.to_str().ok_or(SwInstallError::ConvertOsStrFail)?, Duration::from_secs(0))?
+        },
+    };
+    if IndexKey::of(swinstall_stack)? != index.key {
+        debug!("{} changed while it was indexed", swinstall_stack);
+        return Ok(());
+    }
+    write_index(index_path, index)
+}
+
+fn write_index(index_path: &Path, index: &StackIndex) -> Result<(), SwInstallError> {
+    if let Some(parent) = index_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = serde_json::to_vec(index).map_err(|e| SwInstallError::RuntimeError(e.to_string()))?;
+    write_atomic(index_path.to_str().ok_or(SwInstallError::ConvertOsStrFail)?, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parser::SwinstallParser,
+        testing::{datetime, install},
+        traits::EltOptions,
+        transaction,
+        utils::swinstall_stack_from_versionless,
+    };
+    use tempfile::TempDir;
+
+    fn parser(location: IndexLocation) -> SwinstallParser {
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        parser.set_default_schema(String::from("2"));
+        parser.set_index(Some(location));
+        parser
+    }
+
+    #[test]
+    fn sidecar_rebuilt_when_stack_changes() {
+        let dir = TempDir::new().unwrap();
+        let parser = parser(IndexLocation::Sidecar);
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        let now = datetime("20190201-120000");
+
+        install(&parser, &dir, versionless.as_str(), "first", "20190101-120000");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "1");
+        let index_path = IndexLocation::Sidecar.index_path(swinstall_stack.as_str()).unwrap();
+        assert!(index_path.exists());
+
+        // lookups are answered from the index while it matches the stack
+        let mut index = read_index(&index_path).unwrap();
+        index.channels[0].changes.last_mut().unwrap().1 = Resolution::Version("42".to_string());
+        write_index(&index_path, &index).unwrap();
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "42");
+
+        // and rebuilt once the stack changes
+        install(&parser, &dir, versionless.as_str(), "second", "20190102-120000");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "2");
+        assert_eq!(read_index(&index_path).unwrap().key, IndexKey::of(swinstall_stack.as_str()).unwrap());
+
+        // an index which cannot be read is ignored
+        fs::write(&index_path, "garbage").unwrap();
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "2");
+        remove_index(&IndexLocation::Sidecar, swinstall_stack.as_str()).unwrap();
+        assert!(!index_path.exists());
+    }
+
+    #[test]
+    fn index_agrees_with_stack() {
+        let dir = TempDir::new().unwrap();
+        let indexed = parser(IndexLocation::Sidecar);
+        let unindexed = SwinstallParser::with_builtin_schemas();
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        let options = EltOptions::default();
+        for (contents, dt, options) in &[
+            ("first", "20190101-120000", options.clone()),
+            ("second", "20190103-120000", options.clone().expires(&datetime("20190105-120000"))),
+            ("beta", "20190102-120000", options.clone().channel("beta")),
+            ("third", "20190104-120000", options.clone().channel("beta").expires(&datetime("20190106-120000"))),
+        ] {
+            let source = dir.path().join("source");
+            fs::write(&source, contents).unwrap();
+            transaction::install(&unindexed, versionless.as_str(), source.to_str().unwrap(), "2", &datetime(dt), options).unwrap();
+        }
+
+        for day in 1..8 {
+            for time in &["115959", "120000", "120001"] {
+                let dt = datetime(format!("2019010{}-{}", day, time).as_str());
+                for channel in &[None, Some("beta"), Some("gamma")] {
+                    let expected = unindexed.version_in(swinstall_stack.as_str(), *channel, &dt).ok();
+                    let resolved = indexed.version_in(swinstall_stack.as_str(), *channel, &dt).ok();
+                    assert_eq!(resolved, expected, "{:?} at {}", channel, dt);
+                }
+            }
+        }
+        let index = read_index(&IndexLocation::Sidecar.index_path(swinstall_stack.as_str()).unwrap()).unwrap();
+        assert_eq!(index.channels.len(), 2);
+    }
+
+    #[test]
+    fn cache_directory() {
+        let dir = TempDir::new().unwrap();
+        let cache = dir.path().join("cache");
+        let location = IndexLocation::Directory(cache.clone());
+        let parser = parser(location.clone());
+        let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless.as_str()).unwrap();
+        install(&parser, &dir, versionless.as_str(), "first", "20190101-120000");
+        install(&parser, &dir, versionless.as_str(), "second", "20190102-120000");
+
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20190101-130000")).unwrap(), "1");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20190102-130000")).unwrap(), "2");
+        assert!(location.index_path(swinstall_stack.as_str()).unwrap().starts_with(&cache));
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+        assert!(!IndexLocation::Sidecar.index_path(swinstall_stack.as_str()).unwrap().exists());
+    }
+}
@@ -0,0 +1,301 @@
+//! journal.rs
+//!
+//! A small write-ahead journal for swinstall transactions.
+//!
+//! Installs and rollbacks touch three files: the versioned copy, the swinstall_stack,
+//! and the versionless file. Before any of them are modified, the transaction writes a
+//! journal into the `bak/<file>/` directory, alongside the swinstall_stack:
+//!
+//! ```text
+//! <file>_swinstall_journal         operation, version, hash and completed steps
+//! <file>_swinstall_journal.before  the swinstall_stack prior to the transaction, if any
+//! <file>_swinstall_journal.after   the swinstall_stack once the transaction completes
+//! ```
+//!
+//! Transactions spanning several files record the changeset id and the versionless path
+//! of every member in each member's journal.
+//!
+//! The journal is removed once the transaction has completed. A journal found on disk
+//! therefore marks an interrupted transaction, which `transaction::recover` either
+//! completes or reverts using the stack images.
+
+use crate::{
+    errors::SwInstallError,
+    lock::hostname,
+    utils::write_atomic,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The operation recorded in a journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Install,
+    /// an install which takes effect at a later datetime
+    Schedule,
+    Rollback,
+    Stage,
+    Promote,
+}
+
+impl Operation {
+    /// Whether the operation makes a version current, and so updates the versionless file.
+    pub fn updates_versionless(self) -> bool {
+        self != Operation::Stage && self != Operation::Schedule
+    }
+
+    /// Whether the operation writes a new versioned file.
+    pub fn creates_version(self) -> bool {
+        match self {
+            Operation::Install | Operation::Schedule | Operation::Stage => true,
+            Operation::Rollback | Operation::Promote => false,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Install => write!(f, "install"),
+            Operation::Schedule => write!(f, "schedule"),
+            Operation::Rollback => write!(f, "rollback"),
+            Operation::Stage => write!(f, "stage"),
+            Operation::Promote => write!(f, "promote"),
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = SwInstallError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "install" => Ok(Operation::Install),
+            "schedule" => Ok(Operation::Schedule),
+            "rollback" => Ok(Operation::Rollback),
+            "stage" => Ok(Operation::Stage),
+            "promote" => Ok(Operation::Promote),
+            _ => Err(SwInstallError::RuntimeError(format!("unknown journal operation: {}", s))),
+        }
+    }
+}
+
+/// A write-ahead journal entry for a single transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Journal {
+    path: String,
+    pub txid: String,
+    pub operation: Operation,
+    pub version: String,
+    pub hash: String,
+    pub changeset: Option<String>,
+    pub channel: Option<String>,
+    pub members: Vec<String>,
+    pub steps: Vec<String>,
+}
+
+impl Journal {
+    /// New up a journal, to be stored at `path`, for the supplied operation.
+    pub fn new(path: &str, operation: Operation, version: &str, hash: &str) -> Self {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Journal {
+            path: path.to_string(),
+            txid: format!("{}-{}-{}", hostname(), std::process::id(), secs),
+            operation,
+            version: version.to_string(),
+            hash: hash.to_string(),
+            changeset: None,
+            channel: None,
+            members: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// The path to the journal.
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// Test whether a journal exists at the supplied path.
+    pub fn exists(path: &str) -> bool {
+        Path::new(path).exists()
+    }
+
+    /// Load the journal stored at `path`, if there is one.
+    pub fn load(path: &str) -> Result<Option<Self>, SwInstallError> {
+        if !Self::exists(path) {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)?;
+        let mut txid = None;
+        let mut operation = None;
+        let mut version = None;
+        let mut hash = None;
+        let mut changeset = None;
+        let mut channel = None;
+        let mut members = Vec::new();
+        let mut steps = Vec::new();
+
+        for line in contents.lines() {
+            let mut pieces = line.splitn(2, ' ');
+            let key = pieces.next().unwrap_or("");
+            let value = pieces.next().unwrap_or("").to_string();
+            match key {
+                "txid" => txid = Some(value),
+                "operation" => operation = Some(value.parse::<Operation>()?),
+                "version" => version = Some(value),
+                "hash" => hash = Some(value),
+                "changeset" => changeset = Some(value),
+                "channel" => channel = Some(value),
+                "member" => members.push(value),
+                "step" => steps.push(value),
+                _ => warn!("ignoring unexpected journal line: {}", line),
+            }
+        }
+
+        let missing = |field: &str| SwInstallError::RuntimeError(format!("journal {} missing {}", path, field));
+        Ok(Some(Journal {
+            path: path.to_string(),
+            txid: txid.ok_or_else(|| missing("txid"))?,
+            operation: operation.ok_or_else(|| missing("operation"))?,
+            version: version.ok_or_else(|| missing("version"))?,
+            hash: hash.ok_or_else(|| missing("hash"))?,
+            changeset,
+            channel,
+            members,
+            steps,
+        }))
+    }
+
+    /// Record the start of the transaction, along with the swinstall_stack contents before
+    /// (if the stack exists) and after the transaction.
+    pub fn begin(&self, before: Option<&str>, after: &str) -> Result<(), SwInstallError> {
+        match before {
+            Some(before) => write_atomic(self.before_path().as_str(), before.as_bytes())?,
+            None => {
+                if Path::new(&self.before_path()).exists() {
+                    fs::remove_file(self.before_path())?;
+                }
+            }
+        }
+        write_atomic(self.after_path().as_str(), after.as_bytes())?;
+
+        // the journal itself is written last. Until it exists, nothing has happened.
+        let mut header = format!(
+            "txid {}\noperation {}\nversion {}\nhash {}\n",
+            self.txid, self.operation, self.version, self.hash
+        );
+        if let Some(ref changeset) = self.changeset {
+            header.push_str(&format!("changeset {}\n", changeset));
+        }
+        if let Some(ref channel) = self.channel {
+            header.push_str(&format!("channel {}\n", channel));
+        }
+        for member in &self.members {
+            header.push_str(&format!("member {}\n", member));
+        }
+        write_atomic(self.path.as_str(), header.as_bytes())
+    }
+
+    /// Record the completion of a step in the transaction.
+    pub fn step(&mut self, step: &str) -> Result<(), SwInstallError> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "step {}", step)?;
+        file.sync_all()?;
+        self.steps.push(step.to_string());
+        Ok(())
+    }
+
+    /// Whether the transaction updates the versionless file. The versionless file tracks the
+    /// default channel, so operations on other release channels leave it alone.
+    pub fn updates_versionless(&self) -> bool {
+        self.operation.updates_versionless() && self.channel.is_none()
+    }
+
+    /// Test whether the named step has completed.
+    pub fn completed(&self, step: &str) -> bool {
+        self.steps.iter().any(|s| s == step)
+    }
+
+    /// The swinstall_stack as it was before the transaction, or None if the
+    /// transaction created the stack.
+    pub fn before(&self) -> Result<Option<String>, SwInstallError> {
+        if !Path::new(&self.before_path()).exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_to_string(self.before_path())?))
+    }
+
+    /// The swinstall_stack as it will be once the transaction completes.
+    pub fn after(&self) -> Result<String, SwInstallError> {
+        Ok(fs::read_to_string(self.after_path())?)
+    }
+
+    /// Mark the transaction finished by removing the journal and its stack images.
+    pub fn finish(self) -> Result<(), SwInstallError> {
+        fs::remove_file(&self.path)?;
+        for image in &[self.before_path(), self.after_path()] {
+            if Path::new(image).exists() {
+                fs::remove_file(image)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove whatever has been written of a transaction which is being abandoned: the
+    /// journal, if it got that far, and its stack images.
+    pub fn discard(self) -> Result<(), SwInstallError> {
+        for path in &[self.path.clone(), self.before_path(), self.after_path()] {
+            if Path::new(path).exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn before_path(&self) -> String {
+        format!("{}.before", self.path)
+    }
+
+    fn after_path(&self) -> String {
+        format!("{}.after", self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn journal_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("packages.xml_swinstall_journal");
+        let path = path.to_str().unwrap();
+
+        let mut journal = Journal::new(path, Operation::Install, "5", "c618755af9b63728411bc536d2c60cf2");
+        journal.changeset = Some("cs1".to_string());
+        journal.channel = Some("dev".to_string());
+        journal.members = vec!["/dd/facility/etc/packages.xml".to_string(), "/dd/facility/etc/env.yaml".to_string()];
+        journal.begin(None, "<stack_history/>").expect("unable to begin");
+        journal.step("versioned").expect("unable to record step");
+
+        let loaded = Journal::load(path).unwrap().expect("journal missing");
+        assert_eq!(loaded, journal);
+        assert!(loaded.completed("versioned"));
+        assert!(!loaded.completed("stack"));
+        assert_eq!(loaded.before().unwrap(), None);
+        assert_eq!(loaded.after().unwrap(), "<stack_history/>");
+
+        loaded.finish().expect("unable to finish");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -4,5 +4,24 @@ pub mod errors;
 pub mod schemas;
 pub mod constants;
 pub mod utils;
+pub mod lock;
+pub mod stack;
+pub mod journal;
+pub mod transaction;
+pub mod changeset;
+pub mod search_path;
+pub mod convert;
+pub mod diagnostic;
+pub mod gc;
+pub mod dedup;
+pub mod compress;
+pub mod archive;
+pub mod index;
+pub mod daemon;
+pub mod http;
+#[cfg(feature = "async")]
+pub mod async_parser;
+#[cfg(test)]
+mod testing;
 
 pub use crate::errors::SwInstallError;
@@ -0,0 +1,302 @@
+//! lock.rs
+//!
+//! Advisory locking for swinstall_stack files.
+//!
+//! Every mutation of a swinstall_stack must hold a `StackLock` for the
+//! duration of its read-modify-write cycle. Stacks commonly live on NFS,
+//! where `O_EXCL` has historically been unreliable, so the lock is taken
+//! using the classic link(2) protocol:
+//!
+//! - write a uniquely named file containing `<host> <pid> <epoch secs>`
+//! - hard link it to `<swinstall_stack>.lock`
+//! - the lock is ours if the link succeeded, or if the unique file's link
+//!   count reached two (the link may succeed on the server even though the
+//!   client reports a failure)
+//!
+//! A lock is considered stale, and is broken, when it was taken on this
+//! host by a process which no longer exists, or when it was taken on another
+//! host and has not been touched for `STALE_LOCK_SECS`. Holders touch the
+//! lock every `HEARTBEAT_SECS` for as long as they hold it, so a lock held
+//! for a long time by a live process on another host is not mistaken for
+//! an abandoned one.
+
+use crate::{
+    constants::{LOCK_TIMEOUT_SECS, STALE_LOCK_SECS},
+    errors::SwInstallError,
+    utils::unique_suffix,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// how long to sleep between attempts to take the lock
+const RETRY_INTERVAL_MS: u64 = 100;
+
+// how often a held lock is touched, well within STALE_LOCK_SECS
+const HEARTBEAT_SECS: u64 = STALE_LOCK_SECS / 10;
+
+/// An advisory lock on a swinstall_stack, released when dropped.
+#[derive(Debug)]
+pub struct StackLock {
+    path: PathBuf,
+    // stops the heartbeat when dropped
+    _heartbeat: Sender<()>,
+}
+
+impl StackLock {
+    /// Acquire the lock for the supplied swinstall_stack, waiting up to
+    /// `LOCK_TIMEOUT_SECS` before giving up.
+    pub fn acquire(swinstall_stack: &str) -> Result<Self, SwInstallError> {
+        Self::acquire_timeout(swinstall_stack, Duration::from_secs(LOCK_TIMEOUT_SECS))
+    }
+
+    /// Acquire the lock for the supplied swinstall_stack, waiting up to
+    /// `timeout` before returning `SwInstallError::LockTimeout`.
+    pub fn acquire_timeout(swinstall_stack: &str, timeout: Duration) -> Result<Self, SwInstallError> {
+        let path = PathBuf::from(format!("{}.lock", swinstall_stack));
+        let started = SystemTime::now();
+
+        loop {
+            if try_lock(&path)? {
+                debug!("acquired lock {}", path.display());
+                let _heartbeat = heartbeat(path.clone());
+                return Ok(StackLock { path, _heartbeat });
+            }
+
+            if let Some(inode) = stale_inode(&path) {
+                warn!("breaking stale lock {}", path.display());
+                break_lock(&path, inode)?;
+                continue;
+            }
+
+            let waited = started.elapsed().unwrap_or_default();
+            if waited >= timeout {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                return Err(SwInstallError::LockTimeout(format!("{} (held by {})", path.display(), holder.trim())));
+            }
+            thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+        }
+    }
+
+    /// The path to the lock file.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+impl Drop for StackLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("unable to remove lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Retrieve the name of the host we are running on.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return String::from("localhost");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+// seconds since the epoch
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// touch the lock at path every HEARTBEAT_SECS until the returned sender is dropped
+fn heartbeat(path: PathBuf) -> Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(Duration::from_secs(HEARTBEAT_SECS)) {
+            if let Err(e) = touch(&path) {
+                warn!("unable to refresh lock {}: {}", path.display(), e);
+            }
+        }
+    });
+    sender
+}
+
+// bring the modification time of the lock at path up to date
+fn touch(path: &PathBuf) -> Result<(), SwInstallError> {
+    OpenOptions::new().write(true).open(path)?.set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+// attempt to take the lock once, using the link(2) protocol
+fn try_lock(path: &PathBuf) -> Result<bool, SwInstallError> {
+    // unique to this thread, as every thread of a process may contend for the lock
+    let unique = PathBuf::from(format!("{}.{}", path.display(), unique_suffix()));
+    {
+        let mut file = fs::File::create(&unique)?;
+        writeln!(file, "{} {} {}", hostname(), std::process::id(), now_secs())?;
+        file.sync_all()?;
+    }
+
+    let linked = fs::hard_link(&unique, path).is_ok()
+        || fs::metadata(&unique).map(|m| m.nlink() == 2).unwrap_or(false);
+    fs::remove_file(&unique)?;
+
+    Ok(linked)
+}
+
+// determine whether the lock at path has been abandoned by its holder, returning the inode
+// of the abandoned lock. The contents and age are read from the same open file, so that they
+// describe the lock whose inode is returned.
+fn stale_inode(path: &PathBuf) -> Option<u64> {
+    // the lock may disappear between attempts, in which case just try again
+    let mut file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).ok()?;
+    let age = metadata.modified()
+        .ok()
+        .and_then(|m| m.elapsed().ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let pieces: Vec<&str> = contents.split_whitespace().collect();
+    let stale = if pieces.len() != 3 {
+        // a partially written or foreign lock file. fall back on its age
+        age > STALE_LOCK_SECS
+    } else if pieces[0] == hostname() {
+        match pieces[1].parse::<i32>() {
+            Ok(pid) => !process_exists(pid),
+            Err(_) => age > STALE_LOCK_SECS,
+        }
+    } else {
+        age > STALE_LOCK_SECS
+    };
+    match stale {
+        true => Some(metadata.ino()),
+        false => None,
+    }
+}
+
+// test whether a process with the supplied pid exists on this host
+fn process_exists(pid: i32) -> bool {
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+// remove the stale lock with the supplied inode. The lock is renamed out of the way first,
+// and checked once it is, so that a lock taken since it was judged stale (by another
+// process breaking the same lock) is put back rather than removed.
+fn break_lock(path: &PathBuf, inode: u64) -> Result<(), SwInstallError> {
+    let broken = PathBuf::from(format!("{}.stale.{}", path.display(), unique_suffix()));
+    if fs::rename(path, &broken).is_ok() {
+        if fs::metadata(&broken)?.ino() != inode {
+            debug!("lock {} was retaken before it could be broken", path.display());
+            // fails if the lock has been taken yet again, by someone who now holds it
+            let _ = fs::hard_link(&broken, path);
+        }
+        fs::remove_file(&broken)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn stack_in(dir: &TempDir) -> String {
+        dir.path().join("packages.xml_swinstall_stack").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn lock_released_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        {
+            let lock = StackLock::acquire(&stack).expect("unable to lock");
+            assert!(lock.path().exists());
+        }
+        assert!(!PathBuf::from(format!("{}.lock", stack)).exists());
+    }
+
+    #[test]
+    fn held_lock_times_out() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        let _lock = StackLock::acquire(&stack).expect("unable to lock");
+        match StackLock::acquire_timeout(&stack, Duration::from_millis(250)) {
+            Err(SwInstallError::LockTimeout(_)) => {},
+            other => panic!("expected LockTimeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stale_lock_is_broken() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        // pid_max on linux tops out well below i32::MAX, so this process cannot exist
+        fs::write(format!("{}.lock", stack), format!("{} {} 0\n", hostname(), i32::MAX)).unwrap();
+        let lock = StackLock::acquire_timeout(&stack, Duration::from_millis(250));
+        assert!(lock.is_ok());
+    }
+
+    #[test]
+    fn held_lock_is_refreshed() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        let path = PathBuf::from(format!("{}.lock", stack));
+        // a lock on another host, untouched for longer than STALE_LOCK_SECS
+        fs::write(&path, "elsewhere 1 0\n").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(STALE_LOCK_SECS + 60);
+        File::open(&path).unwrap().set_modified(old).unwrap();
+        assert!(stale_inode(&path).is_some());
+        // until its holder's heartbeat touches it
+        touch(&path).unwrap();
+        assert!(stale_inode(&path).is_none());
+    }
+
+    #[test]
+    fn retaken_lock_is_not_broken() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        let path = PathBuf::from(format!("{}.lock", stack));
+        fs::write(&path, format!("{} {} 0\n", hostname(), i32::MAX)).unwrap();
+        let inode = stale_inode(&path).unwrap();
+        // someone else breaks the lock and takes it before we get to it. The broken lock is
+        // kept, so that its inode cannot be reused for the new one
+        fs::rename(&path, dir.path().join("broken")).unwrap();
+        let _lock = StackLock::acquire(&stack).unwrap();
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode);
+        break_lock(&path, inode).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn threads_exclude_one_another() {
+        let dir = TempDir::new().unwrap();
+        let stack = stack_in(&dir);
+        let counter = dir.path().join("counter");
+        fs::write(&counter, "0").unwrap();
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..10 {
+                        let _lock = StackLock::acquire(&stack).unwrap();
+                        let count: u32 = fs::read_to_string(&counter).unwrap().parse().unwrap();
+                        thread::yield_now();
+                        fs::write(&counter, (count + 1).to_string()).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(fs::read_to_string(&counter).unwrap(), "80");
+    }
+}
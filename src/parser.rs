@@ -4,15 +4,24 @@
 use chrono::{ NaiveDateTime, Local };
 use crate::{
     SwInstallError,
-    traits::SwinstallCurrent,
-    utils::versioned_from_swinstall_stack
+    archive::with_archive,
+    compress::open_versioned,
+    constants::DATETIME_FMT,
+    diagnostic::Diagnostic,
+    index::{read_indexed, IndexLocation},
+    schemas::{one::One, two::Two},
+    stack::StackDocument,
+    traits::{tagged_version, SwinstallCurrent},
+    utils::{swinstall_stack_from_versionless, versioned_from_swinstall_stack},
 };
-use log::{debug};
+use log::{debug, warn};
 use std::{
     collections::HashMap,
-    io::BufReader,
+    io::{BufReader, Read},
     fs::File,
-    path::{Path, PathBuf},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
 };
 use quick_xml::{
     events::{ BytesStart, Event },
@@ -20,8 +29,15 @@ use quick_xml::{
 };
 
 type SwReader = Reader<BufReader<File>>;
-type SwinstallCurrentRegistry = HashMap<&'static str, Box<dyn SwinstallCurrent<SwBufReader = BufReader<File>>> > ;
 
+/// A schema implementation, as registered with the parser. Implementations are `Send + Sync`,
+/// so the parser may be shared between threads, behind an `Arc` or in a static.
+pub type SwinstallSchema = dyn SwinstallCurrent<SwBufReader = BufReader<File>>;
+
+type SwinstallCurrentRegistry = HashMap<&'static str, Box<SwinstallSchema>>;
+
+/// Resolves swinstall_stacks, dispatching on their schema to the registered implementations.
+/// The parser is `Send + Sync`; build it once and share it between threads.
 #[derive(Debug)]
 pub struct SwinstallParser {
     // Registry hashmap storing different implementations of elt parser based on
@@ -29,7 +45,11 @@ pub struct SwinstallParser {
     registry: SwinstallCurrentRegistry,
     // optional default key in case the swinstall_stack does not have a schema
     // attribute
-    default_schema: Option<String>
+    default_schema: Option<String>,
+    // recover what we can from damaged swinstall_stacks, rather than failing
+    lenient: bool,
+    // where to cache the parsed entries of swinstall_stacks, if anywhere
+    index: Option<IndexLocation>,
 }
 
 impl SwinstallParser {
@@ -37,14 +57,26 @@ impl SwinstallParser {
     pub fn new() -> Self {
         SwinstallParser {
             registry: SwinstallCurrentRegistry::new(),
-            default_schema: None
+            default_schema: None,
+            lenient: false,
+            index: None,
         }
     }
 
+    /// new up a Parser with the built in schemas registered, defaulting to schema 1 for
+    /// swinstall_stacks which do not declare a schema, as the oldest stacks do not.
+    pub fn with_builtin_schemas() -> Self {
+        let mut parser = Self::new();
+        parser.register(Box::new(One::new()));
+        parser.register(Box::new(Two::new()));
+        parser.set_default_schema(String::from("1"));
+        parser
+    }
+
     /// Register a struct implementing SwinstallCurrent with the schema registry,
     /// which affords for handling different generations of an swinstall_stack
     /// from the same code.
-    pub fn register(&mut self, value: Box<dyn SwinstallCurrent<SwBufReader = BufReader<File>>>) {
+    pub fn register(&mut self, value: Box<SwinstallSchema>) {
         self.registry.insert(value.schema(), value);
     }
 
@@ -61,11 +93,64 @@ impl SwinstallParser {
         true
     }
 
+    /// Set lenient mode. In lenient mode, a lookup against a damaged swinstall_stack falls back
+    /// on the elt tags which can be recovered from it (see `read_lenient`), logging the damage
+    /// as warnings. The lookup only succeeds if none of the damage precedes the elt tags which
+    /// decide the current version; otherwise it fails with `SwInstallError::AmbiguousCurrent`.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Set where to keep indexes of the swinstall_stacks looked up, caching their parsed entries
+    /// so that lookups against an unchanged stack skip parsing its xml (see `index`). None,
+    /// the default, streams every lookup from the stack itself.
+    pub fn set_index(&mut self, index: Option<IndexLocation>) {
+        self.index = index;
+    }
+
+    /// Read the swinstall_stack at the supplied path, recovering every well formed element we
+    /// can. Elements which are malformed, or which do not validate against the stack's schema,
+    /// are dropped. Returns the recovered stack along with the damage found, in document order.
+    pub fn read_lenient(&self, swinstall_stack: &str) -> Result<(StackDocument, Vec<Diagnostic>), SwInstallError> {
+        let contents = std::fs::read_to_string(swinstall_stack)?;
+        let (mut stack, mut warnings) = StackDocument::from_xml_lenient(contents.as_str())?;
+        for warning in warnings.iter_mut() {
+            warning.path = Some(swinstall_stack.to_string());
+        }
+
+        let component = self.component_for(&stack)?;
+        let mut elements = Vec::with_capacity(stack.elements.len());
+        for element in stack.elements.drain(..) {
+            match component.validate(&element) {
+                Ok(()) => elements.push(element),
+                Err(e) => {
+                    let offset = element.offset().unwrap_or(0);
+                    warnings.push(Diagnostic::new(Some(swinstall_stack), &contents, offset, e));
+                }
+            }
+        }
+        stack.elements = elements;
+        warnings.sort_by_key(|w| w.offset);
+        Ok((stack, warnings))
+    }
+
     /// Retrieve the SwinstallComponent registered against a paritcular schema.
-    pub fn get_component(&self, schema: &str) -> Option<&Box<dyn SwinstallCurrent<SwBufReader = BufReader<File>>>> {
+    pub fn get_component(&self, schema: &str) -> Option<&Box<SwinstallSchema>> {
         self.registry.get(schema)
     }
 
+    /// Retrieve the SwinstallCurrent registered against the schema of the supplied stack
+    /// document, falling back on the default schema if the document does not declare one.
+    pub fn component_for(&self, stack: &StackDocument) -> Result<&SwinstallSchema, SwInstallError> {
+        let schema = match stack.schema() {
+            Some(schema) => schema.to_string(),
+            None => self.default_schema.clone().ok_or(SwInstallError::NoDefaultSchema)?,
+        };
+        self.get_component(schema.as_str())
+            .map(|component| component.as_ref())
+            .ok_or_else(|| SwInstallError::RuntimeError(format!("Unable to get reader for schema: {}", schema)))
+    }
+
     // retrieve the schema
     fn schema<'a>(&self,  e: &'a BytesStart) -> Result<String, SwInstallError> {
          let mut schema = self.default_schema.clone().ok_or(SwInstallError::NoDefaultSchema)?;
@@ -83,13 +168,16 @@ impl SwinstallParser {
     }
 
     // Get the current version as a String
-    fn current_version<'a>(&self, reader: &mut SwReader, schema: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+    fn current_version<'a>(&self, reader: &mut SwReader, schema: &str, datetime: &NaiveDateTime, channel: Option<&str>) -> Result<String, failure::Error> {
 
         let elt_reader = self.get_component(schema).ok_or(SwInstallError::RuntimeError(format!("Unable to get reader for schema: {}", schema)))?;
         debug!("calling elt_reader.current_at(reader, {})", datetime);
 
         // get back the version string of the current file
-        let result = elt_reader.current_at(reader, datetime)?;
+        let result = match channel {
+            Some(channel) => elt_reader.current_in_channel(reader, datetime, channel)?,
+            None => elt_reader.current_at(reader, datetime)?,
+        };
         Ok(result)
     }
 
@@ -100,8 +188,144 @@ impl SwinstallParser {
     }
 
     /// Retrieve the path to the file marked current as close to but not later
-    /// than the supplied datetime.
+    /// than the supplied datetime. Versions which are no longer in use may be stored
+    /// compressed; see `open_in` to read them.
     pub fn current_at(&self, swinstall_stack: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let version_string = self.version_at(swinstall_stack, datetime)?;
+        // we construct the full path to the versioned file out of the full path to the swinstall_stack
+        // and the version_string
+        let versioned_file = versioned_from_swinstall_stack(swinstall_stack, version_string.as_str())?;
+        Ok(versioned_file)
+    }
+
+    /// Retrieve the path to the file current in the named release channel as close to but not
+    /// later than the supplied datetime. A channel of None is the default channel, as used by
+    /// `current_at`.
+    pub fn current_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let version_string = self.version_in(swinstall_stack, channel, datetime)?;
+        let versioned_file = versioned_from_swinstall_stack(swinstall_stack, version_string.as_str())?;
+        Ok(versioned_file)
+    }
+
+    /// Resolve the path to the file current at the supplied datetime for each of the supplied
+    /// versionless files, concurrently. Returns one result per file, in the order supplied.
+    pub fn resolve_many(&self, versionless: &[String], datetime: &NaiveDateTime) -> Vec<Result<String, failure::Error>> {
+        self.resolve_many_in(versionless, None, datetime)
+    }
+
+    /// Resolve the path to the file current in the named release channel for each of the
+    /// supplied versionless files, as `resolve_many` does.
+    pub fn resolve_many_in(&self, versionless: &[String], channel: Option<&str>, datetime: &NaiveDateTime) -> Vec<Result<String, failure::Error>> {
+        let resolve = |file: &str| -> Result<String, failure::Error> {
+            let swinstall_stack = swinstall_stack_from_versionless(file)?;
+            self.current_in(swinstall_stack.as_str(), channel, datetime)
+        };
+        let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(versionless.len());
+        if workers <= 1 {
+            return versionless.iter().map(|file| resolve(file.as_str())).collect();
+        }
+
+        // each worker claims the next unresolved file until none remain
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<(usize, Result<String, failure::Error>)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers).map(|_| scope.spawn(|| {
+                let mut resolved = Vec::new();
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    match versionless.get(idx) {
+                        Some(file) => resolved.push((idx, resolve(file.as_str()))),
+                        None => return resolved,
+                    }
+                }
+            })).collect();
+            handles.into_iter()
+                   .flat_map(|handle| handle.join().expect("resolve_many worker panicked"))
+                   .collect()
+        });
+        results.sort_by_key(|(idx, _)| *idx);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Open the file current in the named release channel as of the supplied datetime for
+    /// reading. Unlike the path returned by `current_in`, this reads the version whether or not
+    /// it has been compressed (see `compress`).
+    pub fn open_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<Box<dyn Read>, failure::Error> {
+        let versioned_file = self.current_in(swinstall_stack, channel, datetime)?;
+        Ok(open_versioned(versioned_file.as_str())?)
+    }
+
+    /// Retrieve the path to the version labelled by `tag` as of the supplied datetime.
+    pub fn resolve_tag(&self, swinstall_stack: &str, tag: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let version_string = self.tagged_version_at(swinstall_stack, tag, datetime)?;
+        let versioned_file = versioned_from_swinstall_stack(swinstall_stack, version_string.as_str())?;
+        Ok(versioned_file)
+    }
+
+    /// Retrieve the version string labelled by `tag` as of the supplied datetime.
+    pub fn tagged_version_at(&self, swinstall_stack: &str, tag: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let stack = StackDocument::from_file(swinstall_stack)?;
+        let tags = self.component_for(&stack)?.tags(&stack)?;
+        let version = tagged_version(&tags, tag, datetime).ok_or_else(|| SwInstallError::TagNotFound(tag.to_string()))?;
+        Ok(version.to_string())
+    }
+
+    /// Retrieve the version string of the file marked current as close to but not later
+    /// than the supplied datetime.
+    pub fn version_at(&self, swinstall_stack: &str, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        self.version_in(swinstall_stack, None, datetime)
+    }
+
+    /// Retrieve the version string of the file current in the named release channel as close
+    /// to but not later than the supplied datetime.
+    pub fn version_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        if let Some(ref location) = self.index {
+            match read_indexed(self, location, swinstall_stack) {
+                Ok(index) => if let Some(resolved) = index.version_in(channel, datetime) {
+                    return Ok(resolved?);
+                },
+                // damage is left to the streaming lookup, which locates it
+                Err(ref e) if e.is_malformed() => debug!("unable to index {}: {}", swinstall_stack, e),
+                Err(e) => return Err(e)?,
+            }
+        }
+        match self.stream_version_in(swinstall_stack, channel, datetime) {
+            Err(ref e) if self.lenient && is_damage(e) => {
+                debug!("falling back on lenient parse: {}", e);
+                self.recovered_version_in(swinstall_stack, channel, datetime)
+            },
+            result => result,
+        }
+    }
+
+    // resolve the version against what can be recovered from a damaged swinstall_stack
+    fn recovered_version_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let (stack, warnings) = self.read_lenient(swinstall_stack)?;
+        for warning in &warnings {
+            warn!("{}", warning);
+        }
+        let resolved = self.component_for(&stack)?.current_in_stack(&stack, datetime, channel);
+        // the damage may have hidden the current elt if it precedes the elt which decided it
+        let decided_at = match resolved {
+            Ok((_, idx)) => stack.elements[idx].offset(),
+            Err(SwInstallError::NoCurrentFound) => None,
+            Err(e) => return Err(e)?,
+        };
+        if let Some(damage) = warnings.iter().find(|w| decided_at.map(|offset| w.offset < offset).unwrap_or(true)) {
+            return Err(SwInstallError::AmbiguousCurrent(damage.to_string()))?;
+        }
+        Ok(resolved?.0)
+    }
+
+    // resolve the version from the swinstall_stack together with the entries archived from it
+    fn archived_version_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
+        let stack = StackDocument::from_file(swinstall_stack)?;
+        let merged = with_archive(self, swinstall_stack, &stack)?;
+        debug!("consulting the archive of {} as of {}", swinstall_stack, datetime);
+        Ok(self.component_for(&stack)?.current_in_stack(&merged, datetime, channel)?.0)
+    }
+
+    // resolve the version by streaming the swinstall_stack
+    fn stream_version_in(&self, swinstall_stack: &str, channel: Option<&str>, datetime: &NaiveDateTime) -> Result<String, failure::Error> {
         let mut reader = Reader::from_file(Path::new(swinstall_stack))?;
         let mut buf = Vec::new();
 
@@ -109,23 +333,40 @@ impl SwinstallParser {
             match reader.read_event(&mut buf) {
                 Ok(Event::Start(ref e)) => {
                     if e.name() == b"stack_history" {
+                        // lookups before the entries left in an archived stack need the archive
+                        if let Some(before) = archived_before(e)? {
+                            if *datetime < before {
+                                return self.archived_version_in(swinstall_stack, channel, datetime);
+                            }
+                        }
                         // get schema version
                         let schema = self.schema(&e)?;
 
-                        debug!("current_at - calling self.current_version(...)");
+                        // schemas which append entries may settle the lookup from the end
+                        if channel.is_none() {
+                            if let Some(component) = self.get_component(schema.as_str()) {
+                                let mut file = File::open(swinstall_stack)?;
+                                let start = reader.buffer_position() as u64;
+                                if let Some(version) = component.current_from_end(&mut file, start, datetime)? {
+                                    return Ok(version);
+                                }
+                            }
+                        }
+
+                        debug!("version_in - calling self.current_version(...)");
                         // we find a current file or we error
-                        let version_string = self.current_version(&mut reader, schema.as_str(), datetime)?;
-                        // we construct the full path to the versioned file out of the full path to the swinstall_stack
-                        // and the version_string
-                        let versioned_file = versioned_from_swinstall_stack(swinstall_stack, version_string.as_str())?;
-                        return Ok(versioned_file);
+                        return self.current_version(&mut reader, schema.as_str(), datetime, channel)
+                            .map_err(|e| match e.downcast::<SwInstallError>() {
+                                Ok(e) => locate(swinstall_stack, reader.buffer_position(), e).into(),
+                                Err(e) => e,
+                            });
                     }
                 },
                 // we never found stack_history
                 Ok(Event::Eof) => {
                     return Err(SwInstallError::NoCurrentFound)?
                 }, // exits the loop when reaching end of file
-                Err(e) => return Err(e)?,
+                Err(e) => return Err(locate(swinstall_stack, reader.buffer_position(), e.into()))?,
                 _ => {}, // There are several other `Event`s we do not consider here
             }
 
@@ -136,6 +377,42 @@ impl SwinstallParser {
 
 }
 
+// the datetime before which lookups must consult the archive of the stack, if it has one
+fn archived_before(e: &BytesStart) -> Result<Option<NaiveDateTime>, SwInstallError> {
+    for attr in e.attributes() {
+        let attr = attr?;
+        if attr.key == b"archived_before" {
+            let value = std::str::from_utf8(&attr.value)?;
+            return NaiveDateTime::parse_from_str(value, DATETIME_FMT)
+                .map(Some)
+                .map_err(|_| SwInstallError::InvalidEltAttribute("archived_before".to_string(), value.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// is the error the result of a damaged swinstall_stack
+fn is_damage(error: &failure::Error) -> bool {
+    matches!(error.downcast_ref::<SwInstallError>(), Some(SwInstallError::Malformed(_)))
+}
+
+// locate an error in the contents of the swinstall_stack, given the position the reader had
+// reached when it was encountered. Errors which do not describe the contents are left alone.
+fn locate(swinstall_stack: &str, position: usize, error: SwInstallError) -> SwInstallError {
+    if !error.is_malformed() {
+        return error;
+    }
+    let contents = match std::fs::read_to_string(swinstall_stack) {
+        Ok(contents) => contents,
+        Err(_) => return error,
+    };
+    match error {
+        // syntax errors are found where the reader stopped; anything else concerns the tag
+        // it has just read
+        SwInstallError::QuckXmlError(_) => Diagnostic::new(Some(swinstall_stack), &contents, position, error),
+        _ => Diagnostic::in_tag_ending(Some(swinstall_stack), &contents, position, error),
+    }.into()
+}
 
 #[cfg(test)]
 mod tests {
@@ -226,4 +503,130 @@ mod tests {
             panic!("unable to get schema 1");
         };
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn malformed_stack_is_located() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let swinstall_stack = dir.path().join("packages.xml_swinstall_stack");
+        std::fs::write(&swinstall_stack, r#"<?xml version="1.0" encoding="UTF-8"?>
+<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+   <elt action="install" datetime="20181221-142313" version="5"/>
+</stack_history>
+"#).unwrap();
+        let mut parser = SwinstallParser::new();
+        parser.register(Box::new(crate::schemas::two::Two::new()));
+        parser.set_default_schema(String::from("2"));
+        let datetime = NaiveDateTime::parse_from_str("20190101-000000", "%Y%m%d-%H%M%S").unwrap();
+
+        let error = parser.version_at(swinstall_stack.to_str().unwrap(), &datetime).unwrap_err();
+        match error.downcast_ref::<SwInstallError>() {
+            Some(SwInstallError::Malformed(diagnostic)) => {
+                assert_eq!(diagnostic.path.as_deref(), swinstall_stack.to_str());
+                assert_eq!((diagnostic.line, diagnostic.column), (3, 4));
+                assert_eq!(diagnostic.element.as_deref(), Some("elt"));
+                assert_eq!(diagnostic.attribute.as_deref(), Some("hash"));
+            },
+            _ => panic!("expected a diagnostic, got {}", error),
+        }
+    }
+
+    #[test]
+    fn resolve_many_keeps_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut parser = SwinstallParser::new();
+        parser.register(Box::new(crate::schemas::two::Two::new()));
+        parser.set_default_schema(String::from("2"));
+        let datetime = NaiveDateTime::parse_from_str("20190101-000000", "%Y%m%d-%H%M%S").unwrap();
+        let source = dir.path().join("source");
+        std::fs::write(&source, "contents").unwrap();
+
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let versionless = dir.path().join(format!("file{}.xml", i)).to_str().unwrap().to_string();
+            // every third file was never installed
+            if i % 3 != 0 {
+                crate::transaction::install(
+                    &parser, versionless.as_str(), source.to_str().unwrap(), "2", &datetime, &Default::default()
+                ).unwrap();
+            }
+            files.push(versionless);
+        }
+        let results = parser.resolve_many(&files, &datetime);
+        assert_eq!(results.len(), files.len());
+        for (i, result) in results.iter().enumerate() {
+            match result {
+                Ok(path) => assert!(i % 3 != 0 && path.ends_with(format!("file{}.xml_1", i).as_str())),
+                Err(_) => assert_eq!(i % 3, 0),
+            }
+        }
+    }
+
+    // the versionless files of the example stacks, one per schema
+    fn examples() -> Vec<String> {
+        ["schema1", "schema2"].iter()
+            .map(|schema| format!("{}/examples/{}/packages.xml", env!("CARGO_MANIFEST_DIR"), schema))
+            .collect()
+    }
+
+    // resolve every example at each of the supplied datetimes
+    fn resolve_examples(parser: &SwinstallParser, datetimes: &[NaiveDateTime]) -> Vec<Option<String>> {
+        let mut resolved = Vec::new();
+        for datetime in datetimes {
+            resolved.extend(parser.resolve_many(&examples(), datetime).into_iter().map(Result::ok));
+        }
+        resolved
+    }
+
+    fn datetimes() -> Vec<NaiveDateTime> {
+        ["20171106-104603", "20180615-000000", "20181221-142300", "20181221-143000", "20190101-000000"].iter()
+            .map(|dt| NaiveDateTime::parse_from_str(dt, "%Y%m%d-%H%M%S").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parser_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SwinstallParser>();
+        assert_send_sync::<Box<SwinstallSchema>>();
+    }
+
+    #[test]
+    fn builtin_schemas_registered() {
+        let parser = SwinstallParser::with_builtin_schemas();
+        assert!(parser.get_component("1").is_some());
+        assert!(parser.get_component("2").is_some());
+        assert_eq!(parser.default_schema.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let parser = std::sync::Arc::new(SwinstallParser::with_builtin_schemas());
+        let datetimes = datetimes();
+        let expected = resolve_examples(&parser, &datetimes);
+        assert!(expected.iter().filter(|r| r.is_some()).count() > 5);
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let parser = std::sync::Arc::clone(&parser);
+            let datetimes = datetimes.clone();
+            std::thread::spawn(move || (0..20).map(|_| resolve_examples(&parser, &datetimes)).collect::<Vec<_>>())
+        }).collect();
+        for handle in handles {
+            for resolved in handle.join().unwrap() {
+                assert_eq!(resolved, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_from_static() {
+        static PARSER: std::sync::OnceLock<SwinstallParser> = std::sync::OnceLock::new();
+        let parser = || PARSER.get_or_init(SwinstallParser::with_builtin_schemas);
+        let datetimes = datetimes();
+        let expected = resolve_examples(parser(), &datetimes);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| assert_eq!(resolve_examples(parser(), &datetimes), expected));
+            }
+        });
+    }
+}
@@ -50,17 +50,35 @@
 //!   recording change dates resulting in lossy history. One cannot reconstruct the
 //!   sequence of events which resulted in the current state if rollbacks have occured.
 //! - new versions are appended to the end of stack_history, making non-pathological
-//!   use cases take O(n) time for lookups (bad design)
+//!   use cases take O(n) time for lookups (bad design). Old entries may be moved
+//!   out of the way with `archive`
 //! - version stores both a date-time stamp and an optional VCS revision id
 //!
+//! # Reading from the end
+//!
+//! Since new versions are appended, the current version is nearly always at or near the
+//! end of the stack. `current_from_end` therefore reads the stack backwards, a block at a
+//! time, taking the entry marked current once it has checked that no entry above it is also
+//! marked current. This avoids parsing the stack as a document, though the whole file is still
+//! read.
+//!
+//! Reading from the end agrees with the forward scan of `current_at` for every stack
+//! swinstall writes, in which at most one entry is marked current and no entry is dated after
+//! the stack was last written (the forward scan stops at the first entry dated after the
+//! lookup). Lookups earlier than the last modification of the stack are therefore left to
+//! the forward scan, as are stacks which are not as swinstall writes them: several entries
+//! marked current, entries dated after the lookup, comments, or tags which do not parse.
+//!
 
-use chrono::{ NaiveDateTime };
+use chrono::{ DateTime, Local, NaiveDateTime };
 use crate::constants::DATETIME_FMT;
 use crate::errors::SwInstallError;
-use crate::traits::SwinstallCurrent;
+use crate::stack::{StackDocument, StackElement};
+use crate::traits::{EltOptions, SwinstallCurrent, VersionRecord, VersionedFile};
 use std::{
+    cmp::Reverse,
     fs::File,
-    io::BufReader,
+    io::{BufReader, Read, Seek, SeekFrom},
     str::{ FromStr, from_utf8, }
 };
 #[allow(unused_imports)]
@@ -69,13 +87,18 @@ use quick_xml::{
     events::{attributes::Attributes, Event, },
     Reader,
 };
+use serde::{Deserialize, Serialize};
 
 /// Model the elt tag contents from swinstall_log
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Elt {
     pub is_current: bool,
     pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub revision: Option<String>,
+    /// when the versioned file was garbage collected, if it has been
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pruned: Option<String>,
 }
 
 impl Elt {
@@ -84,19 +107,21 @@ impl Elt {
         let revision = if pieces.len() == 2 { pieces.pop() } else { None };
         let version = pieces.pop().unwrap_or("10000101-010101".to_string());
         Elt {
-            is_current, version, revision
+            is_current, version, revision, pruned: None
         }
     }
 
     pub fn from_attrs<'a>(attrs: Attributes<'a>) -> Result<Elt, SwInstallError> {
         let mut is_current = None;
         let mut version = None;
+        let mut pruned = None;
 
         for attr in attrs {
             let attr = attr?;
             match attr.key {
                 b"is_current"   => is_current = Some(attr.value),
                 b"version"  => version = Some(attr.value),
+                b"pruned"   => pruned = Some(from_utf8(&attr.value)?.to_string()),
                 _ => {},
             }
         }
@@ -107,27 +132,49 @@ impl Elt {
         //    convert to a vec<u8> ( into_owned())
         //    convert to a str (from_utf8)
         //    convert to a String (to_string)
-        let elt = Elt::new(
-            bool::from_str(
-                from_utf8(
-                    &is_current
-                    .ok_or(SwInstallError::MissingEltAttribute)?
-                    .into_owned()
-                )?
-                .to_string()
-                .to_lowercase()
-                .as_str()
-            )?,
+        let is_current = from_utf8(
+            &is_current
+            .ok_or_else(|| SwInstallError::MissingEltAttribute("is_current".to_string()))?
+            .into_owned()
+        )?
+        .to_string();
+        let mut elt = Elt::new(
+            bool::from_str(is_current.to_lowercase().as_str())
+                .map_err(|_| SwInstallError::InvalidEltAttribute("is_current".to_string(), is_current.clone()))?,
             from_utf8(
                 &version
-                .ok_or(SwInstallError::MissingEltAttribute)?
+                .ok_or_else(|| SwInstallError::MissingEltAttribute("version".to_string()))?
                 .into_owned()
             )?
             .to_string(),
         );
+        elt.pruned = pruned;
         debug!("Elt::from_attrs(...) -> {:?}", elt);
         Ok(elt)
     }
+
+    /// New up an Elt from an elt element of a stack document.
+    pub fn from_element(element: &StackElement) -> Result<Elt, SwInstallError> {
+        let is_current = element.require("is_current")?;
+        let mut elt = Elt::new(
+            bool::from_str(is_current.to_lowercase().as_str())
+                .map_err(|_| SwInstallError::InvalidEltAttribute("is_current".to_string(), is_current.to_string()))?,
+            element.require("version")?.to_string(),
+        );
+        elt.pruned = element.get("pruned").map(|v| v.to_string());
+        // the version is made up of the install datetime and an optional revision
+        NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)
+            .map_err(|_| SwInstallError::InvalidEltAttribute("version".to_string(), elt.version.clone()))?;
+        Ok(elt)
+    }
+
+    // the version string, including the revision if there is one
+    fn full_version(&self) -> String {
+        match self.revision {
+            Some(ref r) => format!("{}_{}", self.version, r),
+            None => self.version.clone(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -146,7 +193,8 @@ mod tests {
                             let expected = Elt {
                                 is_current: true,
                                 version: "20161213-093146".to_string(),
-                                revision: None
+                                revision: None,
+                                pruned: None,
                             };
                             assert_eq!(elt, expected);
                             break;
@@ -168,7 +216,8 @@ mod tests {
                             let expected = Elt {
                                 is_current: true,
                                 version: "20161213-093146".to_string(),
-                                revision: Some("r575055".to_string())
+                                revision: Some("r575055".to_string()),
+                                pruned: None,
                             };
                             assert_eq!(elt, expected);
                             break;
@@ -177,6 +226,88 @@ mod tests {
             }
         }
     }
+
+    const STACK: &str = r#"<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack">
+   <elt is_current="False" version="20161220-090624"/>
+   <elt is_current="True" version="20180613-093146_r575055"/>
+</stack_history>"#;
+
+    #[test]
+    fn install_appends_current_elt() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let version = One::new().install(&mut stack, "abc", &dt, &EltOptions::default()).expect("unable to install");
+        assert_eq!(version, "20181221-102242");
+        let current: Vec<&str> = stack.elts()
+                                      .filter(|e| e.get("is_current") == Some("True"))
+                                      .filter_map(|e| e.get("version"))
+                                      .collect();
+        assert_eq!(current, vec!["20181221-102242"]);
+        assert_eq!(stack.elts().last().unwrap().get("version"), Some("20181221-102242"));
+    }
+
+    #[test]
+    fn rollback_moves_is_current() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        One::new().rollback(&mut stack, "20161220-090624", &dt, &EltOptions::default()).expect("unable to rollback");
+        let first = stack.elts().next().unwrap();
+        assert_eq!(first.get("is_current"), Some("True"));
+        assert!(One::new().rollback(&mut stack, "20000101-000000", &dt, &EltOptions::default()).is_err());
+    }
+
+    #[test]
+    fn reconstruct_from_versions() {
+        let file = |version: &str| VersionedFile {
+            version: version.to_string(),
+            modified: NaiveDateTime::parse_from_str("20190101-000000", DATETIME_FMT).unwrap(),
+            hash: String::new(),
+        };
+        let files = vec![file("20180613-093146_r575055"), file("3"), file("20161220-090624")];
+        let mut stack = StackDocument::new("packages.xml_swinstall_stack", "1");
+        let now = NaiveDateTime::parse_from_str("20190101-000000", DATETIME_FMT).unwrap();
+        let guesses = One::new().reconstruct(&mut stack, &files, Some("20161220-090624"), &now).unwrap();
+        assert_eq!(guesses.len(), 1);
+        let elts: Vec<(&str, &str)> = stack.elts()
+            .map(|e| (e.get("version").unwrap(), e.get("is_current").unwrap()))
+            .collect();
+        assert_eq!(elts, vec![("20161220-090624", "True"), ("20180613-093146_r575055", "False")]);
+    }
+
+    #[test]
+    fn current_in_stack_agrees_with_current_at() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/schema1/bak/packages.xml/packages.xml_swinstall_stack");
+        let stack = StackDocument::from_file(path).unwrap();
+        let one = One::new();
+        let datetimes = ["20161220-090624", "20171201-000000", "20180613-100000", "20190101-000000", "20100101-000000"]
+            .iter()
+            .map(|datetime| NaiveDateTime::parse_from_str(datetime, DATETIME_FMT).unwrap())
+            .collect::<Vec<_>>();
+        let resolved_at = one.current_in_stack_at(&stack, &datetimes, None).unwrap();
+        for (datetime, resolved_at) in datetimes.iter().zip(resolved_at) {
+            let mut reader = Reader::from_file(path).unwrap();
+            let streamed = one.current_at(&mut reader, datetime).ok();
+            let resolved = one.current_in_stack(&stack, datetime, None).ok().map(|(version, _)| version);
+            assert_eq!(resolved, streamed, "at {}", datetime);
+            assert_eq!(resolved_at.ok().map(|(version, _)| version), streamed, "at {}", datetime);
+        }
+    }
+
+    #[test]
+    fn pruned_version_is_reported() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let now = NaiveDateTime::parse_from_str("20190101-000000", DATETIME_FMT).unwrap();
+        One::new().prune(&mut stack, "20161220-090624", &now).unwrap();
+        let elt = Elt::from_element(stack.elts().next().unwrap()).unwrap();
+        assert_eq!(elt.pruned.as_deref(), Some("20190101-000000"));
+
+        // the pruned version was current in 2017, and its predecessor is no substitute
+        let datetime = NaiveDateTime::parse_from_str("20171201-000000", DATETIME_FMT).unwrap();
+        match One::new().current_in_stack(&stack, &datetime, None) {
+            Err(SwInstallError::VersionPruned(version)) => assert_eq!(version, "20161220-090624"),
+            other => panic!("expected a pruned version, got {:?}", other),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -215,13 +346,14 @@ impl SwinstallCurrent for One {
                         debug!("current_at - Event::Empty - Elt::from_attrs returned");
                         let version_str = elt.version.as_str();
                         debug!("current_at - Event::Empty - passing {} to NaiveDateTime::parse_from_str", version_str);
-                        let dt = NaiveDateTime::parse_from_str(version_str, DATETIME_FMT)?;
+                        let dt = NaiveDateTime::parse_from_str(version_str, DATETIME_FMT)
+                            .map_err(|_| SwInstallError::InvalidEltAttribute("version".to_string(), version_str.to_string()))?;
                         // update loop state variables
                         in_datetime =  dt <= *datetime;
                         current = elt.is_current ;
                         debug!("current_at - Event::Empty - state vars: <in_datetime: {} current: {}>", in_datetime, current);
                         // we only update the last_elt if we are in the valid datetime range
-                        // as specified by the user.
+                        // as specified by the user
                         if in_datetime {
                             last_elt = Some(elt);
                         }
@@ -246,12 +378,12 @@ impl SwinstallCurrent for One {
             // 2 - we are not in the datetime range. (presumably we were the prior loop)
             if in_empty && ((current && in_datetime) || !in_datetime) {
                 match last_elt {
+                    // the versioned file is gone, and an older version is no substitute
+                    Some(ref elt) if elt.pruned.is_some() => {
+                        return Err(SwInstallError::VersionPruned(elt.full_version()))?
+                    }
                     Some(ref elt) => {
-                        return match elt.revision {
-                            Some(ref r) => Ok(format!("{}_{}", elt.version, r)),
-                            None => return Ok(elt.version.clone()),
-                        };
-
+                        return Ok(elt.full_version());
                     }
                     None => {
                         return Err(SwInstallError::NoCurrentFound)?
@@ -262,4 +394,328 @@ impl SwinstallCurrent for One {
         Err(SwInstallError::NoCurrentFound)?
     }
 
+    fn current_from_end(&self, file: &mut File, start: u64, datetime: &NaiveDateTime)
+        -> Result<Option<String>, SwInstallError>
+    {
+        let written: DateTime<Local> = file.metadata()?.modified()?.into();
+        // an entry written after the lookup could sit anywhere in the stack
+        if *datetime < written.naive_local() {
+            return Ok(None);
+        }
+        let len = file.metadata()?.len();
+        read_from_end(file, start, len, datetime)
+    }
+
+    fn current_in_stack(&self, stack: &StackDocument, datetime: &NaiveDateTime, channel: Option<&str>)
+        -> Result<(String, usize), SwInstallError>
+    {
+        if let Some(channel) = channel {
+            return Err(SwInstallError::UnsupportedOperation(format!("channel {} for schema 1", channel)));
+        }
+        // as in current_at, we walk the stack until we reach an elt marked current, or
+        // one installed after the datetime, and take the last elt within the datetime
+        let mut last_elt = None;
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            let in_datetime = NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)? <= *datetime;
+            let current = elt.is_current;
+            if in_datetime {
+                last_elt = Some(elt);
+            }
+            if current || !in_datetime {
+                return match last_elt {
+                    Some(ref elt) if elt.pruned.is_some() => Err(SwInstallError::VersionPruned(elt.full_version())),
+                    Some(elt) => Ok((elt.full_version(), idx)),
+                    None => Err(SwInstallError::NoCurrentFound),
+                };
+            }
+        }
+        Err(SwInstallError::NoCurrentFound)
+    }
+
+    fn current_in_stack_at(&self, stack: &StackDocument, datetimes: &[NaiveDateTime], channel: Option<&str>)
+        -> Result<Vec<Result<(String, usize), SwInstallError>>, SwInstallError>
+    {
+        if let Some(channel) = channel {
+            return Err(SwInstallError::UnsupportedOperation(format!("channel {} for schema 1", channel)));
+        }
+        // the elts, parsed once for every datetime
+        let mut elts = Vec::new();
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            elts.push((idx, NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)?, elt));
+        }
+        Ok(datetimes.iter()
+            .map(|datetime| {
+                // as in current_in_stack
+                let mut last_elt = None;
+                for (idx, installed, elt) in &elts {
+                    let in_datetime = installed <= datetime;
+                    if in_datetime {
+                        last_elt = Some(elt);
+                    }
+                    if elt.is_current || !in_datetime {
+                        return match last_elt {
+                            Some(elt) if elt.pruned.is_some() => Err(SwInstallError::VersionPruned(elt.full_version())),
+                            Some(elt) => Ok((elt.full_version(), *idx)),
+                            None => Err(SwInstallError::NoCurrentFound),
+                        };
+                    }
+                }
+                Err(SwInstallError::NoCurrentFound)
+            })
+            .collect())
+    }
+
+    fn validate(&self, element: &StackElement) -> Result<(), SwInstallError> {
+        match element.name.as_str() {
+            "elt" => Elt::from_element(element).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    fn reconstruct(&self, stack: &mut StackDocument, files: &[VersionedFile], current: Option<&str>, _current_since: &NaiveDateTime)
+        -> Result<Vec<String>, SwInstallError>
+    {
+        let mut guesses = Vec::new();
+        // schema 1 versions are the install datetime, and an optional revision
+        let mut versions: Vec<&str> = Vec::new();
+        for file in files {
+            let datetime = Elt::new(false, file.version.clone()).version;
+            match NaiveDateTime::parse_from_str(datetime.as_str(), DATETIME_FMT) {
+                Ok(_) => versions.push(file.version.as_str()),
+                Err(_) => guesses.push(format!("ignored {}: not a schema 1 version", file.version)),
+            }
+        }
+        versions.sort();
+        let current = match current {
+            Some(current) if versions.contains(&current) => current,
+            _ => {
+                if let Some(current) = current {
+                    guesses.push(format!("ignored current version {}; took the most recent as current", current));
+                }
+                match versions.last() {
+                    Some(latest) => latest,
+                    None => return Ok(guesses),
+                }
+            },
+        };
+        // new versions are appended to the end of the stack
+        for version in versions {
+            stack.elements.push(
+                StackElement::new("elt")
+                    .with_attr("is_current", if version == current { "True" } else { "False" })
+                    .with_attr("version", version)
+            );
+        }
+        Ok(guesses)
+    }
+
+    fn versions(&self, stack: &StackDocument, datetime: &NaiveDateTime) -> Result<Vec<VersionRecord>, SwInstallError> {
+        let current = match self.current_in_stack(stack, datetime, None) {
+            Ok((version, _)) => Some(version),
+            Err(SwInstallError::NoCurrentFound) | Err(SwInstallError::VersionPruned(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let mut records = Vec::new();
+        for element in stack.elts() {
+            let elt = Elt::from_element(element)?;
+            let installed = NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)?;
+            let version = elt.full_version();
+            records.push(VersionRecord {
+                // schema 1 keeps no history, but every install was current when made
+                ever_current: installed <= *datetime,
+                in_use: current.as_ref() == Some(&version),
+                pruned: elt.pruned.is_some(),
+                version,
+                installed,
+            });
+        }
+        records.sort_by_key(|r| Reverse(r.installed));
+        Ok(records)
+    }
+
+    fn transitions(&self, stack: &StackDocument) -> Result<Vec<NaiveDateTime>, SwInstallError> {
+        // the current version changes only as versions are installed
+        stack.elts()
+            .map(|element| {
+                let elt = Elt::from_element(element)?;
+                Ok(NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)?)
+            })
+            .collect()
+    }
+
+    fn archivable(&self, stack: &StackDocument, before: &NaiveDateTime) -> Result<Vec<usize>, SwInstallError> {
+        // lookups walk the stack from the top, so the entries above the last one installed by
+        // `before` are only needed by lookups before it, so long as none of them is current
+        let mut keep = None;
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            if NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT)? > *before {
+                break;
+            }
+            keep = Some(idx);
+            if elt.is_current {
+                break;
+            }
+        }
+        Ok(match keep {
+            Some(keep) => stack.elements[..keep].iter().enumerate()
+                                                 .filter(|(_, e)| e.name == "elt")
+                                                 .map(|(idx, _)| idx)
+                                                 .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn merge_archived(&self, stack: &mut StackDocument, archived: Vec<StackElement>) -> Result<(), SwInstallError> {
+        // entries are archived from the top of the stack, so they go back above the rest. A
+        // version appears once in the stack, so an archived entry for a version the stack holds
+        // was restored by a rollback, which may since have moved is_current
+        let restored: Vec<String> = stack.elts().filter_map(|e| e.get("version")).map(|v| v.to_string()).collect();
+        let archived: Vec<StackElement> = archived.into_iter()
+                                                  .filter(|e| e.name != "elt" || !e.get("version").is_some_and(|v| restored.iter().any(|r| r == v)))
+                                                  .collect();
+        let idx = stack.elements.iter().position(|e| e.name == "elt").unwrap_or(stack.elements.len());
+        stack.elements.splice(idx..idx, archived);
+        Ok(())
+    }
+
+    fn install(&self, stack: &mut StackDocument, _hash: &str, datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<String, SwInstallError>
+    {
+        unsupported_options(options)?;
+        // schema 1 versions are the install datetime
+        let version = datetime.format(DATETIME_FMT).to_string();
+        if stack.elts().any(|e| e.get("version") == Some(version.as_str())) {
+            return Err(SwInstallError::RuntimeError(format!("version {} already in swinstall_stack", version)));
+        }
+
+        for elt in stack.elts_mut() {
+            elt.set("is_current", "False");
+        }
+        // new versions are appended to the end of the stack
+        stack.elements.push(
+            StackElement::new("elt")
+                .with_attr("is_current", "True")
+                .with_attr("version", version.as_str())
+        );
+        Ok(version)
+    }
+
+    fn rollback(&self, stack: &mut StackDocument, version: &str, _datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<(), SwInstallError>
+    {
+        unsupported_options(options)?;
+        if !stack.elts().any(|e| e.get("version") == Some(version)) {
+            return Err(SwInstallError::VersionNotFound(version.to_string()));
+        }
+        // schema 1 has no record of when a rollback happens. we simply move is_current
+        for elt in stack.elts_mut() {
+            let is_current = if elt.get("version") == Some(version) { "True" } else { "False" };
+            elt.set("is_current", is_current);
+        }
+        Ok(())
+    }
+}
+
+// size of the blocks read from the end of a stack
+const BLOCK_SIZE: u64 = 8192;
+
+// Resolve the version current at `datetime` by reading the elt tags of the stack backwards,
+// from the end of the file to `start`, where the first elt tag may begin. Returns None when
+// the forward scan must decide instead.
+fn read_from_end(file: &mut File, start: u64, len: u64, datetime: &NaiveDateTime) -> Result<Option<String>, SwInstallError> {
+    // the stack from `read` to the end of the file, of which everything from `unvisited` on
+    // has been visited
+    let mut tail: Vec<u8> = Vec::new();
+    let mut read = len;
+    let mut unvisited = 0;
+    let mut current = None;
+    while read > start {
+        let size = BLOCK_SIZE.min(read - start);
+        read -= size;
+        let mut block = vec![0; size as usize];
+        file.seek(SeekFrom::Start(read))?;
+        file.read_exact(&mut block)?;
+        // a comment may hide entries from the forward scan. look a little beyond the block
+        // in case the end of the comment straddles two blocks
+        let overlap = tail.len().min(2);
+        if block.iter().chain(&tail[..overlap]).collect::<Vec<_>>().windows(3).any(|w| w == [&b'-', &b'-', &b'>']) {
+            return Ok(None);
+        }
+        unvisited += block.len();
+        block.extend_from_slice(&tail);
+        tail = block;
+
+        while let Some(idx) = find_elt(&tail[..unvisited]) {
+            unvisited = idx;
+            let elt = match parse_elt(&tail[idx..]) {
+                Some(elt) => elt,
+                None => return Ok(None),
+            };
+            let version = match NaiveDateTime::parse_from_str(elt.version.as_str(), DATETIME_FMT) {
+                Ok(version) => version,
+                Err(_) => return Ok(None),
+            };
+            if version > *datetime {
+                return Ok(None);
+            }
+            if elt.is_current {
+                // the forward scan stops at the first entry marked current, so a second one
+                // above the last leaves the answer to it
+                if current.is_some() {
+                    return Ok(None);
+                }
+                current = Some(elt);
+            }
+        }
+    }
+    // the only entry marked current is the one current at the time. If it has been pruned,
+    // the forward scan reports it
+    Ok(match current {
+        Some(ref elt) if elt.pruned.is_none() => Some(elt.full_version()),
+        _ => None,
+    })
+}
+
+// the offset of the last elt tag to start within `bytes`
+fn find_elt(bytes: &[u8]) -> Option<usize> {
+    let mut end = bytes.len();
+    while let Some(idx) = bytes[..end].windows(4).rposition(|w| w == b"<elt") {
+        match bytes.get(idx + 4) {
+            Some(c) if c.is_ascii_whitespace() || *c == b'/' => return Some(idx),
+            _ => end = idx,
+        }
+    }
+    None
+}
+
+// parse the empty elt tag at the start of `bytes`
+fn parse_elt(bytes: &[u8]) -> Option<Elt> {
+    let end = bytes.iter().position(|c| *c == b'>')?;
+    let mut reader = Reader::from_reader(&bytes[..=end]);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Empty(ref e)) => return Elt::from_attrs(e.attributes()).ok(),
+            // the reader reports the (empty) text before the tag first
+            Ok(Event::Text(_)) => buf.clear(),
+            _ => return None,
+        }
+    }
+}
+
+// schema 1 elt tags have nowhere to record the optional metadata
+fn unsupported_options(options: &EltOptions) -> Result<(), SwInstallError> {
+    if options.changeset.is_some() {
+        return Err(SwInstallError::UnsupportedOperation("changesets require schema 2".to_string()));
+    }
+    if options.channel.is_some() {
+        return Err(SwInstallError::UnsupportedOperation("release channels require schema 2".to_string()));
+    }
+    if options.expires.is_some() {
+        return Err(SwInstallError::UnsupportedOperation("expiring installs require schema 2".to_string()));
+    }
+    Ok(())
 }
\ No newline at end of file
@@ -9,6 +9,41 @@
 //!   need to reinstall files for rollback / rollforward
 //! - store file hash to help identify post-install mutations
 //!
+//! Versions may also be *staged*: installed into bak and recorded in the stack with
+//! `action="stage"`, without becoming current. Lookups skip staged elts until the version
+//! is promoted, which records a new elt with `action="promote"` at the top of the stack.
+//!
+//! An install may carry an `expires` attribute, after which lookups pass over it and fall
+//! back to the entry beneath it, much as though it had been rolled back at that time:
+//!
+//! ```xml
+//! <elt action="install" datetime="20181221-142313" expires="20181222-090000" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+//! ```
+//!
+//! Nothing in the stack changes when an install expires, so the versionless file still holds
+//! the expired version until `transaction::sync` rewrites it.
+//!
+//! A stack may carry several independent current pointers, or release channels, such as
+//! `dev` and `prod`. Elts recorded for a channel carry a `channel` attribute and are only
+//! considered when resolving that channel; elts without one make up the default channel:
+//!
+//! ```xml
+//! <elt action="install" channel="dev" datetime="20181222-103000" hash="0f343b0931126a20f133d67c2b018a3b" version="6"/>
+//! ```
+//!
+//! Versions may be labelled by tag elements, which follow the elts. Moving a tag records a
+//! new tag element rather than updating the old one, so the history of each tag is kept; a
+//! tag element without a version removes the tag:
+//!
+//! ```xml
+//! <tag datetime="20181222-103000" name="known-good" version="5"/>
+//! <tag datetime="20181221-090000" name="known-good" version="4"/>
+//! ```
+//!
+//! Elts are kept in descending datetime order. An install may be scheduled by recording it
+//! with a datetime in the future; it sits at the top of the stack, and lookups pass over it
+//! until that datetime is reached.
+//!
 //! # Details
 //!
 //! The original swinstall_stack design (schema 1) has a number of flaws:
@@ -35,15 +70,20 @@
 //! </stack_history>
 //! ```
 
-use chrono::{NaiveDateTime};
+use chrono::{Duration, NaiveDateTime};
 use crate::{
     constants::DATETIME_FMT,
     errors::SwInstallError,
-    traits::SwinstallCurrent,
+    stack::{StackDocument, StackElement},
+    traits::{
+        tagged_version, ChangesetEntry, EltOptions, PendingEntry, SwinstallCurrent, TagEntry, TimelineEvent,
+        VersionRecord, VersionedFile,
+    },
 };
 #[allow(unused_imports)]
 use log::{ debug, info, warn };
 use std::{
+    cmp::Reverse,
     fs::File,
     io::BufReader,
     str::from_utf8,
@@ -52,19 +92,36 @@ use quick_xml::{
     Reader,
     events::{ attributes::Attributes, Event, },
 };
+use serde::{Deserialize, Serialize};
+
+// action recorded for staged versions
+const STAGE: &str = "stage";
+// action reported in the timeline when an entry expires
+const EXPIRE: &str = "expire";
+// name of the tags which label versions
+const TAG: &str = "tag";
 
-#[derive(Debug, PartialEq, Eq)]
-struct Elt {
+/// Model the schema 2 elt tag contents
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Elt {
     pub action: String,
     pub datetime: String,
     pub hash: String,
-    pub version: String
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changeset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pruned: Option<String>,
 }
 
 impl Elt {
     pub fn new(action: String, datetime:String, hash: String, version: String) -> Self {
         Elt {
-            action, datetime, hash, version
+            action, datetime, hash, version, changeset: None, expires: None, channel: None, pruned: None,
         }
     }
 
@@ -73,6 +130,10 @@ impl Elt {
         let mut datetime = None;
         let mut hash = None;
         let mut version = None;
+        let mut changeset = None;
+        let mut expires = None;
+        let mut channel = None;
+        let mut pruned = None;
 
         for attr in attrs {
             let attr = attr?;
@@ -81,6 +142,10 @@ impl Elt {
                 b"datetime" => datetime = Some(attr.value),
                 b"hash"     => hash = Some(attr.value),
                 b"version"  => version = Some(attr.value),
+                b"changeset" => changeset = Some(from_utf8(&attr.value)?.to_string()),
+                b"expires"  => expires = Some(from_utf8(&attr.value)?.to_string()),
+                b"channel"  => channel = Some(from_utf8(&attr.value)?.to_string()),
+                b"pruned"   => pruned = Some(from_utf8(&attr.value)?.to_string()),
                 _ => {},
             }
         }
@@ -91,15 +156,34 @@ impl Elt {
         //    convert to a vec<u8> ( into_owned())
         //    convert to a str (from_utf8)
         //    convert to a String (to_string)
-        let elt = Elt::new(
-            from_utf8(&action.ok_or(SwInstallError::MissingEltAttribute)?.into_owned())?.to_string(),
-            from_utf8(&datetime.ok_or(SwInstallError::MissingEltAttribute)?.into_owned())?.to_string(),
-            from_utf8(&hash.ok_or(SwInstallError::MissingEltAttribute)?.into_owned())?.to_string(),
-            from_utf8(&version.ok_or(SwInstallError::MissingEltAttribute)?.into_owned())?.to_string(),
+        let mut elt = Elt::new(
+            from_utf8(&action.ok_or_else(|| SwInstallError::MissingEltAttribute("action".to_string()))?.into_owned())?.to_string(),
+            from_utf8(&datetime.ok_or_else(|| SwInstallError::MissingEltAttribute("datetime".to_string()))?.into_owned())?.to_string(),
+            from_utf8(&hash.ok_or_else(|| SwInstallError::MissingEltAttribute("hash".to_string()))?.into_owned())?.to_string(),
+            from_utf8(&version.ok_or_else(|| SwInstallError::MissingEltAttribute("version".to_string()))?.into_owned())?.to_string(),
         );
+        elt.changeset = changeset;
+        elt.expires = expires;
+        elt.channel = channel;
+        elt.pruned = pruned;
         debug!("elt: {:?}", elt);
         Ok(elt)
     }
+
+    /// New up an Elt from an elt element of a stack document.
+    pub fn from_element(element: &StackElement) -> Result<Elt, SwInstallError> {
+        let mut elt = Elt::new(
+            element.require("action")?.to_string(),
+            element.require("datetime")?.to_string(),
+            element.require("hash")?.to_string(),
+            element.require("version")?.to_string(),
+        );
+        elt.changeset = element.get("changeset").map(|v| v.to_string());
+        elt.expires = element.get("expires").map(|v| v.to_string());
+        elt.channel = element.get("channel").map(|v| v.to_string());
+        elt.pruned = element.get("pruned").map(|v| v.to_string());
+        Ok(elt)
+    }
 }
 
 #[cfg(test)]
@@ -119,6 +203,10 @@ mod tests {
                                 datetime: "20180702-144204".to_string(),
                                 hash: String::from("194f835569a79ba433"),
                                 version: "3".to_string(),
+                                changeset: None,
+                                expires: None,
+                                channel: None,
+                                pruned: None,
                             };
 
                             assert_eq!(elt, expected);
@@ -128,6 +216,200 @@ mod tests {
             }
         }
     }
+
+    const STACK: &str = r#"<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+   <elt action="install" datetime="20180702-144204" hash="194f835569a79ba433" version="3"/>
+   <elt action="install" datetime="20180101-103813" hash="c94f6266789a483a43" version="2"/>
+</stack_history>"#;
+
+    #[test]
+    fn install_pushes_next_version() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let version = Two::new().install(&mut stack, "abc", &dt, &EltOptions::default()).expect("unable to install");
+        assert_eq!(version, "4");
+        let head = stack.elts().next().unwrap();
+        assert_eq!(head.get("action"), Some("install"));
+        assert_eq!(head.get("datetime"), Some("20181221-102242"));
+        assert_eq!(head.get("hash"), Some("abc"));
+    }
+
+    #[test]
+    fn elt_from_attrs_with_changeset() {
+       let str_from = r#"<elt action="install" changeset="cs1" datetime="20180702-144204" hash="194f835569a79ba433" version="3"/>"#;
+       let mut reader = Reader::from_str(str_from);
+       let mut buf = Vec::new();
+       loop {
+            match reader.read_event(&mut buf) {
+                        Ok(Event::Empty(ref e)) => {
+                            let elt = Elt::from_attrs(e.attributes()).expect("could not create elt");
+                            assert_eq!(elt.changeset, Some("cs1".to_string()));
+                            break;
+                        }
+                        _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn changesets_listed() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let options = EltOptions::default().changeset("cs1");
+        Two::new().install(&mut stack, "abc", &dt, &options).expect("unable to install");
+        let head = stack.elts().next().unwrap();
+        let keys: Vec<&str> = head.attributes.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["action", "changeset", "datetime", "hash", "version"]);
+
+        let changesets = Two::new().changesets(&stack).unwrap();
+        assert_eq!(changesets.len(), 1);
+        assert_eq!(changesets[0].changeset, "cs1");
+        assert_eq!(changesets[0].version, "4");
+        assert_eq!(changesets[0].datetime, dt);
+    }
+
+    #[test]
+    fn stage_and_promote() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let two = Two::new();
+        let version = two.stage(&mut stack, "abc", &dt, &EltOptions::default()).expect("unable to stage");
+        assert_eq!(version, "4");
+        assert_eq!(two.staged(&stack, None).unwrap(), vec!["4".to_string()]);
+        assert!(two.promote(&mut stack, "3", &dt, &EltOptions::default()).is_err());
+        // rolling back to a staged version would bypass the promotion
+        match two.rollback(&mut stack, "4", &dt, &EltOptions::default()) {
+            Err(SwInstallError::UnsupportedOperation(_)) => {},
+            other => panic!("expected rollback to a staged version to fail, got {:?}", other),
+        }
+
+        two.promote(&mut stack, "4", &dt, &EltOptions::default()).expect("unable to promote");
+        let head = stack.elts().next().unwrap();
+        assert_eq!(head.get("action"), Some("promote"));
+        assert_eq!(head.get("hash"), Some("abc"));
+        assert!(two.staged(&stack, None).unwrap().is_empty());
+
+        // staging and promotion are per channel
+        let dev = EltOptions::default().channel("dev");
+        let version = two.stage(&mut stack, "def", &dt, &dev).unwrap();
+        assert!(two.staged(&stack, None).unwrap().is_empty());
+        assert_eq!(two.staged(&stack, Some("dev")).unwrap(), vec![version.clone()]);
+        assert!(two.promote(&mut stack, version.as_str(), &dt, &EltOptions::default()).is_err());
+        two.promote(&mut stack, version.as_str(), &dt, &dev).expect("unable to promote in channel");
+        assert!(two.staged(&stack, Some("dev")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rollback_pushes_rollback_elt() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        Two::new().rollback(&mut stack, "2", &dt, &EltOptions::default()).expect("unable to rollback");
+        let head = stack.elts().next().unwrap();
+        assert_eq!(head.get("action"), Some("rollback"));
+        assert_eq!(head.get("hash"), Some("c94f6266789a483a43"));
+        assert_eq!(head.get("version"), Some("2"));
+        assert_eq!(stack.elts().count(), 3);
+        assert!(Two::new().rollback(&mut stack, "7", &dt, &EltOptions::default()).is_err());
+    }
+
+    #[test]
+    fn expired_install_in_timeline() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let two = Two::new();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let expires = NaiveDateTime::parse_from_str("20181222-090000", DATETIME_FMT).unwrap();
+        let options = EltOptions::default().expires(&expires);
+        assert!(two.install(&mut stack, "abc", &expires, &options).is_err());
+        two.install(&mut stack, "abc", &dt, &options).expect("unable to install");
+
+        let head = stack.elts().next().unwrap();
+        assert_eq!(head.get("expires"), Some("20181222-090000"));
+        let timeline = two.timeline(&stack).unwrap();
+        let events: Vec<(&str, &str)> = timeline.iter().map(|e| (e.action.as_str(), e.version.as_str())).collect();
+        assert_eq!(events, vec![("expire", "4"), ("install", "4"), ("install", "3"), ("install", "2")]);
+        assert_eq!(timeline[0].datetime, expires);
+        assert_eq!(timeline[1].expires, Some(expires));
+    }
+
+    #[test]
+    fn tags_move_and_resolve() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let two = Two::new();
+        let first = NaiveDateTime::parse_from_str("20181221-090000", DATETIME_FMT).unwrap();
+        let second = NaiveDateTime::parse_from_str("20181222-103000", DATETIME_FMT).unwrap();
+        let third = NaiveDateTime::parse_from_str("20181223-103000", DATETIME_FMT).unwrap();
+        two.tag(&mut stack, "known-good", Some("2"), &first).expect("unable to tag");
+        two.tag(&mut stack, "known-good", Some("3"), &second).expect("unable to move tag");
+        assert!(two.tag(&mut stack, "known-good", Some("9"), &second).is_err());
+
+        // tags follow the elts, and an install still lands among the elts
+        two.install(&mut stack, "abc", &first, &EltOptions::default()).unwrap();
+        let names: Vec<&str> = stack.elements.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["elt", "elt", "elt", "tag", "tag"]);
+
+        let tags = two.tags(&stack).unwrap();
+        assert_eq!(tagged_version(&tags, "known-good", &second), Some("3"));
+        assert_eq!(tagged_version(&tags, "known-good", &first), Some("2"));
+        assert_eq!(tagged_version(&tags, "approved", &second), None);
+
+        two.tag(&mut stack, "known-good", None, &third).expect("unable to remove tag");
+        let tags = two.tags(&stack).unwrap();
+        assert_eq!(tagged_version(&tags, "known-good", &third), None);
+        assert_eq!(tagged_version(&tags, "known-good", &second), Some("3"));
+    }
+
+    #[test]
+    fn channels_resolve_independently() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("packages.xml_swinstall_stack");
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let two = Two::new();
+        let dt = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        let later = NaiveDateTime::parse_from_str("20181222-102242", DATETIME_FMT).unwrap();
+        let dev = EltOptions::default().channel("dev");
+        two.install(&mut stack, "abc", &dt, &dev).expect("unable to install");
+        two.rollback(&mut stack, "2", &later, &dev).expect("unable to rollback");
+        two.install(&mut stack, "def", &dt, &EltOptions::default()).expect("unable to install");
+        stack.write(path.to_str().unwrap()).unwrap();
+
+        let lookup = |channel: Option<&str>, datetime: &NaiveDateTime| {
+            let mut reader = Reader::from_file(&path).unwrap();
+            two.current_in(&mut reader, datetime, channel).unwrap()
+        };
+        assert_eq!(lookup(None, &later), "5");
+        assert_eq!(lookup(Some("dev"), &dt), "4");
+        assert_eq!(lookup(Some("dev"), &later), "2");
+        assert_eq!(lookup(None, &NaiveDateTime::parse_from_str("20180801-000000", DATETIME_FMT).unwrap()), "3");
+    }
+
+    #[test]
+    fn elt_serde() {
+        let elt = Elt::new("install".to_string(), "20180702-144204".to_string(), "194f835569a79ba433".to_string(), "3".to_string());
+        let json = serde_json::to_string(&elt).unwrap();
+        assert_eq!(json, r#"{"action":"install","datetime":"20180702-144204","hash":"194f835569a79ba433","version":"3"}"#);
+        assert_eq!(serde_json::from_str::<Elt>(&json).unwrap(), elt);
+    }
+
+    #[test]
+    fn scheduled_install_stays_on_top() {
+        let mut stack = StackDocument::from_xml(STACK).unwrap();
+        let two = Two::new();
+        let future = NaiveDateTime::parse_from_str("20190101-000000", DATETIME_FMT).unwrap();
+        let now = NaiveDateTime::parse_from_str("20181221-102242", DATETIME_FMT).unwrap();
+        two.install(&mut stack, "abc", &future, &EltOptions::default()).expect("unable to install");
+        two.rollback(&mut stack, "2", &now, &EltOptions::default()).expect("unable to rollback");
+
+        let order: Vec<(&str, &str)> = stack.elts()
+                                           .map(|e| (e.get("action").unwrap(), e.get("version").unwrap()))
+                                           .collect();
+        assert_eq!(order, vec![("install", "4"), ("rollback", "2"), ("install", "3"), ("install", "2")]);
+
+        let pending = two.pending(&stack, &now).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].version, "4");
+        assert_eq!(pending[0].datetime, future);
+        assert!(two.pending(&stack, &future).unwrap().is_empty());
+    }
 }
 
 /// Model the elt tag contents from swinstall_log
@@ -138,16 +420,10 @@ impl Two {
     pub fn new() -> Self {
         Two {}
     }
-}
-
-impl SwinstallCurrent for Two {
-    type SwBufReader = BufReader<File>;
-
-    fn schema(&self) -> &'static str {
-            "2"
-    }
 
-    fn current_at(&self, reader: &mut Reader<Self::SwBufReader>, datetime: &NaiveDateTime)
+    // find the version current in the supplied channel. None is the default channel,
+    // made up of the elts without a channel attribute
+    fn current_in(&self, reader: &mut Reader<BufReader<File>>, datetime: &NaiveDateTime, channel: Option<&str>)
         -> Result<String, SwInstallError>
     {
         let mut buf = Vec::new();
@@ -157,9 +433,8 @@ impl SwinstallCurrent for Two {
                     if e.name() == b"elt" {
                         debug!("Event::Empty - elt tag matched");
                         let elt = Elt::from_attrs(e.attributes())?;
-                        let dt = NaiveDateTime::parse_from_str(elt.datetime.as_str(), DATETIME_FMT)?;
-                        if dt <= *datetime {
-                            return Ok(elt.version.clone());
+                        if is_current(&elt, datetime, channel)? {
+                            return resolved(elt);
                         }
                     }
                 },
@@ -175,4 +450,489 @@ impl SwinstallCurrent for Two {
             buf.clear();
         }
     }
+}
+
+impl SwinstallCurrent for Two {
+    type SwBufReader = BufReader<File>;
+
+    fn schema(&self) -> &'static str {
+            "2"
+    }
+
+    fn current_at(&self, reader: &mut Reader<Self::SwBufReader>, datetime: &NaiveDateTime)
+        -> Result<String, SwInstallError>
+    {
+        self.current_in(reader, datetime, None)
+    }
+
+    fn current_in_channel(&self, reader: &mut Reader<Self::SwBufReader>, datetime: &NaiveDateTime, channel: &str)
+        -> Result<String, SwInstallError>
+    {
+        self.current_in(reader, datetime, Some(channel))
+    }
+
+    fn current_in_stack(&self, stack: &StackDocument, datetime: &NaiveDateTime, channel: Option<&str>)
+        -> Result<(String, usize), SwInstallError>
+    {
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            if is_current(&elt, datetime, channel)? {
+                return resolved(elt).map(|version| (version, idx));
+            }
+        }
+        Err(SwInstallError::NoCurrentFound)
+    }
+
+    fn current_in_stack_at(&self, stack: &StackDocument, datetimes: &[NaiveDateTime], channel: Option<&str>)
+        -> Result<Vec<Result<(String, usize), SwInstallError>>, SwInstallError>
+    {
+        // the elts which may be current in the channel, parsed once for every datetime
+        let mut candidates = Vec::new();
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            if elt.action == STAGE || elt.channel.as_deref() != channel {
+                continue;
+            }
+            let expires = match elt.expires {
+                Some(_) => Some(element.require_datetime("expires")?),
+                None => None,
+            };
+            candidates.push((idx, element.require_datetime("datetime")?, expires, elt));
+        }
+        Ok(datetimes.iter()
+            .map(|datetime| {
+                let current = candidates.iter()
+                    .find(|(_, dt, expires, _)| dt <= datetime && expires.is_none_or(|expires| expires > *datetime));
+                match current {
+                    Some((_, _, _, elt)) if elt.pruned.is_some() => Err(SwInstallError::VersionPruned(elt.version.clone())),
+                    Some((idx, _, _, elt)) => Ok((elt.version.clone(), *idx)),
+                    None => Err(SwInstallError::NoCurrentFound),
+                }
+            })
+            .collect())
+    }
+
+    fn validate(&self, element: &StackElement) -> Result<(), SwInstallError> {
+        match element.name.as_str() {
+            "elt" => {
+                let elt = Elt::from_element(element)?;
+                element.require_datetime("datetime")?;
+                if elt.expires.is_some() {
+                    element.require_datetime("expires")?;
+                }
+                Ok(())
+            },
+            TAG => {
+                element.require("name")?;
+                element.require_datetime("datetime").map(|_| ())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn reconstruct(&self, stack: &mut StackDocument, files: &[VersionedFile], current: Option<&str>, current_since: &NaiveDateTime)
+        -> Result<Vec<String>, SwInstallError>
+    {
+        let mut guesses = Vec::new();
+        let mut versions = Vec::new();
+        for file in files {
+            match file.version.parse::<u32>() {
+                Ok(number) => versions.push((number, file)),
+                Err(_) => guesses.push(format!("ignored {}: not a schema 2 version", file.version)),
+            }
+        }
+        if versions.is_empty() {
+            return Ok(guesses);
+        }
+        // schema 2 versions do not record when they were installed
+        guesses.push("install times taken from the modification times of the versioned files".to_string());
+        versions.sort_by_key(|(number, _)| *number);
+        for (_, file) in &versions {
+            insert_elt(stack, new_elt("install", &file.modified, &file.hash, &file.version, &EltOptions::default()));
+        }
+
+        // the most recent install is current, unless we have been told otherwise
+        let latest = stack.elts().next().ok_or(SwInstallError::NoCurrentFound)?;
+        let latest_version = latest.require("version")?.to_string();
+        let latest_datetime = latest.require_datetime("datetime")?;
+        let current = current.filter(|c| *c != latest_version)
+            .and_then(|c| {
+                let file = versions.iter().map(|(_, f)| f).find(|f| f.version == c);
+                if file.is_none() {
+                    guesses.push(format!("ignored current version {}; took the most recent as current", c));
+                }
+                file
+            });
+        if let Some(file) = current {
+            let current = file.version.as_str();
+            let datetime = if *current_since > latest_datetime {
+                *current_since
+            } else {
+                let datetime = latest_datetime + Duration::seconds(1);
+                guesses.push(format!(
+                    "rollback to version {} dated {}, just after the latest install", current, datetime.format(DATETIME_FMT)
+                ));
+                datetime
+            };
+            insert_elt(stack, new_elt("rollback", &datetime, &file.hash, current, &EltOptions::default()));
+        }
+        Ok(guesses)
+    }
+
+    fn install(&self, stack: &mut StackDocument, hash: &str, datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<String, SwInstallError>
+    {
+        if let Some(ref expires) = options.expires {
+            if expires <= datetime {
+                return Err(SwInstallError::RuntimeError(format!(
+                    "expiry {} precedes install at {}", expires.format(DATETIME_FMT), datetime.format(DATETIME_FMT)
+                )));
+            }
+        }
+        let version = next_version(stack);
+        insert_elt(stack, new_elt("install", datetime, hash, version.as_str(), options));
+        Ok(version)
+    }
+
+    fn rollback(&self, stack: &mut StackDocument, version: &str, datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<(), SwInstallError>
+    {
+        // staged versions only become current by being promoted, in whichever channel they
+        // were staged
+        if unpromoted(stack)?.iter().any(|(_, v)| v == version) {
+            return Err(SwInstallError::UnsupportedOperation(format!("rollback to staged version {}; promote it instead", version)));
+        }
+        let hash = stack.elts()
+                        .find(|e| e.get("version") == Some(version))
+                        .ok_or_else(|| SwInstallError::VersionNotFound(version.to_string()))?
+                        .require("hash")?
+                        .to_string();
+        insert_elt(stack, new_elt("rollback", datetime, hash.as_str(), version, options));
+        Ok(())
+    }
+
+    fn stage(&self, stack: &mut StackDocument, hash: &str, datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<String, SwInstallError>
+    {
+        let version = next_version(stack);
+        insert_elt(stack, new_elt(STAGE, datetime, hash, version.as_str(), options));
+        Ok(version)
+    }
+
+    fn promote(&self, stack: &mut StackDocument, version: &str, datetime: &NaiveDateTime, options: &EltOptions)
+        -> Result<(), SwInstallError>
+    {
+        if !self.staged(stack, options.channel.as_deref())?.iter().any(|v| v == version) {
+            return Err(SwInstallError::VersionNotFound(format!("{} is not staged", version)));
+        }
+        let hash = stack.elts()
+                        .find(|e| e.get("version") == Some(version))
+                        .ok_or_else(|| SwInstallError::VersionNotFound(version.to_string()))?
+                        .require("hash")?
+                        .to_string();
+        insert_elt(stack, new_elt("promote", datetime, hash.as_str(), version, options));
+        Ok(())
+    }
+
+    fn tag(&self, stack: &mut StackDocument, name: &str, version: Option<&str>, datetime: &NaiveDateTime)
+        -> Result<(), SwInstallError>
+    {
+        let mut tag = StackElement::new(TAG)
+            .with_attr("datetime", datetime.format(DATETIME_FMT).to_string().as_str())
+            .with_attr("name", name);
+        match version {
+            Some(version) => {
+                if !stack.elts().any(|e| e.get("version") == Some(version)) {
+                    return Err(SwInstallError::VersionNotFound(version.to_string()));
+                }
+                tag.set("version", version);
+            }
+            None => {
+                if tagged_version(&self.tags(stack)?, name, datetime).is_none() {
+                    return Err(SwInstallError::TagNotFound(name.to_string()));
+                }
+            }
+        }
+
+        // tags follow the elts, most recent first
+        let idx = stack.elements.iter()
+                       .position(|e| e.name == TAG && e.get("datetime").unwrap_or("") <= tag.get("datetime").unwrap_or(""))
+                       .or_else(|| stack.elements.iter().rposition(|e| e.name == TAG).map(|i| i + 1))
+                       .unwrap_or(stack.elements.len());
+        stack.elements.insert(idx, tag);
+        Ok(())
+    }
+
+    fn tags(&self, stack: &StackDocument) -> Result<Vec<TagEntry>, SwInstallError> {
+        let mut tags = Vec::new();
+        for tag in stack.elements.iter().filter(|e| e.name == TAG) {
+            tags.push(TagEntry {
+                name: tag.require("name")?.to_string(),
+                datetime: tag.require_datetime("datetime")?,
+                version: tag.get("version").map(|v| v.to_string()),
+            });
+        }
+        Ok(tags)
+    }
+
+    fn staged(&self, stack: &StackDocument, channel: Option<&str>) -> Result<Vec<String>, SwInstallError> {
+        Ok(unpromoted(stack)?.into_iter()
+                             .filter(|(c, _)| c.as_deref() == channel)
+                             .map(|(_, version)| version)
+                             .collect())
+    }
+
+    fn pending(&self, stack: &StackDocument, datetime: &NaiveDateTime) -> Result<Vec<PendingEntry>, SwInstallError> {
+        let mut pending = Vec::new();
+        for elt in stack.elts() {
+            let dt = elt.require_datetime("datetime")?;
+            // the stack is ordered by datetime, so the first entry in effect ends the search
+            if dt <= *datetime {
+                break;
+            }
+            let action = elt.require("action")?;
+            if action != STAGE {
+                pending.push(PendingEntry {
+                    action: action.to_string(),
+                    datetime: dt,
+                    version: elt.require("version")?.to_string(),
+                });
+            }
+        }
+        Ok(pending)
+    }
+
+    fn timeline(&self, stack: &StackDocument) -> Result<Vec<TimelineEvent>, SwInstallError> {
+        let mut events = Vec::new();
+        for elt in stack.elts() {
+            let expires = match elt.get("expires") {
+                Some(_) => Some(elt.require_datetime("expires")?),
+                None => None,
+            };
+            let event = TimelineEvent {
+                action: elt.require("action")?.to_string(),
+                datetime: elt.require_datetime("datetime")?,
+                version: elt.require("version")?.to_string(),
+                changeset: elt.get("changeset").map(|c| c.to_string()),
+                channel: elt.get("channel").map(|c| c.to_string()),
+                expires,
+            };
+            if let Some(expires) = expires {
+                events.push(TimelineEvent {
+                    action: EXPIRE.to_string(),
+                    datetime: expires,
+                    expires: None,
+                    ..event.clone()
+                });
+            }
+            events.push(event);
+        }
+        // expiry events may fall anywhere in the history. the sort is stable, so entries
+        // sharing a datetime keep their stack order
+        events.sort_by_key(|e| Reverse(e.datetime));
+        Ok(events)
+    }
+
+    fn changesets(&self, stack: &StackDocument) -> Result<Vec<ChangesetEntry>, SwInstallError> {
+        let mut entries = Vec::new();
+        for elt in stack.elts() {
+            if let Some(changeset) = elt.get("changeset") {
+                entries.push(ChangesetEntry {
+                    changeset: changeset.to_string(),
+                    action: elt.require("action")?.to_string(),
+                    datetime: elt.require_datetime("datetime")?,
+                    version: elt.require("version")?.to_string(),
+                    channel: elt.get("channel").map(|c| c.to_string()),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn versions(&self, stack: &StackDocument, datetime: &NaiveDateTime) -> Result<Vec<VersionRecord>, SwInstallError> {
+        let mut records: Vec<VersionRecord> = Vec::new();
+        for element in stack.elts() {
+            let elt = Elt::from_element(element)?;
+            let dt = element.require_datetime("datetime")?;
+            let ever_current = elt.action != STAGE && dt <= *datetime;
+            match records.iter_mut().find(|r| r.version == elt.version) {
+                Some(record) => {
+                    record.installed = record.installed.min(dt);
+                    record.ever_current |= ever_current;
+                    record.pruned |= elt.pruned.is_some();
+                },
+                None => records.push(VersionRecord {
+                    version: elt.version.clone(),
+                    installed: dt,
+                    ever_current,
+                    in_use: false,
+                    pruned: elt.pruned.is_some(),
+                }),
+            }
+        }
+
+        // versions current in any channel, staged or scheduled are in use
+        let mut in_use: Vec<String> = unpromoted(stack)?.into_iter().map(|(_, version)| version).collect();
+        in_use.extend(self.pending(stack, datetime)?.into_iter().map(|p| p.version));
+        let mut channels = vec![None];
+        for channel in stack.elts().filter_map(|e| e.get("channel")) {
+            if !channels.contains(&Some(channel)) {
+                channels.push(Some(channel));
+            }
+        }
+        for channel in channels {
+            match self.current_in_stack(stack, datetime, channel) {
+                Ok((version, _)) => in_use.push(version),
+                Err(SwInstallError::NoCurrentFound) | Err(SwInstallError::VersionPruned(_)) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        for record in records.iter_mut() {
+            record.in_use = in_use.contains(&record.version);
+        }
+        records.sort_by_key(|r| Reverse(r.installed));
+        Ok(records)
+    }
+
+    fn archivable(&self, stack: &StackDocument, before: &NaiveDateTime) -> Result<Vec<usize>, SwInstallError> {
+        // in each channel, the first entry in effect by `before` which can neither expire nor
+        // be staged ends every lookup from then on. The entries beneath it are no longer
+        // needed, unless they are staged.
+        let staged: Vec<String> = unpromoted(stack)?.into_iter().map(|(_, version)| version).collect();
+        let mut ended: Vec<Option<String>> = Vec::new();
+        let mut archivable = Vec::new();
+        for (idx, element) in stack.elements.iter().enumerate().filter(|(_, e)| e.name == "elt") {
+            let elt = Elt::from_element(element)?;
+            let dt = element.require_datetime("datetime")?;
+            if dt > *before {
+                continue;
+            }
+            if ended.contains(&elt.channel) {
+                if elt.action != STAGE || !staged.contains(&elt.version) {
+                    archivable.push(idx);
+                }
+            } else if elt.action != STAGE && elt.expires.is_none() {
+                ended.push(elt.channel);
+            }
+        }
+        Ok(archivable)
+    }
+
+    fn merge_archived(&self, stack: &mut StackDocument, archived: Vec<StackElement>) -> Result<(), SwInstallError> {
+        // archived entries go beneath any entry sharing their datetime, which kept its place.
+        // Entries restored by a rollback are left unchanged, so their archived copies are
+        // identical to them
+        let restored: Vec<StackElement> = stack.elts().cloned().collect();
+        for elt in archived {
+            if restored.contains(&elt) {
+                continue;
+            }
+            let datetime = elt.require("datetime")?.to_string();
+            let idx = stack.elements.iter()
+                           .position(|e| e.name == "elt" && e.get("datetime").unwrap_or("") < datetime.as_str())
+                           .unwrap_or(stack.elements.len());
+            stack.elements.insert(idx, elt);
+        }
+        Ok(())
+    }
+}
+
+// insert an elt tag into the stack, which is kept in descending datetime order. The new
+// elt goes above any existing elt with the same datetime. Entries scheduled for the future
+// therefore remain at the top of the stack until they take effect.
+fn insert_elt(stack: &mut StackDocument, elt: StackElement) {
+    let datetime = elt.get("datetime").unwrap_or("").to_string();
+    let mut idx = 0;
+    for (i, existing) in stack.elements.iter().enumerate() {
+        if existing.name != "elt" {
+            continue;
+        }
+        // DATETIME_FMT sorts lexically in chronological order
+        if existing.get("datetime").unwrap_or("") <= datetime.as_str() {
+            stack.elements.insert(i, elt);
+            return;
+        }
+        idx = i + 1;
+    }
+    stack.elements.insert(idx, elt);
+}
+
+// the versions staged but not yet promoted, with the channel each was staged in, most
+// recent first. A staged version is promoted by a later, and therefore higher, elt for the
+// same version in the same channel
+fn unpromoted(stack: &StackDocument) -> Result<Vec<(Option<String>, String)>, SwInstallError> {
+    let mut seen = Vec::new();
+    let mut staged = Vec::new();
+    for elt in stack.elts() {
+        let key = (elt.get("channel").map(|c| c.to_string()), elt.require("version")?.to_string());
+        if elt.require("action")? == STAGE {
+            if !seen.contains(&key) {
+                staged.push(key);
+            }
+        } else {
+            seen.push(key);
+        }
+    }
+    Ok(staged)
+}
+
+// is the elt the current one, given that no elt above it is
+fn is_current(elt: &Elt, datetime: &NaiveDateTime, channel: Option<&str>) -> Result<bool, SwInstallError> {
+    let dt = NaiveDateTime::parse_from_str(elt.datetime.as_str(), DATETIME_FMT)
+        .map_err(|_| SwInstallError::InvalidEltAttribute("datetime".to_string(), elt.datetime.clone()))?;
+    // staged versions never become current until promoted, elts in other channels are
+    // ignored, and expired entries give way to the entry beneath them
+    Ok(dt <= *datetime && elt.action != STAGE && elt.channel.as_deref() == channel && !expired(elt, datetime)?)
+}
+
+// the version of the current elt. A pruned elt is still current, but its versioned file is
+// gone, and the entry beneath it is no substitute
+fn resolved(elt: Elt) -> Result<String, SwInstallError> {
+    match elt.pruned {
+        Some(_) => Err(SwInstallError::VersionPruned(elt.version)),
+        None => Ok(elt.version),
+    }
+}
+
+// test whether the elt has expired as of the supplied datetime
+fn expired(elt: &Elt, datetime: &NaiveDateTime) -> Result<bool, SwInstallError> {
+    match elt.expires {
+        Some(ref expires) => {
+            let expires = NaiveDateTime::parse_from_str(expires, DATETIME_FMT)
+                .map_err(|_| SwInstallError::InvalidEltAttribute("expires".to_string(), expires.clone()))?;
+            Ok(expires <= *datetime)
+        },
+        None => Ok(false),
+    }
+}
+
+// the version number following the highest version recorded in the stack
+fn next_version(stack: &StackDocument) -> String {
+    let version = stack.elts()
+                       .filter_map(|e| e.get("version"))
+                       .filter_map(|v| v.parse::<u32>().ok())
+                       .max()
+                       .unwrap_or(0) + 1;
+    version.to_string()
+}
+
+// construct a schema 2 elt tag. Attributes are kept in alphabetical order, as
+// written by the original swinstall.
+fn new_elt(action: &str, datetime: &NaiveDateTime, hash: &str, version: &str, options: &EltOptions) -> StackElement {
+    let mut elt = StackElement::new("elt")
+        .with_attr("action", action)
+        .with_attr("datetime", datetime.format(DATETIME_FMT).to_string().as_str())
+        .with_attr("hash", hash)
+        .with_attr("version", version);
+    if let Some(ref changeset) = options.changeset {
+        elt.set("changeset", changeset.as_str());
+    }
+    if let Some(ref channel) = options.channel {
+        elt.set("channel", channel.as_str());
+    }
+    if let Some(ref expires) = options.expires {
+        elt.set("expires", expires.format(DATETIME_FMT).to_string().as_str());
+    }
+    elt.attributes.sort();
+    elt
 }
\ No newline at end of file
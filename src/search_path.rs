@@ -0,0 +1,171 @@
+//! search_path.rs
+//!
+//! Layered resolution of swinstalled files.
+//!
+//! Pipelines commonly look for a file in a series of locations, from the most specific to
+//! the least (shot, then sequence, then show, then facility). A `SearchPath` holds the
+//! ordered list of roots, and resolves a relative file name to the first root in which a
+//! swinstalled version of the file was current at the requested datetime:
+//!
+//! ```ignore
+//! let search_path: SearchPath = "/shows/dev/seq/shot/etc:/shows/dev/etc:/dd/facility/etc".parse()?;
+//! let resolution = search_path.resolve(&parser, "packages.xml", &datetime)?;
+//! println!("{} (layer {})", resolution.versioned, resolution.layer);
+//! ```
+//!
+//! A root without a swinstall_stack for the file, or whose stack has no version current at
+//! the requested datetime, is passed over. Any other error, such as a malformed stack, ends
+//! the search, rather than silently resolving to a less specific layer.
+
+use chrono::NaiveDateTime;
+use crate::{
+    errors::SwInstallError,
+    parser::SwinstallParser,
+    utils::swinstall_stack_from_versionless,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// An ordered list of roots to search for swinstalled files, most specific first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchPath {
+    roots: Vec<PathBuf>,
+}
+
+/// The outcome of resolving a file against a `SearchPath`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    /// index of the root the file was found in
+    pub layer: usize,
+    /// the root the file was found in
+    pub root: String,
+    /// path to the versionless file within the root
+    pub versionless: String,
+    /// path to the versioned file current at the requested datetime
+    pub versioned: String,
+}
+
+impl SearchPath {
+    /// New up a search path from an ordered list of roots, most specific first.
+    pub fn new<P: Into<PathBuf>>(roots: Vec<P>) -> Self {
+        SearchPath {
+            roots: roots.into_iter().map(|r| r.into()).collect(),
+        }
+    }
+
+    /// The roots searched, most specific first.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Resolve `file_name` to the version current at `datetime` in the first root which has one.
+    pub fn resolve(&self, parser: &SwinstallParser, file_name: &str, datetime: &NaiveDateTime)
+        -> Result<Resolution, failure::Error>
+    {
+        self.resolve_in(parser, file_name, None, datetime)
+    }
+
+    /// Resolve `file_name` to the version current in the release `channel` at `datetime`, in the
+    /// first root which has one.
+    pub fn resolve_in(&self, parser: &SwinstallParser, file_name: &str, channel: Option<&str>, datetime: &NaiveDateTime)
+        -> Result<Resolution, failure::Error>
+    {
+        for (layer, root) in self.roots.iter().enumerate() {
+            let versionless = root.join(file_name);
+            let versionless = versionless.to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+            let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+            if !Path::new(&swinstall_stack).exists() {
+                debug!("layer {}: no swinstall_stack at {}", layer, swinstall_stack);
+                continue;
+            }
+
+            match parser.current_in(swinstall_stack.as_str(), channel, datetime) {
+                Ok(versioned) => {
+                    return Ok(Resolution {
+                        layer,
+                        root: root.to_str().ok_or(SwInstallError::ConvertOsStrFail)?.to_string(),
+                        versionless: versionless.to_string(),
+                        versioned,
+                    });
+                }
+                Err(e) => match e.downcast_ref::<SwInstallError>() {
+                    Some(SwInstallError::NoCurrentFound) => {
+                        debug!("layer {}: nothing current in {}", layer, swinstall_stack);
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        Err(SwInstallError::NotInSearchPath(file_name.to_string()).into())
+    }
+}
+
+impl FromStr for SearchPath {
+    type Err = SwInstallError;
+
+    /// Parse a colon separated list of roots, most specific first. Empty entries are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let roots: Vec<&str> = s.split(':').filter(|r| !r.is_empty()).collect();
+        if roots.is_empty() {
+            return Err(SwInstallError::RuntimeError(format!("empty search path: '{}'", s)));
+        }
+        Ok(SearchPath::new(roots))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::datetime,
+        traits::EltOptions,
+        transaction,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parse_search_path() {
+        let search_path: SearchPath = "/shot:/seq::/show".parse().unwrap();
+        assert_eq!(search_path.roots(), &[PathBuf::from("/shot"), PathBuf::from("/seq"), PathBuf::from("/show")]);
+        assert!("".parse::<SearchPath>().is_err());
+    }
+
+    #[test]
+    fn resolve_first_layer_with_current_version() {
+        let dir = TempDir::new().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let roots: Vec<PathBuf> = ["shot", "seq", "show"].iter().map(|r| dir.path().join(r)).collect();
+        for root in &roots {
+            fs::create_dir_all(root).unwrap();
+        }
+        let source = dir.path().join("source");
+        fs::write(&source, "contents").unwrap();
+        let source = source.to_str().unwrap();
+
+        let install = |root: &PathBuf, dt: &str| {
+            let versionless = root.join("packages.xml");
+            transaction::install(&parser, versionless.to_str().unwrap(), source, "2", &datetime(dt), &EltOptions::default())
+                .expect("install failed");
+        };
+        install(&roots[1], "20181221-142248");
+        install(&roots[2], "20181221-102242");
+
+        let search_path = SearchPath::new(roots.clone());
+        let resolution = search_path.resolve(&parser, "packages.xml", &datetime("20181221-150000")).unwrap();
+        assert_eq!(resolution.layer, 1);
+        assert!(resolution.versioned.ends_with("seq/bak/packages.xml/packages.xml_1"));
+
+        // before the sequence override was installed, the show version wins
+        let resolution = search_path.resolve(&parser, "packages.xml", &datetime("20181221-120000")).unwrap();
+        assert_eq!(resolution.layer, 2);
+        assert_eq!(resolution.root, roots[2].to_str().unwrap());
+
+        assert!(search_path.resolve(&parser, "packages.xml", &datetime("20181221-090000")).is_err());
+        assert!(search_path.resolve(&parser, "env.yaml", &datetime("20181221-150000")).is_err());
+    }
+}
@@ -0,0 +1,750 @@
+//! stack.rs
+//!
+//! In memory model of a swinstall_stack xml document, used by operations which
+//! need to modify a stack (install, rollback, ...).
+//!
+//! Lookups never need the whole document, and continue to stream elt tags via the
+//! `SwinstallCurrent` trait. Mutations, on the other hand, read the full document
+//! into a `StackDocument`, hand it to the `SwinstallCurrent` implementation registered
+//! for the document's schema, and serialize the result back out.
+//!
+//! Elements are stored generically, as a tag name and an ordered list of attributes,
+//! rather than as schema specific structs. This lets a single document type serve
+//! every schema, and keeps attributes we do not know about intact.
+//!
+//! A document read from xml remembers how it was written. Elements which have not been
+//! modified are written back exactly as they were read, along with the xml declaration,
+//! the whitespace and comments between elements, and any element we do not recognise,
+//! children and all. Modified elements keep their attribute order, and new elements take
+//! the indentation of their neighbours, so a read-modify-write cycle only changes the
+//! lines it has to. Documents created from scratch are written in a canonical form.
+//!
+//! Damaged documents, truncated by a full disk or mangled by hand, may be read with
+//! `from_xml_lenient`, which skips over the damage and reports it, rather than failing.
+//!
+//! Documents may also be serialized with serde. Attributes are written as a map, in
+//! document order, and the children of an element we do not recognise are kept as text, so
+//! that converting to JSON (for instance) and back keeps every element and attribute:
+//!
+//! ```json
+//! {
+//!   "root": {"name": "stack_history", "attributes": {"path": "...", "schema": "2"}},
+//!   "elements": [
+//!     {"name": "elt", "attributes": {"action": "install", "datetime": "20181221-142313", "hash": "c618755af9b63728411bc536d2c60cf2", "version": "5"}},
+//!     {"name": "note", "content": "<author>jdoe</author>", "attributes": {}}
+//!   ]
+//! }
+//! ```
+//!
+//! The formatting of the document is not serialized: the xml declaration, whitespace and
+//! comments are lost, and a deserialized document is written in the canonical form.
+
+use chrono::NaiveDateTime;
+use crate::{
+    constants::DATETIME_FMT,
+    diagnostic::Diagnostic,
+    errors::SwInstallError,
+    utils::write_atomic,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    str::from_utf8,
+};
+
+// indentation used for elements when there is nothing to copy it from
+const DEFAULT_INDENT: &str = "\n   ";
+
+/// A tag within the swinstall_stack, along with its attributes in document order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackElement {
+    pub name: String,
+    // the content between the start and end tags, as written, for elements with children
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(with = "ordered_attributes", default)]
+    pub attributes: Vec<(String, String)>,
+    #[serde(skip)]
+    source: Option<Box<Source>>,
+}
+
+// the text an element was parsed from, so that it may be written back unchanged
+#[derive(Debug, Clone)]
+struct Source {
+    // byte offset of the element within the document
+    offset: usize,
+    // whitespace and comments between the previous element (or start tag) and this one
+    leading: String,
+    // the element as written, children and all
+    raw: String,
+    // the element's name and attributes as parsed, to detect modification
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+// the text of a parsed document surrounding its elements
+#[derive(Debug, Clone)]
+struct Layout {
+    // everything before the stack_history start tag: declaration, comments, ...
+    prolog: String,
+    // whitespace and comments between the last element and the stack_history end tag
+    tail: String,
+    // the stack_history end tag, empty if stack_history was an empty tag
+    closing: String,
+    // everything after stack_history
+    epilog: String,
+}
+
+impl PartialEq for StackElement {
+    // elements are equal if their content is, however they were written
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.attributes == other.attributes && self.content == other.content
+    }
+}
+
+impl Eq for StackElement {}
+
+impl StackElement {
+    /// New up an element without any attributes.
+    pub fn new(name: &str) -> Self {
+        StackElement {
+            name: name.to_string(),
+            content: None,
+            attributes: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// Builder style method to append an attribute to the element.
+    pub fn with_attr(mut self, key: &str, value: &str) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Retrieve the value of an attribute, if present.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Retrieve the value of an attribute, or error if it is missing.
+    pub fn require(&self, key: &str) -> Result<&str, SwInstallError> {
+        self.get(key).ok_or_else(|| SwInstallError::MissingEltAttribute(key.to_string()))
+    }
+
+    /// Retrieve the value of an attribute as a datetime, or error if it is missing or is not
+    /// in the swinstall datetime format.
+    pub fn require_datetime(&self, key: &str) -> Result<NaiveDateTime, SwInstallError> {
+        let value = self.require(key)?;
+        NaiveDateTime::parse_from_str(value, DATETIME_FMT)
+            .map_err(|_| SwInstallError::InvalidEltAttribute(key.to_string(), value.to_string()))
+    }
+
+    /// Set the value of an attribute. Existing attributes keep their position;
+    /// new attributes are appended.
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.attributes.iter_mut().find(|(k, _)| k == key) {
+            Some(attr) => attr.1 = value.to_string(),
+            None => self.attributes.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Remove an attribute, returning its value if it was present.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let idx = self.attributes.iter().position(|(k, _)| k == key)?;
+        Some(self.attributes.remove(idx).1)
+    }
+
+    // build an element from a quick-xml start or empty tag
+    fn from_bytes_start(e: &BytesStart) -> Result<Self, SwInstallError> {
+        let mut element = StackElement::new(from_utf8(e.name())?);
+        for attr in e.attributes() {
+            let attr = attr?;
+            let value = attr.unescaped_value()?;
+            element.attributes.push((from_utf8(attr.key)?.to_string(), from_utf8(&value)?.to_string()));
+        }
+        Ok(element)
+    }
+
+    // remember the text the element was parsed from
+    fn with_source(mut self, offset: usize, leading: &str, raw: &str, inner: Option<&str>) -> Self {
+        self.content = inner.map(|i| i.to_string());
+        self.source = Some(Box::new(Source {
+            offset,
+            leading: leading.to_string(),
+            raw: raw.to_string(),
+            name: self.name.clone(),
+            attributes: self.attributes.clone(),
+        }));
+        self
+    }
+
+    /// The byte offset of the element within the document it was read from, if any.
+    pub fn offset(&self) -> Option<usize> {
+        self.source.as_ref().map(|s| s.offset)
+    }
+
+    // the text the element was parsed from, if it has not been modified since
+    fn unmodified_source(&self) -> Option<&str> {
+        self.source.as_ref()
+            .filter(|s| s.name == self.name && s.attributes == self.attributes)
+            .map(|s| s.raw.as_str())
+    }
+
+    // the whitespace and comments which preceded the element when it was parsed
+    fn leading(&self) -> Option<&str> {
+        self.source.as_ref().map(|s| s.leading.as_str())
+    }
+
+    // the element's start tag, without the closing '>'
+    fn open_tag(&self) -> String {
+        let mut xml = format!("<{}", self.name);
+        for (key, value) in &self.attributes {
+            xml.push_str(&format!(" {}=\"{}\"", key, escape(value)));
+        }
+        xml
+    }
+
+    // serialize the element, reusing the parsed text if it is unchanged
+    fn to_xml(&self) -> String {
+        if let Some(raw) = self.unmodified_source() {
+            return raw.to_string();
+        }
+        match self.content {
+            Some(ref content) => format!("{}>{}</{}>", self.open_tag(), content, self.name),
+            None => format!("{}/>", self.open_tag()),
+        }
+    }
+}
+
+/// A swinstall_stack document: the outer stack_history tag and the elements it contains.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StackDocument {
+    pub root: StackElement,
+    pub elements: Vec<StackElement>,
+    #[serde(skip)]
+    layout: Option<Layout>,
+}
+
+impl PartialEq for StackDocument {
+    // documents are equal if their content is, however they were written
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.elements == other.elements
+    }
+}
+
+impl Eq for StackDocument {}
+
+impl StackDocument {
+    /// New up an empty stack for the swinstall_stack at `path`, using the supplied schema.
+    pub fn new(path: &str, schema: &str) -> Self {
+        StackDocument {
+            root: StackElement::new("stack_history")
+                    .with_attr("path", path)
+                    .with_attr("schema", schema),
+            elements: Vec::new(),
+            layout: None,
+        }
+    }
+
+    /// Read the swinstall_stack at the supplied path. Malformed stacks are reported as
+    /// `SwInstallError::Malformed`, locating the problem within the file.
+    pub fn from_file(swinstall_stack: &str) -> Result<Self, SwInstallError> {
+        let contents = fs::read_to_string(swinstall_stack)?;
+        Self::parse(contents.as_str(), Some(swinstall_stack), None)
+    }
+
+    /// Parse a swinstall_stack from a str.
+    pub fn from_xml(xml: &str) -> Result<Self, SwInstallError> {
+        Self::parse(xml, None, None)
+    }
+
+    /// Parse a swinstall_stack, recovering what we can if it is damaged. Malformed tags are
+    /// dropped, and reading resumes at the next tag; a missing or unterminated stack_history
+    /// end tag is tolerated, and restored when the document is written. Each piece of damage
+    /// is returned as a warning, in document order. Only a document without a stack_history
+    /// tag is an error.
+    pub fn from_xml_lenient(xml: &str) -> Result<(Self, Vec<Diagnostic>), SwInstallError> {
+        let mut warnings = Vec::new();
+        let stack = Self::parse(xml, None, Some(&mut warnings))?;
+        Ok((stack, warnings))
+    }
+
+    // parse the document. When `warnings` is supplied, damage is recorded there rather than
+    // ending the parse
+    fn parse(xml: &str, path: Option<&str>, mut warnings: Option<&mut Vec<Diagnostic>>) -> Result<Self, SwInstallError> {
+        let located = |offset: usize, error: SwInstallError| Diagnostic::new(path, xml, offset, error);
+        let mut reader = Reader::from_str(xml);
+        // offset of the text being read within xml. Non zero once we have skipped damage
+        let mut base = 0;
+        let mut buf = Vec::new();
+        let mut root: Option<StackElement> = None;
+        let mut prolog = "";
+        let mut elements = Vec::new();
+        // end of the last element (or start tag) consumed
+        let mut last = 0;
+        // whitespace and comments preceding skipped damage, to be given to the next element
+        let mut carried = "";
+
+        loop {
+            let start = base + reader.buffer_position();
+            let outcome = match reader.read_event(&mut buf) {
+                Err(e) => Err(located(base + reader.buffer_position(), e.into())),
+                Ok(event) => {
+                    let end = base + reader.buffer_position();
+                    let leading = format!("{}{}", carried, &xml[last..start]);
+                    match event {
+                        Event::Start(ref e) if root.is_none() && e.name() == b"stack_history" => {
+                            StackElement::from_bytes_start(e)
+                                .map(|element| {
+                                    prolog = &xml[..start];
+                                    root = Some(element.with_source(start, "", &xml[start..end], None));
+                                    last = end;
+                                    carried = "";
+                                    None
+                                })
+                                .map_err(|e| located(start, e))
+                        },
+                        Event::Empty(ref e) if root.is_none() && e.name() == b"stack_history" => {
+                            StackElement::from_bytes_start(e)
+                                .map(|element| {
+                                    let layout = Layout {
+                                        prolog: xml[..start].to_string(),
+                                        tail: String::new(),
+                                        closing: String::new(),
+                                        epilog: xml[end..].to_string(),
+                                    };
+                                    Some((element.with_source(start, "", &xml[start..end], None), layout))
+                                })
+                                .map_err(|e| located(start, e))
+                        },
+                        Event::Empty(ref e) if root.is_some() => {
+                            StackElement::from_bytes_start(e)
+                                .map(|element| {
+                                    elements.push(element.with_source(start, &leading, &xml[start..end], None));
+                                    last = end;
+                                    carried = "";
+                                    None
+                                })
+                                .map_err(|e| located(start, e))
+                        },
+                        Event::Start(ref e) if root.is_some() => {
+                            // an element with children. We do not interpret the children, but keep them
+                            match StackElement::from_bytes_start(e) {
+                                Ok(element) => {
+                                    Self::skip_children(&mut reader, &mut buf, &element.name, base)
+                                        .map(|(inner_end, element_end)| {
+                                            elements.push(element.with_source(
+                                                start, &leading, &xml[start..element_end], Some(&xml[end..inner_end])
+                                            ));
+                                            last = element_end;
+                                            carried = "";
+                                            None
+                                        })
+                                        .map_err(|(offset, e)| located(offset.unwrap_or(start), e))
+                                },
+                                Err(e) => Err(located(start, e)),
+                            }
+                        },
+                        Event::End(ref e) if e.name() == b"stack_history" => {
+                            let root = match root.take() {
+                                Some(root) => root,
+                                None => return Err(located(start, no_stack_history()).into()),
+                            };
+                            // a document truncated part way through the end tag still reads
+                            // as closed. Restore the end tag, as the damage it is
+                            let mut closing = &xml[start..end];
+                            if !closing.ends_with('>') {
+                                let diagnostic = located(start, SwInstallError::QuckXmlError(
+                                    "stack_history end tag is unterminated".to_string()
+                                ));
+                                match warnings {
+                                    Some(ref mut warnings) => warnings.push(diagnostic),
+                                    None => return Err(diagnostic.into()),
+                                }
+                                closing = "</stack_history>";
+                            }
+                            let layout = Layout {
+                                prolog: prolog.to_string(),
+                                tail: leading,
+                                closing: closing.to_string(),
+                                epilog: xml[end..].to_string(),
+                            };
+                            Ok(Some((root, layout)))
+                        },
+                        Event::Eof => break,
+                        _ => Ok(None),
+                    }
+                },
+            };
+
+            match outcome {
+                Ok(Some((root, layout))) => return Ok(StackDocument { root, elements, layout: Some(layout) }),
+                Ok(None) => {},
+                Err(diagnostic) => {
+                    let warnings = match warnings {
+                        Some(ref mut warnings) => warnings,
+                        None => return Err(diagnostic.into()),
+                    };
+                    warnings.push(diagnostic);
+                    // skip the damage, resuming at the next tag
+                    if carried.is_empty() {
+                        carried = &xml[last..start];
+                    }
+                    base = xml[start + 1..].find('<').map(|i| start + 1 + i).unwrap_or_else(|| xml.len());
+                    last = base;
+                    reader = Reader::from_str(&xml[base..]);
+                    // we may have skipped the start of an element whose end is to come
+                    reader.check_end_names(false);
+                },
+            }
+            buf.clear();
+        }
+
+        let root = root.ok_or_else(|| SwInstallError::from(located(0, no_stack_history())))?;
+        // a document truncated before its end tag is damaged, and is not to be rewritten as if
+        // it were whole
+        let diagnostic = located(xml.len(), SwInstallError::QuckXmlError("stack_history is never closed".to_string()));
+        match warnings {
+            Some(warnings) => warnings.push(diagnostic),
+            None => return Err(diagnostic.into()),
+        }
+        // the document ended without closing stack_history. Fall back on canonical output
+        Ok(StackDocument { root, elements, layout: None })
+    }
+
+    // consume the children of the element `name` just started, returning the positions of the
+    // start and end of its end tag. Errors are returned with their position, if it is known
+    fn skip_children(reader: &mut Reader<&[u8]>, buf: &mut Vec<u8>, name: &str, base: usize)
+        -> Result<(usize, usize), (Option<usize>, SwInstallError)>
+    {
+        let mut depth = 0;
+        loop {
+            buf.clear();
+            let start = base + reader.buffer_position();
+            match reader.read_event(buf) {
+                Ok(Event::Start(_)) => depth += 1,
+                Ok(Event::End(ref e)) if depth == 0 && e.name() == name.as_bytes() => {
+                    return Ok((start, base + reader.buffer_position()));
+                },
+                Ok(Event::End(_)) if depth > 0 => depth -= 1,
+                Ok(Event::End(_)) | Ok(Event::Eof) => {
+                    return Err((None, SwInstallError::QuckXmlError("element is never closed".to_string())));
+                },
+                Err(e) => return Err((Some(base + reader.buffer_position()), e.into())),
+                _ => {},
+            }
+        }
+    }
+
+    /// The schema attribute of the stack_history tag, if present.
+    pub fn schema(&self) -> Option<&str> {
+        self.root.get("schema")
+    }
+
+    /// Iterate over the elt tags in document order.
+    pub fn elts(&self) -> impl Iterator<Item = &StackElement> {
+        self.elements.iter().filter(|e| e.name == "elt")
+    }
+
+    /// Iterate mutably over the elt tags in document order.
+    pub fn elts_mut(&mut self) -> impl Iterator<Item = &mut StackElement> {
+        self.elements.iter_mut().filter(|e| e.name == "elt")
+    }
+
+    /// Serialize the document. Documents read from xml keep their original formatting,
+    /// except where they have been modified.
+    pub fn to_xml(&self) -> String {
+        match self.layout {
+            Some(ref layout) => self.to_xml_preserving(layout),
+            None => self.to_xml_canonical(),
+        }
+    }
+
+    // serialize a document created from scratch
+    fn to_xml_canonical(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&self.root.open_tag());
+        xml.push_str(">\n");
+        for element in &self.elements {
+            xml.push_str(&DEFAULT_INDENT[1..]);
+            xml.push_str(&element.to_xml());
+            xml.push('\n');
+        }
+        xml.push_str("</stack_history>\n");
+        xml
+    }
+
+    // serialize a parsed document, reusing its text wherever it is unchanged
+    fn to_xml_preserving(&self, layout: &Layout) -> String {
+        let mut xml = layout.prolog.clone();
+        let empty_root = layout.closing.is_empty();
+
+        if empty_root && self.elements.is_empty() {
+            match self.root.unmodified_source() {
+                Some(raw) => xml.push_str(raw),
+                None => xml.push_str(&format!("{}/>", self.root.open_tag())),
+            }
+            xml.push_str(&layout.epilog);
+            return xml;
+        }
+
+        match self.root.unmodified_source() {
+            Some(raw) if !empty_root => xml.push_str(raw),
+            _ => {
+                xml.push_str(&self.root.open_tag());
+                xml.push('>');
+            }
+        }
+        for (idx, element) in self.elements.iter().enumerate() {
+            match element.leading() {
+                Some(leading) => xml.push_str(leading),
+                None => xml.push_str(&self.indent_for(idx)),
+            }
+            xml.push_str(&element.to_xml());
+        }
+        if empty_root {
+            xml.push_str("\n</stack_history>");
+        } else {
+            xml.push_str(&layout.tail);
+            xml.push_str(&layout.closing);
+        }
+        xml.push_str(&layout.epilog);
+        xml
+    }
+
+    // the indentation for a new element at idx, copied from the nearest parsed element
+    fn indent_for(&self, idx: usize) -> String {
+        let before = self.elements[..idx].iter().rev();
+        let after = self.elements[idx + 1..].iter();
+        let leading = before.chain(after).filter_map(|e| e.leading()).next();
+        match leading {
+            // keep the final line break and indentation, dropping any comments
+            Some(leading) => match leading.rfind('\n') {
+                Some(pos) if leading[pos..].trim().is_empty() => leading[pos..].to_string(),
+                _ => DEFAULT_INDENT.to_string(),
+            },
+            None => DEFAULT_INDENT.to_string(),
+        }
+    }
+
+    /// Atomically write the document to the supplied path. The caller is expected
+    /// to hold the `StackLock` for the path.
+    pub fn write(&self, swinstall_stack: &str) -> Result<(), SwInstallError> {
+        write_atomic(swinstall_stack, self.to_xml().as_bytes())
+    }
+}
+
+// the error for a document without a stack_history element
+fn no_stack_history() -> SwInstallError {
+    SwInstallError::QuckXmlError("no stack_history element".to_string())
+}
+
+// escape xml special characters in an attribute value
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// (de)serialize attributes as a map, preserving document order in both directions
+mod ordered_attributes {
+    use serde::{
+        de::{MapAccess, Visitor},
+        ser::SerializeMap,
+        Deserializer, Serializer,
+    };
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(attributes: &[(String, String)], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(attributes.len()))?;
+        for (key, value) in attributes {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+        struct AttributeVisitor;
+
+        impl<'de> Visitor<'de> for AttributeVisitor {
+            type Value = Vec<(String, String)>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of attribute names to values")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+                let mut attributes = Vec::new();
+                while let Some(entry) = access.next_entry()? {
+                    attributes.push(entry);
+                }
+                Ok(attributes)
+            }
+        }
+
+        deserializer.deserialize_map(AttributeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        schemas::{one::One, two::Two},
+        testing::datetime,
+        traits::{EltOptions, SwinstallCurrent},
+    };
+    use std::path::PathBuf;
+
+    const SCHEMA2: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<stack_history path="/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack" schema="2">
+   <elt action="install" datetime="20181221-142313" hash="c618755af9b63728411bc536d2c60cf2" version="5"/>
+   <elt action="install" datetime="20181221-142248" hash="5c8fdabe2ae7fa9287c0672b88ef6593" version="4"/>
+</stack_history>
+"#;
+
+    #[test]
+    fn parse_document() {
+        let doc = StackDocument::from_xml(SCHEMA2).expect("unable to parse");
+        assert_eq!(doc.schema(), Some("2"));
+        assert_eq!(doc.elts().count(), 2);
+        let first = doc.elts().next().unwrap();
+        assert_eq!(first.get("version"), Some("5"));
+        assert_eq!(first.attributes[0].0, "action");
+    }
+
+    #[test]
+    fn round_trip_document() {
+        let doc = StackDocument::from_xml(SCHEMA2).expect("unable to parse");
+        assert_eq!(doc.to_xml(), SCHEMA2);
+    }
+
+    #[test]
+    fn attribute_values_escaped() {
+        let mut doc = StackDocument::new("/dd/facility/etc/bak/a&b/a&b_swinstall_stack", "2");
+        doc.elements.push(StackElement::new("elt").with_attr("note", "\"quoted\""));
+        let parsed = StackDocument::from_xml(&doc.to_xml()).expect("unable to parse");
+        assert_eq!(parsed, doc);
+    }
+
+    // compare against a file in tests/golden. Set UPDATE_GOLDEN to rewrite the file instead.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            fs::write(&path, actual).unwrap();
+        }
+        let expected = fs::read_to_string(&path).expect("missing golden file");
+        assert_eq!(actual, expected, "output differs from {}", path.display());
+    }
+
+    fn example(schema: &str) -> String {
+        format!("{}/examples/schema{}/bak/packages.xml/packages.xml_swinstall_stack", env!("CARGO_MANIFEST_DIR"), schema)
+    }
+
+    #[test]
+    fn malformed_document_is_located() {
+        let xml = "<stack_history schema=\"2\">\n   <elt action=\"install\" datetime=\"20181221-142313\"/>\n   <elt version=\"5\"</stack_history>\n";
+        match StackDocument::from_xml(xml) {
+            Err(SwInstallError::Malformed(diagnostic)) => {
+                assert_eq!((diagnostic.line, diagnostic.column), (3, 4));
+                assert_eq!(diagnostic.element.as_deref(), Some("elt"));
+                assert_eq!(diagnostic.path, None);
+            },
+            other => panic!("expected a diagnostic, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_stack_history_is_malformed() {
+        // nor is a stack_history which is never closed
+        for xml in &["<packages/>\n", "", "</stack_history>", "<stack_history schema=\"2\">\n   <elt version=\"1\"/>\n"] {
+            match StackDocument::from_xml(xml) {
+                Err(SwInstallError::Malformed(diagnostic)) => assert!(diagnostic.to_string().contains("stack_history")),
+                other => panic!("expected a diagnostic for {:?}, got {:?}", xml, other),
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_parse_skips_damage() {
+        let damaged = "<stack_history schema=\"2\">\n   <elt version=\"5\"/>\n   <elt version=\"4\"\n   <elt version=\"3\"/>\n   <elt version=\"2\"";
+        assert!(StackDocument::from_xml(damaged).is_err());
+
+        let (doc, warnings) = StackDocument::from_xml_lenient(damaged).expect("unable to recover");
+        let versions: Vec<&str> = doc.elts().filter_map(|e| e.get("version")).collect();
+        assert_eq!(versions, vec!["5", "3"]);
+        let lines: Vec<usize> = warnings.iter().map(|w| w.line).collect();
+        // the two truncated elts, and the missing end tag
+        assert_eq!(lines, vec![3, 5, 5]);
+        assert_eq!(doc.elements[1].offset(), damaged.find("<elt version=\"3\""));
+
+        // a document whose only damage is a truncated end tag keeps its formatting, and has
+        // the end tag restored
+        let xml = fs::read_to_string(example("1")).unwrap();
+        let truncated = xml.trim_end().trim_end_matches('>');
+        assert!(StackDocument::from_xml(truncated).is_err());
+        let (doc, warnings) = StackDocument::from_xml_lenient(truncated).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].offset, truncated.rfind("</stack_history").unwrap());
+        assert_eq!(doc.to_xml(), format!("{}>", truncated));
+    }
+
+    #[test]
+    fn examples_round_trip_unchanged() {
+        for schema in &["1", "2"] {
+            let original = fs::read_to_string(example(schema)).unwrap();
+            let doc = StackDocument::from_xml(&original).expect("unable to parse");
+            assert_eq!(doc.to_xml(), original);
+        }
+    }
+
+    #[test]
+    fn golden_schema1_install() {
+        let mut doc = StackDocument::from_file(&example("1")).unwrap();
+        One::new().install(&mut doc, "", &datetime("20190104-120000"), &EltOptions::default()).unwrap();
+        assert_golden("schema1_install.xml", &doc.to_xml());
+    }
+
+    #[test]
+    fn golden_schema2_install() {
+        let mut doc = StackDocument::from_file(&example("2")).unwrap();
+        let two = Two::new();
+        two.install(&mut doc, "0f343b0931126a20f133d67c2b018a3b", &datetime("20190104-120000"), &EltOptions::default()).unwrap();
+        two.rollback(&mut doc, "3", &datetime("20190105-090000"), &EltOptions::default()).unwrap();
+        assert_golden("schema2_install.xml", &doc.to_xml());
+    }
+
+    #[test]
+    fn golden_annotated_install() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/annotated_swinstall_stack.xml");
+        let original = fs::read_to_string(&path).unwrap();
+        let mut doc = StackDocument::from_xml(&original).unwrap();
+        assert_eq!(doc.to_xml(), original);
+        assert_eq!(doc.root.get("owner"), Some("pipeline"));
+        assert_eq!(doc.elements.last().unwrap().name, "note");
+
+        let two = Two::new();
+        two.install(&mut doc, "0f343b0931126a20f133d67c2b018a3b", &datetime("20190104-120000"), &EltOptions::default()).unwrap();
+        two.tag(&mut doc, "known-good", Some("5"), &datetime("20190104-120000")).unwrap();
+        // modify an element, keeping its unrecognised attribute
+        doc.elements[1].set("reviewed", "no");
+        assert_golden("annotated_install.xml", &doc.to_xml());
+    }
+}
@@ -0,0 +1,40 @@
+//! testing.rs
+//!
+//! Helpers shared by the unit tests of several modules.
+
+use chrono::NaiveDateTime;
+use crate::{
+    parser::SwinstallParser,
+    traits::EltOptions,
+    transaction,
+    utils::parse_datetime,
+};
+use std::fs;
+use tempfile::TempDir;
+
+/// Parse a datetime in any of the forms accepted on the command line.
+pub fn datetime(s: &str) -> NaiveDateTime {
+    parse_datetime(s).unwrap()
+}
+
+/// Install `contents` as a new schema 2 version of `versionless` at `dt`.
+pub fn install(parser: &SwinstallParser, dir: &TempDir, versionless: &str, contents: &str, dt: &str) {
+    let source = dir.path().join("source");
+    fs::write(&source, contents).unwrap();
+    transaction::install(
+        parser, versionless, source.to_str().unwrap(), "2", &datetime(dt), &EltOptions::default()
+    ).unwrap();
+}
+
+/// Install each of `contents` as a new version of the versionless file `name` within `dir`, one
+/// a day at noon from the first of January 2019. Returns the path of the versionless file.
+pub fn install_daily(parser: &SwinstallParser, dir: &TempDir, name: &str, contents: &[impl AsRef<str>]) -> String {
+    let versionless = dir.path().join(name);
+    fs::create_dir_all(versionless.parent().unwrap()).unwrap();
+    let versionless = versionless.to_str().unwrap().to_string();
+    for (i, contents) in contents.iter().enumerate() {
+        let dt = format!("201901{:02}-120000", i + 1);
+        install(parser, dir, versionless.as_str(), contents.as_ref(), dt.as_str());
+    }
+    versionless
+}
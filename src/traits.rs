@@ -15,6 +15,11 @@
 //!     - retrieving the file swinstalled on the date and time closest to but not
 //!       exceeding that provided by the user
 //!
+//! In addition, implementations may record installs, rollbacks, staged installs and
+//! promotions in a `StackDocument`, tag versions, and report the timeline, tags, changesets,
+//! staged versions and scheduled entries recorded in it. Operations which modify the stack default to returning
+//! `SwInstallError::UnsupportedOperation`.
+//!
 //! Because swinstall_stack maintains a registry of SwinstallCurrent trait objects,
 //! allowing us to parse multiple different schema versions from the same runtime,
 //! identified at runtime via the outer *stack_history's schema_version* attribute,
@@ -30,10 +35,123 @@
 //! but I didn't want to pattern match against each enum branch for each elt tag,
 //! as the each xml file should have a uniform elt tag structure based on its schema.
 use chrono::{NaiveDateTime, Local};
-use crate::errors::SwInstallError;
+use crate::{
+    constants::DATETIME_FMT,
+    errors::SwInstallError,
+    stack::{StackDocument, StackElement},
+};
 use quick_xml::Reader;
+use std::fs::File;
+
+/// Optional metadata recorded on the elt tag written by an install or rollback.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EltOptions {
+    /// id of the changeset the operation belongs to
+    pub changeset: Option<String>,
+    /// datetime after which the entry stops being current
+    pub expires: Option<NaiveDateTime>,
+    /// release channel the entry belongs to. None is the default channel
+    pub channel: Option<String>,
+}
+
+impl EltOptions {
+    /// Builder style method to set the changeset id.
+    pub fn changeset(mut self, changeset: &str) -> Self {
+        self.changeset = Some(changeset.to_string());
+        self
+    }
+
+    /// Builder style method to set the release channel.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = Some(channel.to_string());
+        self
+    }
+
+    /// Builder style method to set the expiry datetime.
+    pub fn expires(mut self, expires: &NaiveDateTime) -> Self {
+        self.expires = Some(*expires);
+        self
+    }
+}
 
-pub trait SwinstallCurrent: std::fmt::Debug  {
+/// An elt tag recorded as part of a changeset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangesetEntry {
+    pub changeset: String,
+    pub action: String,
+    pub datetime: NaiveDateTime,
+    pub version: String,
+    /// the release channel the elt was recorded in, or None for the default channel
+    pub channel: Option<String>,
+}
+
+/// An event in the history of a swinstalled file: an entry recorded in the stack, or the
+/// expiry of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEvent {
+    pub action: String,
+    pub datetime: NaiveDateTime,
+    pub version: String,
+    pub changeset: Option<String>,
+    /// release channel of the entry. None is the default channel
+    pub channel: Option<String>,
+    /// when the entry expires, for entries recorded with an expiry
+    pub expires: Option<NaiveDateTime>,
+}
+
+/// A tag recorded in the stack, pointing a name at a version as of a datetime. A tag
+/// without a version records the removal of the name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagEntry {
+    pub name: String,
+    pub datetime: NaiveDateTime,
+    pub version: Option<String>,
+}
+
+/// Find the version the tag `name` points at as of `datetime`, given the tags recorded in a
+/// stack, most recent first.
+pub fn tagged_version<'a>(tags: &'a [TagEntry], name: &str, datetime: &NaiveDateTime) -> Option<&'a str> {
+    tags.iter()
+        .find(|t| t.name == name && t.datetime <= *datetime)
+        .and_then(|t| t.version.as_deref())
+}
+
+/// An elt tag which takes effect after a given datetime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEntry {
+    pub action: String,
+    pub datetime: NaiveDateTime,
+    pub version: String,
+}
+
+/// A versioned file found alongside a swinstall_stack, used to reconstruct a lost stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionedFile {
+    /// the version string, taken from the file name
+    pub version: String,
+    /// modification time of the file
+    pub modified: NaiveDateTime,
+    pub hash: String,
+}
+
+/// A version recorded in a stack, as considered for garbage collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRecord {
+    pub version: String,
+    /// when the version was first recorded in the stack
+    pub installed: NaiveDateTime,
+    /// whether the version has ever been current, in any channel
+    pub ever_current: bool,
+    /// whether the version must be kept whatever the policy: it is current in some channel,
+    /// staged, or scheduled to become current
+    pub in_use: bool,
+    /// whether the versioned file has already been garbage collected
+    pub pruned: bool,
+}
+
+/// Schema implementations are shared between threads by `SwinstallParser::resolve_many`, and
+/// so must be `Send + Sync`.
+pub trait SwinstallCurrent: std::fmt::Debug + Send + Sync {
     type SwBufReader;
 
     // this sucks. associated const are not object safe so....
@@ -60,4 +178,171 @@ pub trait SwinstallCurrent: std::fmt::Debug  {
         -> Result<String, SwInstallError>
     where
         <Self as SwinstallCurrent>::SwBufReader: std::io::BufRead;
+
+    /// Retrieve the version string current in the named release channel at the provided datetime.
+    /// `current_at` resolves the default channel. Schemas without channel support return
+    /// `SwInstallError::UnsupportedOperation`.
+    fn current_in_channel(&self, _reader: &mut Reader<Self::SwBufReader>, _datetime: &NaiveDateTime, channel: &str)
+        -> Result<String, SwInstallError>
+    where
+        <Self as SwinstallCurrent>::SwBufReader: std::io::BufRead
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("channel {} for schema {}", channel, self.schema())))
+    }
+
+    /// Retrieve the version string current at the provided datetime by reading the
+    /// swinstall_stack from its end, for schemas which append new entries. `start` is the offset
+    /// of the first byte after the stack_history start tag. Returns None when the end of the
+    /// stack cannot decide the answer, leaving the lookup to `current_at`. The default never
+    /// decides.
+    fn current_from_end(&self, _file: &mut File, _start: u64, _datetime: &NaiveDateTime)
+        -> Result<Option<String>, SwInstallError>
+    {
+        Ok(None)
+    }
+
+    /// Retrieve the version string current in `channel` (None for the default channel) at the
+    /// provided datetime from a stack document, rather than by streaming the swinstall_stack.
+    /// Along with the version string, returns the index into `stack.elements` of the last
+    /// element consulted; elements after it cannot change the outcome. Used when resolving
+    /// against a stack recovered from a damaged file.
+    fn current_in_stack(&self, _stack: &StackDocument, _datetime: &NaiveDateTime, _channel: Option<&str>)
+        -> Result<(String, usize), SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("resolving a stack document for schema {}", self.schema())))
+    }
+
+    /// Resolve a stack document as `current_in_stack` does, at each of the supplied datetimes.
+    /// Schemas may parse the stack once for all of them, in which case any element which
+    /// cannot be parsed fails the whole.
+    #[allow(clippy::type_complexity)]
+    fn current_in_stack_at(&self, stack: &StackDocument, datetimes: &[NaiveDateTime], channel: Option<&str>)
+        -> Result<Vec<Result<(String, usize), SwInstallError>>, SwInstallError>
+    {
+        Ok(datetimes.iter().map(|datetime| self.current_in_stack(stack, datetime, channel)).collect())
+    }
+
+    /// Check that an element of a stack is well formed for this schema, so that it may be
+    /// kept when recovering a damaged stack. Elements the schema does not know are accepted.
+    fn validate(&self, _element: &StackElement) -> Result<(), SwInstallError> {
+        Ok(())
+    }
+
+    /// Rebuild the elements of a lost stack from the versioned files found alongside it, making
+    /// `current` the current version, or the most recent version if None. `current_since` is
+    /// our best guess at when `current` became current. Files whose version this schema does
+    /// not recognise are ignored. Returns a description of each guess made.
+    fn reconstruct(&self, _stack: &mut StackDocument, _files: &[VersionedFile], _current: Option<&str>, _current_since: &NaiveDateTime)
+        -> Result<Vec<String>, SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("reconstruct for schema {}", self.schema())))
+    }
+
+    /// Retrieve every version recorded in the supplied stack, as of `datetime`, most recently
+    /// installed first.
+    fn versions(&self, _stack: &StackDocument, _datetime: &NaiveDateTime) -> Result<Vec<VersionRecord>, SwInstallError> {
+        Err(SwInstallError::UnsupportedOperation(format!("versions for schema {}", self.schema())))
+    }
+
+    /// Mark the elt tags for `version` as pruned at `datetime`, once its versioned file has
+    /// been garbage collected. Lookups which resolve to a pruned entry fail with
+    /// `SwInstallError::VersionPruned`.
+    fn prune(&self, stack: &mut StackDocument, version: &str, datetime: &NaiveDateTime) -> Result<(), SwInstallError> {
+        let mut found = false;
+        for elt in stack.elts_mut().filter(|e| e.get("version") == Some(version)) {
+            elt.set("pruned", datetime.format(DATETIME_FMT).to_string().as_str());
+            found = true;
+        }
+        if !found {
+            return Err(SwInstallError::VersionNotFound(version.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Find the elements of the supplied stack which may be moved to an archive without
+    /// changing the result of any lookup at or after `before`. Returns their indices within
+    /// `stack.elements`, in document order.
+    fn archivable(&self, _stack: &StackDocument, _before: &NaiveDateTime) -> Result<Vec<usize>, SwInstallError> {
+        Err(SwInstallError::UnsupportedOperation(format!("archive for schema {}", self.schema())))
+    }
+
+    /// Merge elements previously archived from the stack back into it, in their place. The
+    /// archived elements are older than those left in the stack. Entries brought back into
+    /// the stack by a rollback keep their copies in the archive; those copies are dropped.
+    fn merge_archived(&self, _stack: &mut StackDocument, _archived: Vec<StackElement>) -> Result<(), SwInstallError> {
+        Err(SwInstallError::UnsupportedOperation(format!("archive for schema {}", self.schema())))
+    }
+
+    /// Record the installation of a new version of the file, whose contents hash to `hash`,
+    /// in the supplied stack. Returns the version string of the newly installed file.
+    fn install(&self, _stack: &mut StackDocument, _hash: &str, _datetime: &NaiveDateTime, _options: &EltOptions)
+        -> Result<String, SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("install for schema {}", self.schema())))
+    }
+
+    /// Record a rollback (or rollforward) to an existing version in the supplied stack.
+    fn rollback(&self, _stack: &mut StackDocument, _version: &str, _datetime: &NaiveDateTime, _options: &EltOptions)
+        -> Result<(), SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("rollback for schema {}", self.schema())))
+    }
+
+    /// Record a new version of the file, whose contents hash to `hash`, as staged: present
+    /// in the stack but not current. Returns the version string of the staged file.
+    fn stage(&self, _stack: &mut StackDocument, _hash: &str, _datetime: &NaiveDateTime, _options: &EltOptions)
+        -> Result<String, SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("stage for schema {}", self.schema())))
+    }
+
+    /// Make a staged version current.
+    fn promote(&self, _stack: &mut StackDocument, _version: &str, _datetime: &NaiveDateTime, _options: &EltOptions)
+        -> Result<(), SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("promote for schema {}", self.schema())))
+    }
+
+    /// Point the tag `name` at `version` as of `datetime`, or remove it if `version` is None.
+    /// Earlier positions of the tag are kept, so that it may be resolved as of any datetime.
+    fn tag(&self, _stack: &mut StackDocument, _name: &str, _version: Option<&str>, _datetime: &NaiveDateTime)
+        -> Result<(), SwInstallError>
+    {
+        Err(SwInstallError::UnsupportedOperation(format!("tag for schema {}", self.schema())))
+    }
+
+    /// Retrieve every tag recorded in the supplied stack, most recent first.
+    fn tags(&self, _stack: &StackDocument) -> Result<Vec<TagEntry>, SwInstallError> {
+        Ok(Vec::new())
+    }
+
+    /// Retrieve the versions which have been staged in the supplied release channel but not
+    /// yet promoted in it, most recent first. None is the default channel.
+    fn staged(&self, _stack: &StackDocument, _channel: Option<&str>) -> Result<Vec<String>, SwInstallError> {
+        Ok(Vec::new())
+    }
+
+    /// Retrieve the entries in the supplied stack scheduled to take effect after `datetime`,
+    /// latest first. Schemas without scheduling support return an empty list.
+    fn pending(&self, _stack: &StackDocument, _datetime: &NaiveDateTime) -> Result<Vec<PendingEntry>, SwInstallError> {
+        Ok(Vec::new())
+    }
+
+    /// Retrieve the history of the file recorded in the supplied stack, most recent event first.
+    /// Expiring entries contribute an additional `expire` event at their expiry datetime.
+    fn timeline(&self, _stack: &StackDocument) -> Result<Vec<TimelineEvent>, SwInstallError> {
+        Err(SwInstallError::UnsupportedOperation(format!("timeline for schema {}", self.schema())))
+    }
+
+    /// Retrieve the datetimes at which the version current in the supplied stack may change,
+    /// in any order. Lookups between one of them and the next resolve alike, in every channel.
+    fn transitions(&self, stack: &StackDocument) -> Result<Vec<NaiveDateTime>, SwInstallError> {
+        Ok(self.timeline(stack)?.into_iter().map(|event| event.datetime).collect())
+    }
+
+    /// Retrieve the elt tags in the supplied stack which belong to a changeset, in stack order.
+    /// Schemas without changeset support return an empty list.
+    fn changesets(&self, _stack: &StackDocument) -> Result<Vec<ChangesetEntry>, SwInstallError> {
+        Ok(Vec::new())
+    }
 }
\ No newline at end of file
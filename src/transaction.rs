@@ -0,0 +1,975 @@
+//! transaction.rs
+//!
+//! Crash safe installs and rollbacks of swinstalled files.
+//!
+//! Each operation holds the `StackLock` for the swinstall_stack and records its intent in
+//! a write-ahead `Journal` before touching anything. Files are then updated in a fixed
+//! order, each via an atomic rename:
+//!
+//! 1. the versioned copy (installs and staged installs only)
+//! 2. the swinstall_stack
+//! 3. the versionless file (not for staged or scheduled installs, nor for operations on a
+//!    release channel other than the default)
+//!
+//! The versionless file only ever holds the version current when it was written. An install
+//! scheduled for later (see `install_effective`) is recorded in the swinstall_stack, but
+//! nothing rewrites the versionless file when it takes effect, nor when an install expires,
+//! until `sync` is run against it. Consumers which must see scheduled entries take effect (or
+//! expired ones lapse) on time should resolve through the stack rather than read the
+//! versionless file.
+//!
+//! If the process dies part way through, the journal is left behind and `recover`
+//! either completes the operation (when the versioned file is intact) or reverts it.
+//! New operations refuse to run against a file with a pending journal.
+//!
+//! Operations spanning several files (see `changeset`) journal every file up front, and
+//! mark each journal `prepared` once all of the versioned copies are in place. Recovery
+//! completes every member of such a transaction if any of them was prepared, and reverts
+//! them all otherwise.
+
+use chrono::{Local, NaiveDateTime};
+use crate::{
+    archive::{archive_link, with_archive},
+    compress::{decompress_file, read_versioned, versioned_hash},
+    constants::COMPRESSED_SUFFIX,
+    diagnostic::Diagnostic,
+    errors::SwInstallError,
+    journal::{Journal, Operation},
+    lock::StackLock,
+    parser::SwinstallParser,
+    stack::{StackDocument, StackElement},
+    traits::{EltOptions, PendingEntry, TagEntry, TimelineEvent, VersionedFile},
+    utils::{
+        file_hash, modified_time, swinstall_journal_from_versionless, swinstall_stack_from_versionless,
+        versioned_from_versionless, write_atomic,
+    },
+};
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use std::{
+    fs,
+    path::Path,
+};
+
+/// The outcome of recovering a swinstalled file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// There was no interrupted transaction
+    Clean,
+    /// The interrupted operation was completed
+    Completed(Operation, String),
+    /// The interrupted operation was reverted
+    Reverted(Operation, String),
+}
+
+/// A single file's part in a transaction, prepared in memory but not yet applied.
+#[derive(Debug)]
+pub(crate) struct Member {
+    versionless: String,
+    swinstall_stack: String,
+    versioned: String,
+    // contents of a newly installed version. None for rollbacks
+    contents: Option<Vec<u8>>,
+    before: Option<String>,
+    stack: StackDocument,
+    journal: Journal,
+    versioned_written: bool,
+}
+
+impl Member {
+    /// Prepare the install (or staging) of `source` as a new version of `versionless`, taking
+    /// effect at `datetime`. An install which is not current as of `now` is recorded without
+    /// touching the versionless file. The caller must hold the lock on the swinstall_stack.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_version(
+        parser: &SwinstallParser,
+        operation: Operation,
+        versionless: &str,
+        source: &str,
+        schema: &str,
+        datetime: &NaiveDateTime,
+        now: &NaiveDateTime,
+        options: &EltOptions,
+    ) -> Result<Self, SwInstallError> {
+        let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+        let journal_path = pending_check(versionless)?;
+
+        let before = read_stack(swinstall_stack.as_str())?;
+        let mut stack = match before {
+            Some(ref contents) => StackDocument::from_xml(contents)?,
+            None => StackDocument::new(swinstall_stack.as_str(), schema),
+        };
+
+        let contents = fs::read(source)?;
+        let hash = format!("{:x}", md5::compute(&contents));
+        let component = parser.component_for(&stack)?;
+        // schema 1 marks a new version current outright, so cannot hold it back until later
+        if operation == Operation::Install && *datetime > *now && component.schema() == "1" {
+            return Err(SwInstallError::UnsupportedOperation("scheduled install for schema 1".to_string()));
+        }
+        let version = match operation {
+            Operation::Stage => component.stage(&mut stack, hash.as_str(), datetime, options)?,
+            _ => component.install(&mut stack, hash.as_str(), datetime, options)?,
+        };
+
+        // the versionless file holds what lookups resolve to now. An install which takes
+        // effect later, or which is dated beneath the entry current now, is recorded in the
+        // stack but left out of the versionless file
+        let operation = match operation {
+            Operation::Install => {
+                let current = component.current_in_stack(&stack, now, options.channel.as_deref())
+                                       .map(|(current, _)| current == version)
+                                       .unwrap_or(false);
+                if current { Operation::Install } else { Operation::Schedule }
+            },
+            operation => operation,
+        };
+        let versioned = versioned_from_versionless(versionless, version.as_str())?;
+        debug!("{} {} as {}", operation, source, versioned);
+
+        let mut journal = Journal::new(journal_path.as_str(), operation, version.as_str(), hash.as_str());
+        journal.channel = options.channel.clone();
+
+        Ok(Member {
+            versionless: versionless.to_string(),
+            swinstall_stack,
+            versioned,
+            contents: Some(contents),
+            before,
+            stack,
+            journal,
+            versioned_written: false,
+        })
+    }
+
+    /// Prepare the rollback (or promotion) of `versionless` to an existing version. The
+    /// caller must hold the lock on the swinstall_stack.
+    pub(crate) fn existing_version(
+        parser: &SwinstallParser,
+        operation: Operation,
+        versionless: &str,
+        version: &str,
+        datetime: &NaiveDateTime,
+        options: &EltOptions,
+    ) -> Result<Self, SwInstallError> {
+        let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+        let journal_path = pending_check(versionless)?;
+
+        let before = fs::read_to_string(&swinstall_stack)?;
+        let mut stack = StackDocument::from_xml(before.as_str())?;
+        let component = parser.component_for(&stack)?;
+        // bring the entries for a version which has been archived back into the stack. The
+        // archive keeps its copies, which resolve just as they did, and which merging the
+        // archive back in passes over
+        if !stack.elts().any(|e| e.get("version") == Some(version)) {
+            if let Some((archive, _)) = archive_link(swinstall_stack.as_str(), &stack)? {
+                let archived: Vec<StackElement> = StackDocument::from_file(archive.as_str())?
+                    .elements
+                    .into_iter()
+                    .filter(|e| e.name == "elt" && e.get("version") == Some(version))
+                    .collect();
+                component.merge_archived(&mut stack, archived)?;
+            }
+        }
+        match operation {
+            Operation::Promote => component.promote(&mut stack, version, datetime, options)?,
+            _ => component.rollback(&mut stack, version, datetime, options)?,
+        }
+
+        let versioned = versioned_from_versionless(versionless, version)?;
+        let hash = versioned_hash(versioned.as_str())?;
+        debug!("{} {} to {}", operation, versionless, versioned);
+
+        let mut journal = Journal::new(journal_path.as_str(), operation, version, hash.as_str());
+        journal.channel = options.channel.clone();
+
+        Ok(Member {
+            versionless: versionless.to_string(),
+            swinstall_stack,
+            versioned,
+            contents: None,
+            before: Some(before),
+            stack,
+            journal,
+            versioned_written: false,
+        })
+    }
+
+    /// Record the member as part of a transaction spanning several files.
+    pub(crate) fn set_changeset(&mut self, changeset: &str, members: &[String]) {
+        self.journal.changeset = Some(changeset.to_string());
+        self.journal.members = members.to_vec();
+    }
+
+    /// Write the journal. Nothing else has been touched until this succeeds.
+    pub(crate) fn begin(&self) -> Result<(), SwInstallError> {
+        self.journal.begin(self.before.as_deref(), self.stack.to_xml().as_str())
+    }
+
+    /// Write the versioned copy of a new install.
+    pub(crate) fn write_versioned(&mut self) -> Result<(), SwInstallError> {
+        if let Some(ref contents) = self.contents {
+            write_atomic(self.versioned.as_str(), contents)?;
+            self.versioned_written = true;
+            self.journal.step("versioned")?;
+        }
+        Ok(())
+    }
+
+    /// Record that every member of the transaction has been written to bak.
+    pub(crate) fn prepare(&mut self) -> Result<(), SwInstallError> {
+        self.journal.step("prepared")
+    }
+
+    /// Update the swinstall_stack and versionless file, and remove the journal.
+    pub(crate) fn commit(mut self) -> Result<String, SwInstallError> {
+        // a version coming back into use is no longer stored compressed
+        if self.contents.is_none() {
+            decompress_file(self.versioned.as_str())?;
+        }
+        self.stack.write(self.swinstall_stack.as_str())?;
+        self.journal.step("stack")?;
+        failpoint("stack");
+
+        if !self.journal.updates_versionless() {
+            self.journal.finish()?;
+            return Ok(self.versioned);
+        }
+
+        let contents = match self.contents {
+            Some(ref contents) => contents.clone(),
+            None => read_versioned(self.versioned.as_str())?,
+        };
+        write_atomic(self.versionless.as_str(), &contents)?;
+        self.journal.step("versionless")?;
+        failpoint("versionless");
+
+        self.journal.finish()?;
+        Ok(self.versioned)
+    }
+
+    /// Undo a member which has begun but not been prepared.
+    pub(crate) fn abort(self) -> Result<(), SwInstallError> {
+        if self.versioned_written {
+            fs::remove_file(&self.versioned)?;
+        }
+        // begin may have failed after writing the stack images but before the journal
+        self.journal.discard()
+    }
+}
+
+/// Install the file at `source` as a new version of the `versionless` file, making it current
+/// as of `datetime`, which is taken to be now. If the versionless file has no swinstall_stack
+/// yet, one is created using the supplied schema. Returns the path to the versioned file.
+///
+/// An install with an expiry stops being current at that time as far as lookups are
+/// concerned, but the versionless file keeps its contents until the next install, rollback or
+/// `sync`.
+pub fn install(
+    parser: &SwinstallParser,
+    versionless: &str,
+    source: &str,
+    schema: &str,
+    datetime: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<String, SwInstallError> {
+    install_effective(parser, versionless, source, schema, datetime, datetime, options)
+}
+
+/// Install the file at `source` as a new version of the `versionless` file, taking effect at
+/// `effective` rather than `now`. Returns the path to the versioned file.
+///
+/// The version is written to bak and recorded in the swinstall_stack, where lookups find it
+/// from `effective` onwards. The versionless file is only rewritten if the new version is
+/// current as of `now`: an install scheduled for the future, or dated beneath an entry which
+/// is current now, leaves it as it is. Nothing rewrites the versionless file once a scheduled
+/// install takes effect short of `sync`, so consumers should resolve such files through the
+/// stack.
+pub fn install_effective(
+    parser: &SwinstallParser,
+    versionless: &str,
+    source: &str,
+    schema: &str,
+    effective: &NaiveDateTime,
+    now: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<String, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let bak = Path::new(&swinstall_stack).parent().ok_or(SwInstallError::NoParentFromPath)?;
+    fs::create_dir_all(bak)?;
+
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    let mut member = Member::new_version(parser, Operation::Install, versionless, source, schema, effective, now, options)?;
+    member.begin()?;
+    failpoint("journal");
+
+    member.write_versioned()?;
+    failpoint("versioned");
+
+    member.commit()
+}
+
+/// Roll the `versionless` file back (or forward) to an existing version, as of `datetime`.
+/// Returns the path to the versioned file.
+pub fn rollback(
+    parser: &SwinstallParser,
+    versionless: &str,
+    version: &str,
+    datetime: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<String, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    let member = Member::existing_version(parser, Operation::Rollback, versionless, version, datetime, options)?;
+    member.begin()?;
+    failpoint("journal");
+
+    member.commit()
+}
+
+/// Stage the file at `source` as a new version of the `versionless` file. The versioned file
+/// is written and recorded in the swinstall_stack, but does not become current until it is
+/// promoted. Returns the path to the versioned file.
+pub fn stage(
+    parser: &SwinstallParser,
+    versionless: &str,
+    source: &str,
+    schema: &str,
+    datetime: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<String, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let bak = Path::new(&swinstall_stack).parent().ok_or(SwInstallError::NoParentFromPath)?;
+    fs::create_dir_all(bak)?;
+
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    let mut member = Member::new_version(parser, Operation::Stage, versionless, source, schema, datetime, datetime, options)?;
+    member.begin()?;
+    failpoint("journal");
+
+    member.write_versioned()?;
+    failpoint("versioned");
+
+    member.commit()
+}
+
+/// Promote a staged version of the `versionless` file, making it current as of `datetime`.
+/// Returns the path to the versioned file.
+pub fn promote(
+    parser: &SwinstallParser,
+    versionless: &str,
+    version: &str,
+    datetime: &NaiveDateTime,
+    options: &EltOptions,
+) -> Result<String, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    let member = Member::existing_version(parser, Operation::Promote, versionless, version, datetime, options)?;
+    member.begin()?;
+    failpoint("journal");
+
+    member.commit()
+}
+
+/// Bring the `versionless` file into line with the version current in the default channel as
+/// of `datetime`, as it falls out of step when scheduled installs take effect or installs
+/// expire. Only the versionless file is written; the swinstall_stack is left as it is. Returns
+/// the version written, or None if the versionless file was already up to date. Nothing is
+/// written when `dry_run` is set.
+pub fn sync(
+    parser: &SwinstallParser,
+    versionless: &str,
+    datetime: &NaiveDateTime,
+    dry_run: bool,
+) -> Result<Option<String>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    let (version, _) = parser.component_for(&stack)?.current_in_stack(&stack, datetime, None)?;
+    let versioned = versioned_from_versionless(versionless, version.as_str())?;
+    if Path::new(versionless).exists() && file_hash(versionless)? == versioned_hash(versioned.as_str())? {
+        return Ok(None);
+    }
+    if dry_run {
+        return Ok(Some(version));
+    }
+    write_atomic(versionless, &read_versioned(versioned.as_str())?)?;
+    info!("synced {} to version {}", versionless, version);
+    Ok(Some(version))
+}
+
+/// Retrieve the versions of the `versionless` file which are staged in `channel` but not yet
+/// promoted in it, most recent first.
+pub fn staged(parser: &SwinstallParser, versionless: &str, channel: Option<&str>) -> Result<Vec<String>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    parser.component_for(&stack)?.staged(&stack, channel)
+}
+
+/// Retrieve the entries of the `versionless` file's swinstall_stack which take effect after
+/// `datetime`, latest first.
+pub fn pending(parser: &SwinstallParser, versionless: &str, datetime: &NaiveDateTime) -> Result<Vec<PendingEntry>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    parser.component_for(&stack)?.pending(&stack, datetime)
+}
+
+/// Point the tag `name` at `version` of the `versionless` file as of `datetime`, or remove the
+/// tag if `version` is None. Only the swinstall_stack is modified, so no journal is needed;
+/// the stack is replaced atomically while holding its lock.
+pub fn tag(
+    parser: &SwinstallParser,
+    versionless: &str,
+    name: &str,
+    version: Option<&str>,
+    datetime: &NaiveDateTime,
+) -> Result<(), SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let mut stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    parser.component_for(&stack)?.tag(&mut stack, name, version, datetime)?;
+    stack.write(swinstall_stack.as_str())
+}
+
+/// Retrieve the tags recorded for the `versionless` file, most recent first.
+pub fn tags(parser: &SwinstallParser, versionless: &str) -> Result<Vec<TagEntry>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    parser.component_for(&stack)?.tags(&stack)
+}
+
+/// Retrieve the history of the `versionless` file, most recent event first, including any
+/// entries archived from its swinstall_stack.
+pub fn timeline(parser: &SwinstallParser, versionless: &str) -> Result<Vec<TimelineEvent>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let stack = StackDocument::from_file(swinstall_stack.as_str())?;
+    let stack = with_archive(parser, swinstall_stack.as_str(), &stack)?;
+    parser.component_for(&stack)?.timeline(&stack)
+}
+
+/// Rewrite a damaged swinstall_stack for the `versionless` file from the elements which can be
+/// recovered from it (see `SwinstallParser::read_lenient`), returning the damage found. The
+/// damaged stack is kept alongside the repaired one, with a `.damaged` suffix. A stack without
+/// damage is left alone, as it is when `dry_run` is set.
+pub fn repair(parser: &SwinstallParser, versionless: &str, dry_run: bool) -> Result<Vec<Diagnostic>, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+
+    let (stack, damage) = parser.read_lenient(swinstall_stack.as_str())?;
+    if damage.is_empty() || dry_run {
+        return Ok(damage);
+    }
+    fs::copy(&swinstall_stack, format!("{}.damaged", swinstall_stack))?;
+    stack.write(swinstall_stack.as_str())?;
+    info!("repaired {}: {} problems", swinstall_stack, damage.len());
+    Ok(damage)
+}
+
+/// The outcome of reconstructing a lost swinstall_stack.
+#[derive(Debug)]
+pub struct Reconstruction {
+    pub swinstall_stack: String,
+    /// the reconstructed stack
+    pub stack: StackDocument,
+    /// the version made current, if the versionless file identified it
+    pub current: Option<String>,
+    /// descriptions of the guesses made in reconstructing the stack
+    pub guesses: Vec<String>,
+}
+
+/// Rebuild the lost swinstall_stack of the `versionless` file, in the supplied schema, from the
+/// versioned files left alongside it. The current version is the one whose contents match the
+/// versionless file; failing that, the most recent. Nothing is written when `dry_run` is set.
+/// An existing swinstall_stack is never replaced; see `repair` for damaged stacks.
+pub fn reconstruct(parser: &SwinstallParser, versionless: &str, schema: &str, dry_run: bool) -> Result<Reconstruction, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let _lock = StackLock::acquire(swinstall_stack.as_str())?;
+    pending_check(versionless)?;
+    if Path::new(&swinstall_stack).exists() {
+        return Err(SwInstallError::RuntimeError(format!("{} exists; nothing to reconstruct", swinstall_stack)));
+    }
+    let component = parser.get_component(schema)
+        .ok_or_else(|| SwInstallError::RuntimeError(format!("Unable to get reader for schema: {}", schema)))?;
+
+    // versioned files are named <file>_<version>, alongside the stack
+    let prefix = format!("{}_", Path::new(versionless).file_name().and_then(|f| f.to_str()).ok_or(SwInstallError::NoFileNameFromPath)?);
+    let bak = Path::new(&swinstall_stack).parent().ok_or(SwInstallError::NoParentFromPath)?;
+    let mut files = Vec::new();
+    for entry in fs::read_dir(bak)? {
+        let path = entry?.path();
+        let path = path.to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+        let version = match Path::new(path).file_name().and_then(|f| f.to_str()).and_then(|f| f.strip_prefix(prefix.as_str())) {
+            // skip the journal, lock and the like
+            Some(version) if !version.starts_with("swinstall_") => version,
+            _ => continue,
+        };
+        // versions may be stored compressed
+        let version = version.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(version).to_string();
+        if files.iter().any(|f: &VersionedFile| f.version == version) {
+            continue;
+        }
+        let versioned = versioned_from_versionless(versionless, version.as_str())?;
+        files.push(VersionedFile { version, modified: modified_time(path)?, hash: versioned_hash(versioned.as_str())? });
+    }
+    if files.is_empty() {
+        return Err(SwInstallError::RuntimeError(format!("no versioned files found in {}", bak.display())));
+    }
+    files.sort_by(|a, b| a.modified.cmp(&b.modified).then_with(|| a.version.cmp(&b.version)));
+
+    // the versionless file is a copy of the current version
+    let mut guesses = Vec::new();
+    let (current, current_since) = match file_hash(versionless) {
+        Ok(hash) => {
+            let matches: Vec<&VersionedFile> = files.iter().filter(|f| f.hash == hash).collect();
+            if matches.len() > 1 {
+                let versions: Vec<&str> = matches.iter().map(|f| f.version.as_str()).collect();
+                guesses.push(format!(
+                    "versionless file matches versions {}; took the most recently modified as current", versions.join(", ")
+                ));
+            } else if matches.is_empty() {
+                guesses.push("versionless file matches no version; took the most recent as current".to_string());
+            }
+            (matches.last().map(|f| f.version.clone()), modified_time(versionless)?)
+        },
+        Err(_) => {
+            guesses.push("versionless file is missing; took the most recent version as current".to_string());
+            (None, Local::now().naive_local())
+        },
+    };
+
+    let mut stack = StackDocument::new(swinstall_stack.as_str(), schema);
+    guesses.extend(component.reconstruct(&mut stack, &files, current.as_deref(), &current_since)?);
+    if stack.elts().next().is_none() {
+        return Err(SwInstallError::RuntimeError(format!("no schema {} versions found in {}", schema, bak.display())));
+    }
+    if !dry_run {
+        stack.write(swinstall_stack.as_str())?;
+    }
+    Ok(Reconstruction { swinstall_stack, stack, current, guesses })
+}
+
+/// Complete or revert an interrupted install or rollback of the `versionless` file.
+///
+/// A single file operation is completed when the versioned file it refers to is present
+/// and intact; otherwise it is reverted by restoring the swinstall_stack recorded in the
+/// journal. Operations spanning several files are recovered together.
+pub fn recover(versionless: &str) -> Result<Recovery, SwInstallError> {
+    let journal_path = swinstall_journal_from_versionless(versionless)?;
+    let members = match Journal::load(journal_path.as_str())? {
+        Some(ref journal) if journal.changeset.is_some() => journal.members.clone(),
+        Some(_) => vec![versionless.to_string()],
+        None => return Ok(Recovery::Clean),
+    };
+
+    let _locks = lock_all(&members)?;
+    // reload the journals now that we hold the locks. Another process may have
+    // recovered while we waited.
+    let mut journals = Vec::new();
+    for member in &members {
+        let journal_path = swinstall_journal_from_versionless(member)?;
+        if let Some(journal) = Journal::load(journal_path.as_str())? {
+            journals.push((member.clone(), journal));
+        }
+    }
+
+    let prepared = journals.iter().any(|(_, journal)| journal.completed("prepared"));
+    let mut recovery = Recovery::Clean;
+    for (member, journal) in journals {
+        info!("recovering {} {} of {} (steps completed: {:?})",
+              journal.txid, journal.operation, member, journal.steps);
+
+        let versioned = versioned_from_versionless(member.as_str(), journal.version.as_str())?;
+        let complete = match journal.changeset {
+            Some(_) => prepared,
+            None => versioned_hash(versioned.as_str()).map(|hash| hash == journal.hash).unwrap_or(false),
+        };
+        let result = recover_member(member.as_str(), versioned.as_str(), journal, complete)?;
+        if member == versionless {
+            recovery = result;
+        }
+    }
+    Ok(recovery)
+}
+
+/// Acquire the locks for the swinstall_stacks of all of the supplied versionless files. Locks
+/// are taken in a consistent order to avoid deadlocking with other multi-file transactions.
+pub(crate) fn lock_all(versionless: &[String]) -> Result<Vec<StackLock>, SwInstallError> {
+    let mut stacks = Vec::new();
+    for file in versionless {
+        stacks.push(swinstall_stack_from_versionless(file.as_str())?);
+    }
+    stacks.sort();
+    stacks.dedup();
+
+    let mut locks = Vec::new();
+    for swinstall_stack in stacks {
+        let bak = Path::new(&swinstall_stack).parent().ok_or(SwInstallError::NoParentFromPath)?;
+        fs::create_dir_all(bak)?;
+        locks.push(StackLock::acquire(swinstall_stack.as_str())?);
+    }
+    Ok(locks)
+}
+
+/// Abort the process if the failpoint environment variable names this step. Used to
+/// exercise recovery of interrupted transactions, and only built with the `failpoints`
+/// feature.
+#[cfg(feature = "failpoints")]
+pub(crate) fn failpoint(step: &str) {
+    use crate::constants::FAILPOINT_ENV;
+    if std::env::var(FAILPOINT_ENV).map(|v| v == step).unwrap_or(false) {
+        error!("aborting at failpoint: {}", step);
+        std::process::abort();
+    }
+}
+
+#[cfg(not(feature = "failpoints"))]
+pub(crate) fn failpoint(_step: &str) {}
+
+// complete or revert the journaled operation on a single versionless file
+fn recover_member(versionless: &str, versioned: &str, journal: Journal, complete: bool) -> Result<Recovery, SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(versionless)?;
+    let recovery = if complete {
+        decompress_file(versioned)?;
+        write_atomic(swinstall_stack.as_str(), journal.after()?.as_bytes())?;
+        if journal.updates_versionless() {
+            write_atomic(versionless, &read_versioned(versioned)?)?;
+        }
+        Recovery::Completed(journal.operation, journal.version.clone())
+    } else {
+        if journal.operation.creates_version() && Path::new(versioned).exists() {
+            fs::remove_file(versioned)?;
+        }
+        match journal.before()? {
+            Some(before) => write_atomic(swinstall_stack.as_str(), before.as_bytes())?,
+            None => {
+                if Path::new(&swinstall_stack).exists() {
+                    fs::remove_file(&swinstall_stack)?;
+                }
+            }
+        }
+        Recovery::Reverted(journal.operation, journal.version.clone())
+    };
+
+    journal.finish()?;
+    Ok(recovery)
+}
+
+// error if there is an interrupted transaction against the versionless file. Returns the
+// path to the journal otherwise.
+pub(crate) fn pending_check(versionless: &str) -> Result<String, SwInstallError> {
+    let journal_path = swinstall_journal_from_versionless(versionless)?;
+    if Journal::exists(journal_path.as_str()) {
+        return Err(SwInstallError::PendingTransaction(journal_path));
+    }
+    Ok(journal_path)
+}
+
+// read the swinstall_stack, if it exists
+fn read_stack(swinstall_stack: &str) -> Result<Option<String>, SwInstallError> {
+    if !Path::new(swinstall_stack).exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(swinstall_stack)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::datetime;
+    use tempfile::TempDir;
+
+    fn source(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn install_and_rollback() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+
+        let first = source(&dir, "first", "first");
+        let second = source(&dir, "second", "second");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).expect("install failed");
+        let versioned = install(&parser, versionless, second.as_str(), "2", &datetime("20181221-142248"), &EltOptions::default()).expect("install failed");
+        assert!(versioned.ends_with("packages.xml_2"));
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "second");
+
+        rollback(&parser, versionless, "1", &datetime("20181221-142313"), &EltOptions::default()).expect("rollback failed");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "first");
+
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+        let current = parser.current_at(swinstall_stack.as_str(), &datetime("20181221-142300")).unwrap();
+        assert!(current.ends_with("packages.xml_2"));
+        let current = parser.current_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap();
+        assert!(current.ends_with("packages.xml_1"));
+        assert_eq!(recover(versionless).unwrap(), Recovery::Clean);
+    }
+
+    #[test]
+    fn stage_and_promote() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        let first = source(&dir, "first", "first");
+        let candidate = source(&dir, "candidate", "candidate");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        let versioned = stage(&parser, versionless, candidate.as_str(), "2", &datetime("20181221-142248"), &EltOptions::default())
+                            .expect("stage failed");
+        assert_eq!(fs::read_to_string(&versioned).unwrap(), "candidate");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "first");
+        assert_eq!(staged(&parser, versionless, None).unwrap(), vec!["2".to_string()]);
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap(), "1");
+
+        promote(&parser, versionless, "2", &datetime("20181221-142313"), &EltOptions::default()).expect("promote failed");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "candidate");
+        assert!(staged(&parser, versionless, None).unwrap().is_empty());
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-142300")).unwrap(), "1");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap(), "2");
+    }
+
+    #[test]
+    fn scheduled_install() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+        let now = Local::now().naive_local();
+        let tomorrow = now + chrono::Duration::days(1);
+
+        let first = source(&dir, "first", "first");
+        let later = source(&dir, "later", "later");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        let versioned = install_effective(&parser, versionless, later.as_str(), "2", &tomorrow, &now, &EltOptions::default())
+                            .expect("scheduled install failed");
+        assert_eq!(fs::read_to_string(&versioned).unwrap(), "later");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "first");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "1");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &(tomorrow + chrono::Duration::seconds(1))).unwrap(), "2");
+
+        let scheduled = pending(&parser, versionless, &now).unwrap();
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].version, "2");
+
+        // an install made today lands beneath the scheduled one
+        install(&parser, versionless, first.as_str(), "2", &now, &EltOptions::default()).unwrap();
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "3");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &(tomorrow + chrono::Duration::seconds(1))).unwrap(), "2");
+
+        // once the scheduled install takes effect, sync writes it to the versionless file
+        assert_eq!(sync(&parser, versionless, &tomorrow, false).unwrap(), Some("2".to_string()));
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "later");
+    }
+
+    #[test]
+    fn backdated_install_leaves_versionless_file() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+        let now = datetime("20181224-120000");
+
+        install(&parser, versionless, source(&dir, "first", "first").as_str(), "2", &datetime("20181221-120000"), &EltOptions::default()).unwrap();
+        install(&parser, versionless, source(&dir, "third", "third").as_str(), "2", &datetime("20181223-120000"), &EltOptions::default()).unwrap();
+        // dated beneath the entry current now
+        let backdated = source(&dir, "second", "second");
+        install_effective(&parser, versionless, backdated.as_str(), "2", &datetime("20181222-120000"), &now, &EltOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "third");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "2");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181222-130000")).unwrap(), "3");
+
+        // dated in the past, but above every other entry, so current now
+        let recent = source(&dir, "recent", "recent");
+        install_effective(&parser, versionless, recent.as_str(), "2", &datetime("20181224-110000"), &now, &EltOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "recent");
+        assert_eq!(recover(versionless).unwrap(), Recovery::Clean);
+    }
+
+    #[test]
+    fn expiring_install_reverts() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        let first = source(&dir, "first", "first");
+        let hotfix = source(&dir, "hotfix", "hotfix");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        let options = EltOptions::default().expires(&datetime("20181222-090000"));
+        install(&parser, versionless, hotfix.as_str(), "2", &datetime("20181221-142248"), &options).expect("install failed");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "hotfix");
+
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap(), "2");
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181222-090000")).unwrap(), "1");
+
+        let events = timeline(&parser, versionless).unwrap();
+        assert_eq!(events[0].action, "expire");
+        assert_eq!(events[0].version, "2");
+        assert_eq!(events.len(), 3);
+
+        // the versionless file keeps the hotfix until synced
+        assert_eq!(sync(&parser, versionless, &datetime("20181221-150000"), false).unwrap(), None);
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "hotfix");
+        assert_eq!(sync(&parser, versionless, &datetime("20181222-090000"), true).unwrap(), Some("1".to_string()));
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "hotfix");
+        assert_eq!(sync(&parser, versionless, &datetime("20181222-090000"), false).unwrap(), Some("1".to_string()));
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "first");
+        assert_eq!(sync(&parser, versionless, &datetime("20181222-090000"), false).unwrap(), None);
+    }
+
+    #[test]
+    fn tag_and_resolve() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        let first = source(&dir, "first", "first");
+        let second = source(&dir, "second", "second");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        install(&parser, versionless, second.as_str(), "2", &datetime("20181221-142248"), &EltOptions::default()).unwrap();
+        tag(&parser, versionless, "known-good", Some("1"), &datetime("20181221-150000")).expect("tag failed");
+
+        let resolved = parser.resolve_tag(swinstall_stack.as_str(), "known-good", &datetime("20181221-160000")).unwrap();
+        assert!(resolved.ends_with("packages.xml_1"));
+        assert!(parser.resolve_tag(swinstall_stack.as_str(), "known-good", &datetime("20181221-140000")).is_err());
+        assert_eq!(tags(&parser, versionless).unwrap().len(), 1);
+        // tags leave resolution of the current version alone
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-160000")).unwrap(), "2");
+    }
+
+    #[test]
+    fn lenient_resolution_and_repair() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let mut parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        for (name, dt) in &[("first", "20181221-102242"), ("second", "20181221-142248"), ("third", "20181222-090000")] {
+            let file = source(&dir, name, name);
+            install(&parser, versionless, file.as_str(), "2", &datetime(dt), &EltOptions::default()).unwrap();
+        }
+        // truncate the elt for version 2
+        let contents = fs::read_to_string(&swinstall_stack).unwrap();
+        let damaged = contents.replace("version=\"2\"/>", "version=\"2\"");
+        fs::write(&swinstall_stack, &damaged).unwrap();
+
+        assert!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).is_err());
+        parser.set_lenient(true);
+        // the damage comes after version 3, so cannot affect it
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181222-100000")).unwrap(), "3");
+        // but it may have hidden the version current before version 3
+        let error = parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap_err();
+        match error.downcast_ref::<SwInstallError>() {
+            Some(SwInstallError::AmbiguousCurrent(_)) => {},
+            _ => panic!("expected an ambiguous result, got {}", error),
+        }
+
+        assert_eq!(repair(&parser, versionless, true).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(&swinstall_stack).unwrap(), damaged);
+        assert_eq!(repair(&parser, versionless, false).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(format!("{}.damaged", swinstall_stack)).unwrap(), damaged);
+
+        parser.set_lenient(false);
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &datetime("20181221-150000")).unwrap(), "1");
+        assert!(repair(&parser, versionless, false).unwrap().is_empty());
+
+        // a stack truncated within its end tag has the end tag restored
+        let repaired = fs::read_to_string(&swinstall_stack).unwrap();
+        fs::write(&swinstall_stack, repaired.trim_end().trim_end_matches('>')).unwrap();
+        assert_eq!(repair(&parser, versionless, false).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(&swinstall_stack).unwrap(), repaired.trim_end());
+        assert!(repair(&parser, versionless, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reconstruct_lost_stack() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        for name in &["first", "second", "third"] {
+            let file = source(&dir, name, name);
+            install(&parser, versionless, file.as_str(), "2", &Local::now().naive_local(), &EltOptions::default()).unwrap();
+        }
+        rollback(&parser, versionless, "1", &Local::now().naive_local(), &EltOptions::default()).unwrap();
+        fs::remove_file(&swinstall_stack).unwrap();
+
+        let reconstruction = reconstruct(&parser, versionless, "2", true).expect("unable to reconstruct");
+        assert_eq!(reconstruction.current.as_deref(), Some("1"));
+        assert!(!reconstruction.guesses.is_empty());
+        assert!(!Path::new(&swinstall_stack).exists());
+
+        let reconstruction = reconstruct(&parser, versionless, "2", false).unwrap();
+        let mut versions: Vec<&str> = reconstruction.stack.elts().filter_map(|e| e.get("version")).collect();
+        versions.sort();
+        assert_eq!(versions, vec!["1", "1", "2", "3"]);
+        let now = Local::now().naive_local() + chrono::Duration::seconds(2);
+        assert_eq!(parser.version_at(swinstall_stack.as_str(), &now).unwrap(), "1");
+        // an existing stack is left alone
+        assert!(reconstruct(&parser, versionless, "2", false).is_err());
+    }
+
+    #[test]
+    fn channel_install_leaves_versionless() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let swinstall_stack = swinstall_stack_from_versionless(versionless).unwrap();
+
+        let first = source(&dir, "first", "first");
+        let candidate = source(&dir, "candidate", "candidate");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).unwrap();
+        let dev = EltOptions::default().channel("dev");
+        install(&parser, versionless, candidate.as_str(), "2", &datetime("20181221-142248"), &dev).expect("install failed");
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "first");
+        assert_eq!(parser.version_in(swinstall_stack.as_str(), Some("dev"), &datetime("20181221-150000")).unwrap(), "2");
+        assert_eq!(parser.version_in(swinstall_stack.as_str(), None, &datetime("20181221-150000")).unwrap(), "1");
+
+        // promoting the dev version to the default channel updates the versionless file
+        rollback(&parser, versionless, "2", &datetime("20181221-142313"), &EltOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(versionless).unwrap(), "candidate");
+    }
+
+    #[test]
+    fn pending_journal_blocks_install() {
+        let dir = TempDir::new().unwrap();
+        let versionless = dir.path().join("packages.xml");
+        let versionless = versionless.to_str().unwrap();
+        let parser = SwinstallParser::with_builtin_schemas();
+        let first = source(&dir, "first", "first");
+        install(&parser, versionless, first.as_str(), "2", &datetime("20181221-102242"), &EltOptions::default()).expect("install failed");
+
+        // simulate an install which died before copying the versioned file
+        let journal_path = swinstall_journal_from_versionless(versionless).unwrap();
+        let before = fs::read_to_string(swinstall_stack_from_versionless(versionless).unwrap()).unwrap();
+        let journal = Journal::new(journal_path.as_str(), Operation::Install, "2", "abc");
+        journal.begin(Some(before.as_str()), "<stack_history/>").unwrap();
+
+        match install(&parser, versionless, first.as_str(), "2", &datetime("20181221-142248"), &EltOptions::default()) {
+            Err(SwInstallError::PendingTransaction(_)) => {},
+            other => panic!("expected PendingTransaction, got {:?}", other),
+        }
+        assert_eq!(recover(versionless).unwrap(), Recovery::Reverted(Operation::Install, "2".to_string()));
+        assert_eq!(fs::read_to_string(swinstall_stack_from_versionless(versionless).unwrap()).unwrap(), before);
+    }
+}
@@ -3,8 +3,24 @@
 //! Standalone helper functions
 //!
 
-use crate::errors::SwInstallError;
-use std::path::{PathBuf};
+use chrono::{DateTime, Local, NaiveDateTime, Timelike};
+use crate::{
+    constants::DATETIME_FMT,
+    errors::SwInstallError,
+    lock::hostname,
+};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// distinguishes the temporary files of the threads of a process
+static UNIQUE_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 /// Given the path to a versionless swinstalled file, get the path to
 /// the swinstall_stack.
@@ -29,6 +45,24 @@ pub fn swinstall_stack_from_versionless(filepath: &str) -> Result<String,SwInsta
     Ok(result)
 }
 
+/// Given the path to a versionless swinstalled file, get the path to the journal
+/// recording in-flight transactions against it.
+pub fn swinstall_journal_from_versionless(filepath: &str) -> Result<String,SwInstallError> {
+    let swinstall_stack = swinstall_stack_from_versionless(filepath)?;
+    let journal = swinstall_stack.trim_end_matches("_swinstall_stack");
+    Ok(format!("{}_swinstall_journal", journal))
+}
+
+/// Given the path to a swinstall_stack, get the path to the archive holding its older entries.
+pub fn swinstall_archive_from_swinstall_stack(swinstall_stack: &str) -> String {
+    format!("{}_archive", swinstall_stack)
+}
+
+/// Given the path to a swinstall_stack, get the path to the index caching its parsed entries.
+pub fn swinstall_index_from_swinstall_stack(swinstall_stack: &str) -> String {
+    format!("{}_index", swinstall_stack)
+}
+
 /// Given a filepath to a versionless swinstalled file, and a str representing a specific version
 /// whose makeup is determined by the swinstall_stack schema, construct a full path to a
 /// versioned file
@@ -64,12 +98,80 @@ pub fn versioned_from_swinstall_stack(filepath: &str, version: &str) -> Result<S
                       .to_str()
                       .ok_or(SwInstallError::ConvertOsStrFail)?;
 
-    let mut pb = pb.to_path_buf();
+    // versioned files live alongside the swinstall_stack
+    let versioned = pb.join(format!("{}_{}", file_name, version));
 
-    pb.pop(); // pop off the file name since we dont need it in the path
-    pb.push("bak");
+    let result = versioned.to_str()
+      .ok_or(SwInstallError::Utf8Error(filepath.to_string()))?.to_string();
+
+    Ok(result)
+}
+
+/// A suffix for temporary file names unique to this call, across threads, processes and the
+/// hosts sharing a filesystem: `<host>.<pid>.<count>`.
+pub fn unique_suffix() -> String {
+    format!("{}.{}.{}", hostname(), std::process::id(), UNIQUE_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Atomically replace the contents of the file at `filepath`. The contents are written to a
+/// temporary file in the same directory, flushed to disk, and renamed over the target, so
+/// that readers see either the old or the new file but never a partially written one. A
+/// replaced file keeps its mode and group.
+pub fn write_atomic(filepath: &str, contents: &[u8]) -> Result<(), SwInstallError> {
+    write_atomic_as(filepath, contents, filepath)
+}
+
+/// Atomically replace the contents of the file at `filepath`, as `write_atomic` does, giving
+/// it the mode and group of the file at `template`, if there is one, rather than those of the
+/// file replaced. Used when a file takes the place of another under a new name.
+pub fn write_atomic_as(filepath: &str, contents: &[u8], template: &str) -> Result<(), SwInstallError> {
+    let path = Path::new(filepath);
+    let parent = match path.parent().ok_or(SwInstallError::NoParentFromPath)? {
+        // a bare file name lives in the working directory
+        parent if parent.as_os_str().is_empty() => Path::new("."),
+        parent => parent,
+    };
+    let file_name = path.file_name()
+                        .ok_or(SwInstallError::NoFileNameFromPath)?
+                        .to_str()
+                        .ok_or(SwInstallError::ConvertOsStrFail)?;
+
+    let tmp = parent.join(format!(".{}.tmp.{}", file_name, unique_suffix()));
+    let written = File::create(&tmp).and_then(|mut file| {
+        if let Ok(existing) = fs::metadata(template) {
+            // only the owner, or root, may change the group, so this is best effort. The mode
+            // is set afterwards, as changing the group may clear the setgid bit
+            if unsafe { libc::fchown(file.as_raw_fd(), libc::uid_t::MAX, existing.gid()) } != 0 {
+                debug!("unable to keep the group of {}: {}", filepath, std::io::Error::last_os_error());
+            }
+            file.set_permissions(existing.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+
+    // flush the rename itself
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+/// Given the full path to a swinstall_stack, get the path to the versionless swinstalled file
+/// it tracks.
+pub fn versionless_from_swinstall_stack(filepath: &str) -> Result<String,SwInstallError> {
+    let mut pb = PathBuf::from(filepath);
+    pb.pop(); // remove swinstall_stack
+    // get versionless file name from the directory name
+    let file_name = pb.file_name()
+                      .ok_or(SwInstallError::NoFileNameFromPath)?
+                      .to_str()
+                      .ok_or(SwInstallError::ConvertOsStrFail)?
+                      .to_string();
+    pb.pop(); // remove <file>
+    pb.pop(); // remove bak
     pb.push(file_name);
-    pb.push(format!("{}_{}", file_name, version));
 
     let result = pb.to_str()
       .ok_or(SwInstallError::Utf8Error(filepath.to_string()))?.to_string();
@@ -77,6 +179,134 @@ pub fn versioned_from_swinstall_stack(filepath: &str, version: &str) -> Result<S
     Ok(result)
 }
 
+/// Recursively find every swinstall_stack beneath the supplied root directory. Returns
+/// the paths sorted. Fails on the first directory which cannot be read.
+pub fn find_swinstall_stacks(root: &str) -> Result<Vec<String>, SwInstallError> {
+    let (stacks, skipped) = search_swinstall_stacks(root)?;
+    match skipped.into_iter().next() {
+        Some((_, error)) => Err(error.into()),
+        None => Ok(stacks),
+    }
+}
+
+/// Recursively find every swinstall_stack beneath the supplied root directory, as
+/// `find_swinstall_stacks` does, passing over directories which cannot be read. Returns the
+/// paths found, and each directory passed over with the reason, both sorted by path.
+#[allow(clippy::type_complexity)]
+pub fn search_swinstall_stacks(root: &str) -> Result<(Vec<String>, Vec<(String, io::Error)>), SwInstallError> {
+    let mut stacks = Vec::new();
+    let mut skipped = Vec::new();
+    let mut dirs = vec![PathBuf::from(root)];
+
+    while let Some(dir) = dirs.pop() {
+        let searched = fs::read_dir(&dir).and_then(|entries| {
+            for entry in entries {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type()?.is_dir() {
+                    dirs.push(path);
+                } else if is_swinstall_stack(&path) {
+                    stacks.push(path);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = searched {
+            debug!("unable to search {}: {}", dir.display(), e);
+            skipped.push((dir.to_str().ok_or(SwInstallError::ConvertOsStrFail)?.to_string(), e));
+        }
+    }
+    let mut stacks = stacks.iter()
+        .map(|path| path.to_str().map(str::to_string).ok_or(SwInstallError::ConvertOsStrFail))
+        .collect::<Result<Vec<_>, _>>()?;
+    stacks.sort();
+    skipped.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((stacks, skipped))
+}
+
+// whether the path is that of a swinstall_stack: bak/<file>/<file>_swinstall_stack
+fn is_swinstall_stack(path: &Path) -> bool {
+    match (path.file_name().and_then(|f| f.to_str()), path.parent()) {
+        (Some(name), Some(parent)) => {
+            let parent_name = parent.file_name().and_then(|f| f.to_str()).unwrap_or("");
+            let bak = parent.parent().and_then(|p| p.file_name()).and_then(|f| f.to_str());
+            name == format!("{}_swinstall_stack", parent_name) && bak == Some("bak")
+        }
+        _ => false,
+    }
+}
+
+/// Expand a path containing the wildcards `*` and `?`, as a shell would, for paths which reach
+/// us unexpanded (quoted on the command line, or read from a file). Returns the matching paths
+/// sorted, none if nothing matches, or the path itself if it has no wildcards. As with a
+/// shell, wildcards do not match a leading `.`.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>, SwInstallError> {
+    let is_wild = |s: &str| s.contains('*') || s.contains('?');
+    if !is_wild(pattern) {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let mut candidates = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let component = component.as_os_str().to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+        if !is_wild(component) {
+            candidates.iter_mut().for_each(|c| c.push(component));
+            continue;
+        }
+        let mut matched = Vec::new();
+        for candidate in candidates.iter().filter(|c| c.is_dir() || c.as_os_str().is_empty()) {
+            let dir = if candidate.as_os_str().is_empty() { Path::new(".") } else { candidate.as_path() };
+            for entry in fs::read_dir(dir)? {
+                let name = entry?.file_name();
+                let name = name.to_str().ok_or(SwInstallError::ConvertOsStrFail)?;
+                if (!name.starts_with('.') || component.starts_with('.')) && wildcard_match(component.as_bytes(), name.as_bytes()) {
+                    matched.push(candidate.join(name));
+                }
+            }
+        }
+        candidates = matched;
+    }
+    let mut paths = candidates.into_iter()
+        .filter(|c| c.exists())
+        .map(|c| c.to_str().map(str::to_string).ok_or(SwInstallError::ConvertOsStrFail))
+        .collect::<Result<Vec<String>, SwInstallError>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+// whether `name` matches `pattern`, in which `*` matches any run of characters and `?` any one
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => wildcard_match(&pattern[1..], name) || (!name.is_empty() && wildcard_match(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Compute the md5 hash of the file at the supplied path, as stored in schema 2 elt tags.
+pub fn file_hash(filepath: &str) -> Result<String, SwInstallError> {
+    let contents = fs::read(filepath)?;
+    Ok(format!("{:x}", md5::compute(contents)))
+}
+
+/// The modification time of the file at the supplied path, in local time, to the second.
+pub fn modified_time(filepath: &str) -> Result<NaiveDateTime, SwInstallError> {
+    let modified: DateTime<Local> = fs::metadata(filepath)?.modified()?.into();
+    let modified = modified.naive_local();
+    Ok(modified.with_nanosecond(0).unwrap_or(modified))
+}
+
+/// Parse a datetime supplied by a user, either in the swinstall_stack form (YYYYMMDD-HHMMSS)
+/// or as YYYY-MM-DD HH:MM:SS (optionally with a T separator).
+pub fn parse_datetime(datetime: &str) -> Result<NaiveDateTime, SwInstallError> {
+    for fmt in &[DATETIME_FMT, "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(datetime, fmt) {
+            return Ok(dt);
+        }
+    }
+    Err(SwInstallError::InvalidDate(datetime.to_string()))
+}
 
 #[cfg(test)]
 mod tests {
@@ -89,10 +319,127 @@ mod tests {
         assert_eq!(path.unwrap(), expected);
     }
     #[test]
+    fn swinstall_journal_from_versionless_file() {
+        let path_str = "/dd/facility/etc/packages.xml";
+        let expected = "/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_journal";
+        let path = swinstall_journal_from_versionless(path_str);
+        assert_eq!(path.unwrap(), expected);
+    }
+    #[test]
     fn versioned_file_from_versionless_file() {
         let path_str = "/dd/facility/etc/packages.xml";
         let expected = "/dd/facility/etc/bak/packages.xml/packages.xml_0002";
         let path = versioned_from_versionless(path_str, "0002");
         assert_eq!(path.unwrap(), expected);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn versioned_file_from_swinstall_stack() {
+        let path_str = "/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack";
+        let expected = "/dd/facility/etc/bak/packages.xml/packages.xml_0002";
+        let path = versioned_from_swinstall_stack(path_str, "0002");
+        assert_eq!(path.unwrap(), expected);
+    }
+
+    #[test]
+    fn versionless_from_swinstall_stack_file() {
+        let path_str = "/dd/facility/etc/bak/packages.xml/packages.xml_swinstall_stack";
+        let expected = "/dd/facility/etc/packages.xml";
+        let path = versionless_from_swinstall_stack(path_str);
+        assert_eq!(path.unwrap(), expected);
+    }
+
+    #[test]
+    fn find_swinstall_stacks_in_examples() {
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let stacks = find_swinstall_stacks(root).unwrap();
+        assert_eq!(stacks.len(), 2);
+        assert!(stacks[0].ends_with("schema1/bak/packages.xml/packages.xml_swinstall_stack"));
+        assert!(stacks[1].ends_with("schema2/bak/packages.xml/packages.xml_swinstall_stack"));
+    }
+
+    #[test]
+    fn search_passes_over_unreadable_directories() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let stack = dir.path().join("etc/bak/packages.xml/packages.xml_swinstall_stack");
+        fs::create_dir_all(stack.parent().unwrap()).unwrap();
+        fs::write(&stack, "").unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let (stacks, skipped) = search_swinstall_stacks(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(stacks, vec![stack.to_str().unwrap().to_string()]);
+        // root reads every directory regardless
+        if unsafe { libc::geteuid() } != 0 {
+            assert_eq!(skipped.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>(), vec![locked.to_str().unwrap()]);
+            assert!(find_swinstall_stacks(dir.path().to_str().unwrap()).is_err());
+        }
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let missing = dir.path().join("missing");
+        let (stacks, skipped) = search_swinstall_stacks(missing.to_str().unwrap()).unwrap();
+        assert!(stacks.is_empty());
+        assert_eq!(skipped[0].0, missing.to_str().unwrap());
+        assert!(find_swinstall_stacks(missing.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let target = dir.path().join("packages.xml_swinstall_stack");
+        let target_str = target.to_str().unwrap();
+        fs::write(&target, "old").unwrap();
+        write_atomic(target_str, b"new").expect("unable to write");
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        // the temporary file should have been renamed away
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn expand_glob_matches() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in &["env.xml", "packages.xml", "packages.yaml", ".hidden.xml"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        fs::create_dir(dir.path().join("show")).unwrap();
+        fs::write(dir.path().join("show").join("shots.xml"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let names = |pattern: &str| -> Vec<String> {
+            expand_glob(format!("{}/{}", root, pattern).as_str()).unwrap()
+                .into_iter()
+                .map(|p| p[root.len() + 1..].to_string())
+                .collect()
+        };
+        assert_eq!(names("*.xml"), vec!["env.xml", "packages.xml"]);
+        assert_eq!(names("packages.?ml"), vec!["packages.xml"]);
+        assert_eq!(names("*/*.xml"), vec!["show/shots.xml"]);
+        assert!(names("*.json").is_empty());
+        // paths without wildcards are returned whether or not they exist
+        assert_eq!(expand_glob("missing.xml").unwrap(), vec!["missing.xml"]);
+    }
+
+    #[test]
+    fn parse_datetime_formats() {
+        let expected = NaiveDateTime::parse_from_str("20181221-142313", DATETIME_FMT).unwrap();
+        assert_eq!(parse_datetime("20181221-142313").unwrap(), expected);
+        assert_eq!(parse_datetime("2018-12-21 14:23:13").unwrap(), expected);
+        assert_eq!(parse_datetime("2018-12-21T14:23:13").unwrap(), expected);
+        assert!(parse_datetime("next tuesday").is_err());
+    }
+
+    #[test]
+    fn write_atomic_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("packages.xml");
+        fs::write(&path, "first").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(path.to_str().unwrap(), b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
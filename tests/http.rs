@@ -0,0 +1,113 @@
+//! Query `swinst http` about the example trees, as a dashboard would.
+
+use serde_json::Value;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    process::{Child, Command, Stdio},
+};
+
+const SWINST: &str = env!("CARGO_BIN_EXE_swinst");
+
+// a running `swinst http`, stopped when dropped
+struct Server {
+    child: Child,
+    address: String,
+}
+
+impl Server {
+    fn start() -> Self {
+        let mut child = Command::new(SWINST)
+            .args(["http", "--bind", "127.0.0.1:0"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("unable to run swinst http");
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        let address = line.trim().trim_start_matches("listening on http://").to_string();
+        Server { child, address }
+    }
+
+    fn get(&self, url: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(self.address.as_str()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", url).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        (status, serde_json::from_str(body).unwrap())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn resolve_examples() {
+    let server = Server::start();
+    let (status, reply) = server.get("/resolve?path=examples/schema2/packages.xml");
+    assert_eq!(status, 200);
+    assert_eq!(reply["version"], "5");
+    assert_eq!(reply["versioned"], "examples/schema2/bak/packages.xml/packages.xml_5");
+
+    let (status, reply) = server.get("/resolve?path=examples/schema2/packages.xml&at=2018-08-01+00:00:00");
+    assert_eq!((status, reply["version"].as_str()), (200, Some("3")));
+    let (status, reply) = server.get("/resolve?path=examples/schema1/packages.xml");
+    assert_eq!((status, reply["version"].as_str()), (200, Some("20180613-093146_r575055")));
+
+    assert_eq!(server.get("/resolve?path=examples/schema2/packages.xml&at=20100101-000000").0, 404);
+    assert_eq!(server.get("/resolve?path=examples/missing.xml").0, 404);
+    assert_eq!(server.get("/resolve").0, 400);
+}
+
+#[test]
+fn history_of_examples() {
+    let server = Server::start();
+    let (status, reply) = server.get("/history?path=examples/schema2/packages.xml");
+    assert_eq!(status, 200);
+    let history = reply["history"].as_array().unwrap();
+    assert_eq!(history.len(), 7);
+    assert_eq!(history[0]["version"], "5");
+    assert_eq!(history[2]["action"], "rollback");
+
+    // schema 1 stacks record no history
+    let (status, reply) = server.get("/history?path=examples/schema1/packages.xml");
+    assert_eq!(status, 422);
+    assert!(reply["error"].as_str().unwrap().contains("schema 1"));
+}
+
+#[test]
+fn status_of_examples() {
+    let server = Server::start();
+    let (status, reply) = server.get("/status?root=examples");
+    assert_eq!(status, 200);
+    let files: Vec<(&str, &str)> = reply["files"].as_array().unwrap().iter()
+        .map(|file| (file["path"].as_str().unwrap(), file["version"].as_str().unwrap()))
+        .collect();
+    assert_eq!(files, vec![
+        ("examples/schema1/packages.xml", "20180613-093146_r575055"),
+        ("examples/schema2/packages.xml", "5"),
+    ]);
+    assert_eq!(server.get("/status?root=examples/missing").0, 404);
+}
+
+#[test]
+fn content_of_examples() {
+    let server = Server::start();
+    let bak = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/schema2/bak/packages.xml");
+    let (status, reply) = server.get("/content?path=examples/schema2/packages.xml");
+    assert_eq!(status, 200);
+    assert_eq!(reply["content"].as_str().unwrap(), fs::read_to_string(bak.join("packages.xml_5")).unwrap());
+
+    let (status, reply) = server.get("/content?path=examples/schema2/packages.xml&version=2");
+    assert_eq!(status, 200);
+    assert_eq!(reply["content"].as_str().unwrap(), fs::read_to_string(bak.join("packages.xml_2")).unwrap());
+    assert_eq!(server.get("/content?path=examples/schema2/packages.xml&version=9").0, 404);
+}
@@ -0,0 +1,103 @@
+//! Kill `swinst install` and `swinst rollback` at each failpoint, then verify that
+//! `swinst recover` leaves the versioned file, swinstall_stack and versionless file
+//! consistent with one another.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+use tempfile::TempDir;
+
+const SWINST: &str = env!("CARGO_BIN_EXE_swinst");
+
+fn swinst(args: &[&str], failpoint: Option<&str>) -> Output {
+    let mut cmd = Command::new(SWINST);
+    cmd.args(args);
+    if let Some(step) = failpoint {
+        cmd.env("SWINSTALL_FAILPOINT", step);
+    }
+    cmd.output().expect("unable to run swinst")
+}
+
+fn setup() -> (TempDir, String) {
+    let dir = TempDir::new().unwrap();
+    let versionless = dir.path().join("packages.xml").to_str().unwrap().to_string();
+    let first = dir.path().join("first");
+    fs::write(&first, "first").unwrap();
+    assert!(swinst(&["install", first.to_str().unwrap(), versionless.as_str()], None).status.success());
+    fs::write(dir.path().join("second"), "second").unwrap();
+    (dir, versionless)
+}
+
+fn bak(dir: &TempDir, name: &str) -> String {
+    dir.path().join("bak").join("packages.xml").join(name).to_str().unwrap().to_string()
+}
+
+fn current(versionless: &str) -> String {
+    String::from_utf8(swinst(&[versionless], None).stdout).unwrap()
+}
+
+#[test]
+fn interrupted_installs_recover() {
+    for (step, completed) in &[("journal", false), ("versioned", true), ("stack", true), ("versionless", true)] {
+        let (dir, versionless) = setup();
+        let second = dir.path().join("second");
+        let output = swinst(&["install", second.to_str().unwrap(), versionless.as_str()], Some(step));
+        assert!(!output.status.success(), "install should have died at {}", step);
+        assert!(Path::new(&bak(&dir, "packages.xml_swinstall_journal")).exists());
+
+        // further installs are refused until we recover
+        assert!(!swinst(&["install", second.to_str().unwrap(), versionless.as_str()], None).status.success());
+
+        let output = swinst(&["recover", versionless.as_str()], None);
+        assert!(output.status.success());
+        assert!(!Path::new(&bak(&dir, "packages.xml_swinstall_journal")).exists());
+
+        let expected = if *completed { "second" } else { "first" };
+        assert_eq!(fs::read_to_string(&versionless).unwrap(), expected, "failpoint {}", step);
+        assert_eq!(Path::new(&bak(&dir, "packages.xml_2")).exists(), *completed, "failpoint {}", step);
+        let version = if *completed { "packages.xml_2" } else { "packages.xml_1" };
+        assert!(current(&versionless).contains(version), "failpoint {}", step);
+    }
+}
+
+#[test]
+fn interrupted_rollbacks_recover() {
+    for step in &["journal", "stack", "versionless"] {
+        let (dir, versionless) = setup();
+        let second = dir.path().join("second");
+        assert!(swinst(&["install", second.to_str().unwrap(), versionless.as_str()], None).status.success());
+
+        let output = swinst(&["rollback", versionless.as_str(), "1"], Some(step));
+        assert!(!output.status.success(), "rollback should have died at {}", step);
+
+        assert!(swinst(&["recover", versionless.as_str()], None).status.success());
+        assert_eq!(fs::read_to_string(&versionless).unwrap(), "first", "failpoint {}", step);
+        assert!(current(&versionless).contains("packages.xml_1"), "failpoint {}", step);
+    }
+}
+
+#[test]
+fn interrupted_changesets_recover() {
+    for (step, completed) in &[("versioned", false), ("prepared", true), ("stack", true)] {
+        let (dir, versionless) = setup();
+        let env = dir.path().join("env.yaml").to_str().unwrap().to_string();
+        let env_source = dir.path().join("env_source");
+        fs::write(&env_source, "env").unwrap();
+        let second = dir.path().join("second");
+
+        let output = swinst(&["install", "--changeset", "cs1",
+                              second.to_str().unwrap(), versionless.as_str(),
+                              env_source.to_str().unwrap(), env.as_str()], Some(step));
+        assert!(!output.status.success(), "changeset install should have died at {}", step);
+
+        // recovering either member recovers the whole changeset
+        assert!(swinst(&["recover", env.as_str()], None).status.success());
+        assert!(!Path::new(&bak(&dir, "packages.xml_swinstall_journal")).exists(), "failpoint {}", step);
+
+        let expected = if *completed { "second" } else { "first" };
+        assert_eq!(fs::read_to_string(&versionless).unwrap(), expected, "failpoint {}", step);
+        assert_eq!(Path::new(&env).exists(), *completed, "failpoint {}", step);
+    }
+}
@@ -0,0 +1,166 @@
+//! Resolve a corpus of schema 1 stacks at many datetimes, checking that reading the stack
+//! from the end gives the same answers as the forward scan.
+
+use chrono::{Duration, Local, NaiveDateTime, TimeZone};
+use quick_xml::{events::Event, Reader};
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use swinstall_stack::{
+    constants::DATETIME_FMT,
+    parser::SwinstallParser,
+    schemas::one::One,
+    traits::SwinstallCurrent,
+    utils::parse_datetime,
+};
+use tempfile::TempDir;
+
+// a reader positioned just after the stack_history start tag
+fn reader_at_elts(path: &Path) -> Reader<BufReader<File>> {
+    let mut reader = Reader::from_file(path).unwrap();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf).unwrap() {
+            Event::Start(ref e) if e.name() == b"stack_history" => return reader,
+            Event::Eof => panic!("no stack_history in {:?}", path),
+            _ => buf.clear(),
+        }
+    }
+}
+
+fn forward(path: &Path, datetime: &NaiveDateTime) -> Option<String> {
+    One::new().current_at(&mut reader_at_elts(path), datetime).ok()
+}
+
+// the datetimes of the entries of a stack
+fn versions(contents: &str) -> Vec<NaiveDateTime> {
+    contents.split("version=\"")
+            .skip(1)
+            .map(|v| parse_datetime(&v[..15]).unwrap())
+            .collect()
+}
+
+// copy the stack into `dir`, last modified when its latest entry was written, as it would be
+// had swinstall written it
+fn stage(dir: &TempDir, name: &str, contents: &str) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::write(&path, contents).unwrap();
+    if let Some(latest) = versions(contents).into_iter().max() {
+        let modified: SystemTime = Local.from_local_datetime(&latest).unwrap().into();
+        File::open(&path).unwrap().set_modified(modified).unwrap();
+    }
+    path
+}
+
+// compare the parser against the forward scan around every entry of the stack, and well
+// before and after them all
+fn assert_agrees(parser: &SwinstallParser, path: &Path, contents: &str) {
+    let mut datetimes = vec![parse_datetime("20000101-000000").unwrap(), Local::now().naive_local()];
+    // long stacks are sampled, as every lookup scans them from the top
+    let versions = versions(contents);
+    let step = 1.max(versions.len() / 40);
+    for version in versions.into_iter().step_by(step) {
+        datetimes.push(version - Duration::seconds(1));
+        datetimes.push(version);
+        datetimes.push(version + Duration::seconds(1));
+        datetimes.push(version + Duration::days(400));
+    }
+    for datetime in datetimes {
+        let resolved = parser.version_at(path.to_str().unwrap(), &datetime).ok();
+        assert_eq!(resolved, forward(path, &datetime), "{:?} at {}", path, datetime);
+    }
+}
+
+#[test]
+fn fixtures_agree() {
+    let dir = TempDir::new().unwrap();
+    let parser = SwinstallParser::with_builtin_schemas();
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/schema1");
+    for entry in fs::read_dir(&corpus).unwrap() {
+        let entry = entry.unwrap();
+        let contents = fs::read_to_string(entry.path()).unwrap();
+        let path = stage(&dir, entry.file_name().to_str().unwrap(), contents.as_str());
+        assert_agrees(&parser, &path, contents.as_str());
+    }
+}
+
+// a stack of `len` entries drawn from `seed`, with the odd rollback, pruned entry and entry
+// dated before those above it
+fn generated(seed: u64, len: usize) -> String {
+    let mut state = seed;
+    let mut next = |n: u64| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) % n
+    };
+    let current = match next(8) {
+        0 => None,
+        1 => Some(next(len as u64) as usize),
+        _ => Some(len - 1 - next(3.min(len as u64)) as usize),
+    };
+    let mut dt = parse_datetime("20150101-000000").unwrap();
+    let mut contents = String::from("<stack_history path=\"packages.xml_swinstall_stack\" schema=\"1\">\n");
+    for idx in 0..len {
+        dt += Duration::minutes(1 + next(60 * 24) as i64);
+        let version = match next(20) {
+            0 => dt - Duration::days(1000),
+            _ => dt,
+        };
+        let revision = match next(4) {
+            0 => format!("_r{}", next(100_000)),
+            _ => String::new(),
+        };
+        let is_current = if current == Some(idx) { "True" } else { "False" };
+        let pruned = match next(6) {
+            0 => " pruned=\"20200101-000000\"",
+            _ => "",
+        };
+        contents.push_str(format!(
+            "   <elt is_current=\"{}\"{} version=\"{}{}\"/>\n",
+            is_current, pruned, version.format(DATETIME_FMT), revision
+        ).as_str());
+    }
+    contents.push_str("</stack_history>\n");
+    contents
+}
+
+#[test]
+fn generated_stacks_agree() {
+    let dir = TempDir::new().unwrap();
+    let parser = SwinstallParser::with_builtin_schemas();
+    for seed in 0..20 {
+        // long enough to span several blocks
+        for len in &[1, 2, 7, 30, 400] {
+            let contents = generated(seed, *len);
+            let path = stage(&dir, format!("stack_{}_{}", seed, len).as_str(), contents.as_str());
+            assert_agrees(&parser, &path, contents.as_str());
+        }
+    }
+}
+
+#[test]
+fn current_read_from_end() {
+    let dir = TempDir::new().unwrap();
+    let mut contents = String::from("<stack_history path=\"packages.xml_swinstall_stack\" schema=\"1\">\n");
+    let mut dt = parse_datetime("20150101-000000").unwrap();
+    for idx in 0..2000 {
+        dt += Duration::hours(6);
+        let is_current = if idx == 1998 { "True" } else { "False" };
+        contents.push_str(format!("   <elt is_current=\"{}\" version=\"{}\"/>\n", is_current, dt.format(DATETIME_FMT)).as_str());
+    }
+    contents.push_str("</stack_history>\n");
+    let path = stage(&dir, "stack", contents.as_str());
+    let now = Local::now().naive_local();
+    let start = reader_at_elts(&path).buffer_position() as u64;
+
+    // the fast path decides lookups of the present without falling back
+    let mut file = File::open(&path).unwrap();
+    let version = One::new().current_from_end(&mut file, start, &now).unwrap();
+    assert_eq!(version, Some((dt - Duration::hours(6)).format(DATETIME_FMT).to_string()));
+    assert_eq!(version, forward(&path, &now));
+
+    // lookups from before the stack was last written are left to the forward scan
+    assert_eq!(One::new().current_from_end(&mut file, start, &(dt - Duration::seconds(1))).unwrap(), None);
+}